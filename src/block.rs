@@ -1,9 +1,17 @@
-use crate::transaction::Transaction;
-use crate::utils::to_hex_string;
-use sha2::{Digest, Sha256};
+use crate::codec::{CodecError, Decode, Encode, Reader, Writer};
+use crate::merkle_proof::{MerkleTree, MerkleVariant};
+use crate::transaction::{SerializableTransaction, Transaction};
+use crate::utils::{leading_zero_bits, to_hex_string};
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 
-#[derive(Serialize, Deserialize, Debug)]
+// Block versions. Version 1 blocks build their Merkle tree the legacy way,
+// version 2 blocks use the domain separated Rfc6962 tree and commit to their
+// version in the hash, see MerkleVariant.
+pub const LEGACY_BLOCK_VERSION: u32 = 1;
+pub const BLOCK_VERSION: u32 = 2;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Block {
     pub id: u32,
     pub timestamp: i64,
@@ -11,14 +19,36 @@ pub struct Block {
     pub hash: Option<Vec<u8>>,
     pub merkle_root: Option<Vec<u8>>,
     pub transactions: Vec<Transaction>,
+    // Proof of Work: the number of leading zero bits the block hash must have,
+    // and the nonce the miner found to get there.
+    #[serde(default)]
+    pub difficulty: u32,
+    #[serde(default)]
+    pub nonce: u64,
+    // Blocks saved before there were versions are legacy blocks
+    #[serde(default = "legacy_block_version")]
+    pub version: u32,
+}
+
+pub(crate) fn legacy_block_version() -> u32 {
+    LEGACY_BLOCK_VERSION
 }
 
 impl Block {
+    // Hash of the block header. The header commits to the transactions
+    // through the stored merkle_root, so this does not build the Merkle tree;
+    // check_merkle_root is what ties the root to the transactions.
     pub fn calculate_hash(&self) -> Option<Vec<u8>> {
-        // <--- Return Option<Vec<u8>>
-        // Use a SHA-256 library to calculate the hash of the block data
+        let merkle_root = self.merkle_root.clone().unwrap_or_else(|| vec![0; 32]);
+        let header = self.header_bytes(&merkle_root);
+
+        Some(Self::hash_header(&header, self.nonce))
+    }
+
+    // Everything that goes into the block hash except the nonce, so the mining
+    // loop only has to append a new nonce on each attempt.
+    fn header_bytes(&self, merkle_root: &[u8]) -> Vec<u8> {
         let timestamp_bytes = self.timestamp.to_le_bytes();
-        let merkle_root = self.calculate_merkle_root().unwrap_or_else(|| vec![0; 32]); 
         let mut data_to_hash = Vec::new();
 
         // Correctly handle previous_hash:
@@ -28,138 +58,76 @@ impl Block {
 
         data_to_hash.extend_from_slice(&self.id.to_le_bytes());
         data_to_hash.extend_from_slice(&timestamp_bytes); // Reference timestamp_bytes directly
-        data_to_hash.extend_from_slice(&merkle_root);
+        data_to_hash.extend_from_slice(merkle_root);
+        data_to_hash.extend_from_slice(&self.difficulty.to_le_bytes());
+        // Left out of version 1 blocks so they hash the same as blocks did
+        // before the version field existed
+        if self.version != LEGACY_BLOCK_VERSION {
+            data_to_hash.extend_from_slice(&self.version.to_le_bytes());
+        }
+
+        data_to_hash
+    }
 
+    fn hash_header(header: &[u8], nonce: u64) -> Vec<u8> {
         // Use a SHA-256 library to calculate the hash of the aggregated data.
         let mut hasher = Sha256::new();
-        hasher.update(data_to_hash);
-        let digest = hasher.finalize();
-
-        Some(digest.to_vec())
+        hasher.update(header);
+        hasher.update(nonce.to_le_bytes());
+        hasher.finalize().to_vec()
     }
 
-    pub fn calculate_merkle_root(&self) -> Option<Vec<u8>> {
-        if self.transactions.is_empty() {
-            return None;
-        }
-
-        let mut leaf_hashes = self
-            .transactions
-            .iter()
-            .map(|transaction| {
-                let transaction_data = serde_json::to_string(transaction).unwrap();
-                let mut hasher = Sha256::new();
-                hasher.update(transaction_data.as_bytes());
-                let hash_result = hasher.finalize();
-                let hash_array: [u8; 32] = hash_result.into();
-                hash_array
-            })
-            .collect::<Vec<[u8; 32]>>();
-
-        while leaf_hashes.len() > 1 {
-            if leaf_hashes.len() % 2 != 0 {
-                leaf_hashes.push(*leaf_hashes.last().unwrap());
+    // Search for a nonce that gives a hash with at least `difficulty` leading
+    // zero bits, i.e. a hash below the target 2^(256 - difficulty). The merkle
+    // root and hash fields are filled in once a nonce is found.
+    pub fn mine(&mut self) {
+        self.merkle_root = self.calculate_merkle_root();
+        let merkle_root = self.merkle_root.clone().unwrap_or_else(|| vec![0; 32]);
+        let header = self.header_bytes(&merkle_root);
+
+        let mut nonce: u64 = 0;
+        loop {
+            let hash = Self::hash_header(&header, nonce);
+            if leading_zero_bits(&hash) >= self.difficulty {
+                self.nonce = nonce;
+                self.hash = Some(hash);
+                return;
             }
-
-            leaf_hashes = leaf_hashes
-                .chunks(2)
-                .map(|chunk| {
-                    let mut hasher = Sha256::new();
-                    hasher.update(&chunk[0]);
-                    hasher.update(&chunk[1]);
-                    let hash_result = hasher.finalize();
-                    hash_result.into()
-                })
-                .collect::<Vec<[u8; 32]>>(); 
+            nonce = nonce.wrapping_add(1);
         }
-
-        Some(leaf_hashes.first()?.to_vec()) // Convert the first (and only) hash array to Vec<u8>
     }
 
-    pub fn generate_merkle_path(&self, transaction_hash: &Vec<u8>) -> Option<Vec<(Vec<u8>, bool)>> {
-        let transaction_hashes = self
-            .transactions
-            .iter()
-            .map(|tx| tx.calculate_hash())
-            .collect::<Vec<_>>();
-        let mut tree_layers = vec![transaction_hashes]; // The bottom layer of the tree
-
-        // Build the tree, layer by layer
-        while tree_layers.last().unwrap().len() > 1 {
-            let prev_layer = tree_layers.last().unwrap();
-            let new_layer = prev_layer
-                .chunks(2)
-                .map(|chunk| {
-                    let left = &chunk[0];
-                    let right = chunk.get(1).unwrap_or(left); // Handle odd number of elements
-
-                    // Create a new Vec<u8> and extend it with the bytes from left and right
-                    let mut combined = Vec::new();
-                    combined.extend_from_slice(left);
-                    combined.extend_from_slice(right);
-
-                    // Hash the combined vector
-                    Block::hash_function(&combined)
-                })
-                .collect::<Vec<_>>();
-            tree_layers.push(new_layer);
-        }
-
-        // Find the transaction index in the bottom layer
-        let index = tree_layers[0]
-            .iter()
-            .position(|hash| hash == transaction_hash)?;
-        let mut path = Vec::new();
-        let mut current_index = index;
-
-        // Collect the sibling hashes and direction for each layer
-        for layer in tree_layers.iter().take(tree_layers.len() - 1) {
-            let is_right_sibling = current_index % 2 == 1;
-            let sibling_index = if is_right_sibling {
-                current_index - 1
-            } else {
-                current_index + 1
-            };
-            let sibling_hash = layer.get(sibling_index).cloned().unwrap_or_default();
-
-            path.push((sibling_hash, is_right_sibling));
-            current_index /= 2; // Move up to the next layer
+    // True if the stored hash satisfies the difficulty the block declares.
+    pub fn meets_difficulty(&self) -> bool {
+        match &self.hash {
+            Some(hash) => leading_zero_bits(hash) >= self.difficulty,
+            None => false,
         }
-
-        Some(path)
     }
 
-    fn hash_function(data: &[u8]) -> Vec<u8> {
-        Sha256::digest(data).to_vec()
+    // Expected number of hashes it took to mine the block. Fork choice sums
+    // this over a branch, so a branch of harder blocks can beat a longer one.
+    pub fn work(&self) -> u128 {
+        1u128 << self.difficulty.min(127)
     }
 
-    pub fn construct_merkle_tree(&self) -> Vec<u8> {
-        let mut layer = self
-            .transactions
-            .iter()
-            .map(|tx| tx.calculate_hash())
-            .collect::<Vec<_>>();
-
-        while layer.len() > 1 {
-            layer = Self::construct_merkle_layer(&layer);
+    // The kind of Merkle tree the block commits to its transactions with.
+    pub fn merkle_variant(&self) -> MerkleVariant {
+        match self.version {
+            LEGACY_BLOCK_VERSION => MerkleVariant::Legacy,
+            _ => MerkleVariant::Rfc6962,
         }
+    }
 
-        layer.first().cloned().unwrap_or_else(|| vec![])
+    // The Merkle tree over the block's transaction ids, as they are now.
+    // Mining, check_merkle_root and proofs each build it once.
+    pub fn merkle_tree(&self) -> MerkleTree {
+        let ids = self.transactions.iter().map(|tx| tx.id()).collect();
+        MerkleTree::new(self.merkle_variant(), ids)
     }
 
-    fn construct_merkle_layer(current_layer: &[Vec<u8>]) -> Vec<Vec<u8>> {
-        current_layer
-            .chunks(2)
-            .map(|chunk| {
-                let left = &chunk[0];
-                let right = chunk.get(1).unwrap_or(left);
-
-                let mut hasher = Sha256::new();
-                hasher.update(left);
-                hasher.update(right);
-                hasher.finalize().to_vec()
-            })
-            .collect()
+    pub fn calculate_merkle_root(&self) -> Option<Vec<u8>> {
+        self.merkle_tree().root().map(<[u8]>::to_vec)
     }
 
     pub fn print_json(&self) -> serde_json::Result<()> {
@@ -169,61 +137,105 @@ impl Block {
         Ok(())
     }
 
-    fn to_serializable(&self) -> SerializableBlock {
+    // View of the block with every hash hex encoded, for printing and the RPC API.
+    pub fn to_serializable(&self) -> SerializableBlock {
         SerializableBlock {
             id: self.id,
+            version: self.version,
             timestamp: self.timestamp,
-            transactions: self.transactions.clone(),
+            difficulty: self.difficulty,
+            nonce: self.nonce,
+            transactions: self.transactions.iter().map(Transaction::to_serializable).collect(),
             // Check if previous_hash is Some, then convert to hex, else default to an empty string
             previous_hash: self
                 .previous_hash
-                .as_ref()
-                .map_or_else(String::new, |hash| to_hex_string(hash)),
+                .as_deref()
+                .map_or_else(String::new, to_hex_string),
             // Do the same for hash and merkle_root if they are also Option<Vec<u8>>
             hash: self
                 .hash
-                .as_ref()
-                .map_or_else(String::new, |hash| to_hex_string(hash)),
+                .as_deref()
+                .map_or_else(String::new, to_hex_string),
             merkle_root: self
                 .merkle_root
-                .as_ref()
-                .map_or_else(String::new, |root| to_hex_string(root)),
+                .as_deref()
+                .map_or_else(String::new, to_hex_string),
         }
     }
 
     // Debug print function for a Block
     pub fn debug_print(&self) {
         println!("Block ID: {}", self.id);
+        println!("Version: {}", self.version);
         println!("Timestamp: {}", self.timestamp);
+        println!("Difficulty: {}", self.difficulty);
+        println!("Nonce: {}", self.nonce);
         // Handle Option<Vec<u8>> for previous_hash, merkle_root, and hash
         println!(
             "Previous Hash: {}",
             self.previous_hash
-                .as_ref()
-                .map_or_else(|| "None".to_string(), |hash| to_hex_string(hash))
+                .as_deref()
+                .map_or_else(|| "None".to_string(), to_hex_string)
         );
         println!(
             "Merkle Root: {}",
             self.merkle_root
-                .as_ref()
-                .map_or_else(|| "None".to_string(), |root| to_hex_string(root))
+                .as_deref()
+                .map_or_else(|| "None".to_string(), to_hex_string)
         );
         println!(
             "Hash: {}",
             self.hash
-                .as_ref()
-                .map_or_else(|| "None".to_string(), |hash| to_hex_string(hash))
+                .as_deref()
+                .map_or_else(|| "None".to_string(), to_hex_string)
         );
 
         println!("Transactions: {:?}", self.transactions);
     }
 }
 
+impl Encode for Block {
+    fn encode_to(&self, writer: &mut Writer) {
+        writer.u32(self.id);
+        writer.i64(self.timestamp);
+        writer.option_bytes(self.previous_hash.as_deref());
+        writer.option_bytes(self.hash.as_deref());
+        writer.option_bytes(self.merkle_root.as_deref());
+        writer.list(&self.transactions);
+        writer.u32(self.difficulty);
+        writer.u64(self.nonce);
+        writer.u32(self.version);
+    }
+}
+
+impl Decode for Block {
+    fn decode_from(reader: &mut Reader) -> Result<Self, CodecError> {
+        Ok(Block {
+            id: reader.u32()?,
+            timestamp: reader.i64()?,
+            previous_hash: reader.option_bytes()?,
+            hash: reader.option_bytes()?,
+            merkle_root: reader.option_bytes()?,
+            transactions: reader.list()?,
+            difficulty: reader.u32()?,
+            nonce: reader.u64()?,
+            // Version 1 of the format had legacy blocks only
+            version: match reader.format_version() {
+                1 => LEGACY_BLOCK_VERSION,
+                _ => reader.u32()?,
+            },
+        })
+    }
+}
+
 #[derive(Serialize)]
 pub struct SerializableBlock {
     id: u32,
+    version: u32,
     timestamp: i64,
-    transactions: Vec<Transaction>,
+    difficulty: u32,
+    nonce: u64,
+    transactions: Vec<SerializableTransaction>,
     previous_hash: String,
     hash: String,
     merkle_root: String,
@@ -0,0 +1,318 @@
+use crate::block::Block;
+use crate::storage::{decode_block, encode_block, write_atomically, BlockStore, StoreError};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+// Files of a BlockLog data directory: the log, and the chain's metadata,
+// which is small and replaced atomically on every save.
+pub const BLOCK_LOG_FILE: &str = "blocks.log";
+pub const METADATA_FILE: &str = "chain.json";
+
+// Every record starts with the payload length (u32, little endian) and the
+// first 4 bytes of the SHA-256 of the length and payload, then the encoded
+// block. The checksum covers the length too, so a damaged length is caught
+// instead of sending the reader off to a wrong offset.
+const HEADER_LEN: usize = 8;
+
+// A BlockStore appending blocks to a single file. Blocks are only ever added
+// at the end and synced to disk before put_block returns, so a crash can at
+// worst leave the last record half written, which open cuts off again.
+//
+// The index from hash and height to file offset is rebuilt while open checks
+// the records, so it is never out of step with the file. The best chain by
+// height is only kept in memory, the Blockchain sets it again after opening.
+#[derive(Debug)]
+pub struct BlockLog {
+    file: File,
+    dir: PathBuf,
+    by_hash: HashMap<Vec<u8>, u64>,
+    // Offsets in the order the blocks were put
+    order: Vec<u64>,
+    // Offsets of the best chain by height
+    best_chain: Vec<u64>,
+    // Bytes of a torn record open cut off the end of the file
+    truncated: u64,
+}
+
+impl BlockLog {
+    // Start a new, empty log in the data directory `dir`, replacing any there.
+    pub fn create(dir: &Path) -> Result<BlockLog, StoreError> {
+        fs::create_dir_all(dir)?;
+        File::create(dir.join(BLOCK_LOG_FILE))?.sync_all()?;
+        let _ = fs::remove_file(dir.join(METADATA_FILE));
+        Self::open_file(dir)
+    }
+
+    // Open the log in the data directory `dir`. A torn record at the end is
+    // truncated away, see truncated_bytes.
+    pub fn open(dir: &Path) -> Result<BlockLog, StoreError> {
+        let mut log = Self::open_file(dir)?;
+        let mut data = Vec::new();
+        log.file.read_to_end(&mut data)?;
+
+        let mut offset = 0;
+        while let Some(record) = read_record(&data[offset..]) {
+            let block = decode_block(&record[HEADER_LEN..])?;
+            log.index(&block, offset as u64);
+            offset += record.len();
+        }
+
+        if offset < data.len() {
+            // A crash can only tear the record that was being appended, so
+            // nothing valid comes after a torn record. If something does, the
+            // record was damaged in place and cutting it off would lose every
+            // block after it.
+            if (offset + 1..data.len()).any(|start| read_record(&data[start..]).is_some()) {
+                return Err(StoreError::Corrupt { offset: offset as u64 });
+            }
+            log.truncated = (data.len() - offset) as u64;
+            log.file.set_len(offset as u64)?;
+            log.file.sync_all()?;
+        }
+        Ok(log)
+    }
+
+    fn open_file(dir: &Path) -> Result<BlockLog, StoreError> {
+        let file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .open(dir.join(BLOCK_LOG_FILE))?;
+        Ok(BlockLog {
+            file,
+            dir: dir.to_path_buf(),
+            by_hash: HashMap::new(),
+            order: Vec::new(),
+            best_chain: Vec::new(),
+            truncated: 0,
+        })
+    }
+
+    // Length of the torn record open cut off the end of the log, 0 if there
+    // was none. Left to the caller to report.
+    pub fn truncated_bytes(&self) -> u64 {
+        self.truncated
+    }
+
+    // Number of blocks in the log.
+    pub fn len(&self) -> usize {
+        self.order.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.order.is_empty()
+    }
+
+    fn read_at(&self, offset: u64) -> Result<Block, StoreError> {
+        let mut file = &self.file;
+        file.seek(SeekFrom::Start(offset))?;
+        let mut header = [0; HEADER_LEN];
+        file.read_exact(&mut header)?;
+        let len = u32::from_le_bytes([header[0], header[1], header[2], header[3]]) as usize;
+        let mut payload = vec![0; len];
+        file.read_exact(&mut payload)?;
+        if checksum(len as u32, &payload) != header[4..] {
+            return Err(StoreError::Corrupt { offset });
+        }
+        decode_block(&payload)
+    }
+
+    fn index(&mut self, block: &Block, offset: u64) {
+        self.by_hash.insert(block.hash.clone().unwrap_or_default(), offset);
+        self.order.push(offset);
+    }
+}
+
+impl BlockStore for BlockLog {
+    fn put_block(&mut self, block: &Block) -> Result<(), StoreError> {
+        if self.by_hash.contains_key(block.hash.as_deref().unwrap_or_default()) {
+            return Ok(());
+        }
+        let payload = encode_block(block);
+        let mut record = Vec::with_capacity(HEADER_LEN + payload.len());
+        record.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        record.extend_from_slice(&checksum(payload.len() as u32, &payload));
+        record.extend_from_slice(&payload);
+
+        let offset = self.file.seek(SeekFrom::End(0))?;
+        self.file.write_all(&record)?;
+        self.file.sync_data()?;
+        self.index(block, offset);
+        Ok(())
+    }
+
+    fn get_by_hash(&self, hash: &[u8]) -> Result<Option<Block>, StoreError> {
+        match self.by_hash.get(hash) {
+            Some(&offset) => self.read_at(offset).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn get_by_height(&self, height: u32) -> Result<Option<Block>, StoreError> {
+        match self.best_chain.get(height as usize) {
+            Some(&offset) => self.read_at(offset).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn tip(&self) -> Result<Option<Block>, StoreError> {
+        match self.best_chain.last() {
+            Some(&offset) => self.read_at(offset).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn set_best_chain(&mut self, height: u32, hashes: &[Vec<u8>]) -> Result<(), StoreError> {
+        let offsets = hashes
+            .iter()
+            .map(|hash| self.by_hash.get(hash).copied().ok_or_else(|| StoreError::MissingBlock(hash.clone())))
+            .collect::<Result<Vec<u64>, StoreError>>()?;
+        self.best_chain.truncate(height as usize);
+        self.best_chain.extend(offsets);
+        Ok(())
+    }
+
+    fn iterate(&self) -> Box<dyn Iterator<Item = Result<Block, StoreError>> + '_> {
+        Box::new(self.order.iter().map(|&offset| self.read_at(offset)))
+    }
+
+    fn save_metadata(&mut self, metadata: &[u8]) -> Result<(), StoreError> {
+        write_atomically(&self.dir.join(METADATA_FILE), metadata)?;
+        Ok(())
+    }
+
+    fn load_metadata(&self) -> Result<Option<Vec<u8>>, StoreError> {
+        let path = self.dir.join(METADATA_FILE);
+        if !path.exists() {
+            return Ok(None);
+        }
+        Ok(Some(fs::read(path)?))
+    }
+
+    fn path(&self) -> Option<&Path> {
+        Some(&self.dir)
+    }
+}
+
+// The whole record at the start of `data`, or None if it is cut short or
+// does not match its checksum.
+fn read_record(data: &[u8]) -> Option<&[u8]> {
+    let header = data.get(..HEADER_LEN)?;
+    let len = u32::from_le_bytes([header[0], header[1], header[2], header[3]]);
+    let record = data.get(..HEADER_LEN + len as usize)?;
+    (checksum(len, &record[HEADER_LEN..]) == header[4..]).then_some(record)
+}
+
+fn checksum(len: u32, payload: &[u8]) -> [u8; 4] {
+    let mut hasher = Sha256::new();
+    hasher.update(len.to_le_bytes());
+    hasher.update(payload);
+    let hash = hasher.finalize();
+    [hash[0], hash[1], hash[2], hash[3]]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::Blockchain;
+    use crate::config::ChainConfig;
+    use crate::test_utils::TempDir;
+
+    // A few mined blocks, each on top of the one before
+    fn blocks(count: usize) -> Vec<Block> {
+        let mut blockchain = Blockchain::with_config(ChainConfig {
+            initial_difficulty: 4,
+            min_difficulty: 1,
+            ..ChainConfig::default()
+        });
+        for _ in 1..count {
+            blockchain.mine_block("miner");
+        }
+        blockchain.chain
+    }
+
+    fn log_with(dir: &Path, blocks: &[Block]) -> Vec<u64> {
+        let mut log = BlockLog::create(dir).unwrap();
+        for block in blocks {
+            log.put_block(block).unwrap();
+        }
+        log.order.clone()
+    }
+
+    fn stored_hashes(log: &BlockLog) -> Vec<Option<Vec<u8>>> {
+        log.iterate().map(|block| block.unwrap().hash).collect()
+    }
+
+    #[test]
+    fn blocks_are_read_back_after_reopening() {
+        let dir = TempDir::new("block-log-reopen");
+        let blocks = blocks(3);
+        log_with(dir.path(), &blocks);
+
+        let log = BlockLog::open(dir.path()).unwrap();
+        assert_eq!(stored_hashes(&log), blocks.iter().map(|block| block.hash.clone()).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn torn_last_record_is_truncated() {
+        let dir = TempDir::new("block-log-torn");
+        let blocks = blocks(3);
+        let offsets = log_with(dir.path(), &blocks);
+        let path = dir.path().join(BLOCK_LOG_FILE);
+        let len = fs::metadata(&path).unwrap().len();
+
+        // A crash half way through appending the last block
+        OpenOptions::new().write(true).open(&path).unwrap().set_len(len - 10).unwrap();
+
+        let mut log = BlockLog::open(dir.path()).unwrap();
+        assert_eq!(log.len(), 2);
+        assert_eq!(log.truncated_bytes(), len - 10 - offsets[2]);
+        assert_eq!(fs::metadata(&path).unwrap().len(), offsets[2]);
+
+        // The block can be appended again after the surviving ones
+        log.put_block(&blocks[2]).unwrap();
+        let log = BlockLog::open(dir.path()).unwrap();
+        assert_eq!(log.len(), 3);
+        assert_eq!(log.truncated_bytes(), 0);
+        assert_eq!(log.get_by_hash(blocks[2].hash.as_deref().unwrap()).unwrap().unwrap().id, 2);
+    }
+
+    #[test]
+    fn corrupt_length_in_the_middle_is_an_error() {
+        let dir = TempDir::new("block-log-corrupt-length");
+        let offsets = log_with(dir.path(), &blocks(3));
+        let path = dir.path().join(BLOCK_LOG_FILE);
+        let mut data = fs::read(&path).unwrap();
+        let len = data.len();
+
+        // The length of the second record now runs past the end of the file
+        let start = offsets[1] as usize;
+        data[start..start + 4].copy_from_slice(&(len as u32).to_le_bytes());
+        fs::write(&path, &data).unwrap();
+
+        assert!(matches!(
+            BlockLog::open(dir.path()),
+            Err(StoreError::Corrupt { offset }) if offset == offsets[1]
+        ));
+        // Nothing was cut off
+        assert_eq!(fs::metadata(&path).unwrap().len(), len as u64);
+    }
+
+    #[test]
+    fn corrupt_payload_in_the_middle_is_an_error() {
+        let dir = TempDir::new("block-log-corrupt-payload");
+        let offsets = log_with(dir.path(), &blocks(3));
+        let path = dir.path().join(BLOCK_LOG_FILE);
+        let mut data = fs::read(&path).unwrap();
+
+        data[offsets[1] as usize + HEADER_LEN + 5] ^= 0xff;
+        fs::write(&path, &data).unwrap();
+
+        assert!(matches!(
+            BlockLog::open(dir.path()),
+            Err(StoreError::Corrupt { offset }) if offset == offsets[1]
+        ));
+    }
+}
@@ -1,49 +1,258 @@
 use crate::block::Block;
-use crate::transaction::Transaction;
-use crate::merkle_proof::MerkleProof;
-use crate::MAX_TRANSACTIONS_PER_BLOCK;
+use crate::config::{ChainConfig, Ledger};
+use crate::consensus::{self, block_hash, ValidationError};
+use crate::genesis::GenesisSpec;
+use crate::transaction::{Transaction, TransactionError};
+use crate::mempool::{Mempool, MempoolEvent};
+use crate::merkle_proof::{InclusionProof, MerkleProof};
+use crate::state::LedgerState;
+use crate::storage::{check_transaction_ids, BlockStore, DynBlockStore, MemoryStore, PersistencePolicy, StoreError};
+use crate::utils::to_hex_string;
+use crate::utxo::{OutPoint, TxOutput};
 use serde::{Deserialize, Serialize};
-use std::fs::File;
-use std::io::Write;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::time::Instant;
 
+// Deepest a fork may start below the tip. Side blocks further down are
+// dropped, and blocks forking off further down are refused before they are
+// replayed, so stale forks can not pile up or make every block replay the
+// whole chain.
+pub const MAX_REORG_DEPTH: usize = 100;
 
-#[derive(Serialize, Deserialize, Debug)]
-pub struct Blockchain {
+// What submit_block did with a block.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SubmitOutcome {
+    // The block built on the tip and is now the tip
+    ExtendedTip,
+    // The block is valid but its branch has no more work than the best chain
+    SideBranch,
+    // The block's branch overtook the best chain: `disconnected` blocks left
+    // the best chain and `connected` blocks, including this one, joined it
+    Reorg { disconnected: usize, connected: usize },
+}
+
+#[derive(Debug, PartialEq)]
+pub enum BlockError {
+    AlreadyKnown,
+    // The parent is not in the block tree, so the block can not be placed
+    UnknownParent,
+    // The block forks off more than MAX_REORG_DEPTH blocks below the tip
+    StaleFork,
+    Invalid(ValidationError),
+}
+
+impl fmt::Display for BlockError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BlockError::AlreadyKnown => write!(f, "block is already known"),
+            BlockError::UnknownParent => write!(f, "parent of the block is not known"),
+            BlockError::StaleFork => write!(
+                f,
+                "block forks off more than {} blocks below the tip",
+                MAX_REORG_DEPTH
+            ),
+            BlockError::Invalid(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+impl std::error::Error for BlockError {}
+
+// Everything about a chain that is not in its blocks, kept in the store as
+// its metadata.
+#[derive(Serialize, Deserialize)]
+struct ChainMetadata {
+    chain_id: String,
+    config: ChainConfig,
+    miner_address: Option<String>,
+    mempool: Mempool,
+}
+
+// A chain of blocks and everything needed to extend it: the side branches,
+// the mempool, the consensus parameters and the ledger state. Changes are
+// kept in memory until they are written to the store `S` by save, or by
+// persist when the persistence policy says so, see storage.rs.
+#[derive(Serialize, Debug)]
+pub struct Blockchain<S: BlockStore = DynBlockStore> {
+    // The best chain, from genesis to the tip
     pub chain: Vec<Block>,
-    mempool: Vec<Transaction>,
+    // Valid blocks that are not on the best chain, keyed by hex hash. Together
+    // with `chain` they make up the block tree.
+    side_blocks: HashMap<String, Block>,
+    mempool: Mempool,
+    chain_id: String,
+    config: ChainConfig,
+    miner_address: Option<String>,
+    // Balances after the last block, rebuilt from the chain when opening it
+    #[serde(skip)]
+    state: LedgerState,
+    // None once detached, then nothing is written anymore
+    #[serde(skip)]
+    store: Option<S>,
+    #[serde(skip)]
+    policy: PersistencePolicy,
+    // Blocks accepted since the last save, in the order they came
+    #[serde(skip)]
+    unsaved_blocks: Vec<Block>,
+    // Lowest height of the best chain that changed since the last save
+    #[serde(skip)]
+    best_chain_changed: Option<usize>,
+    // The mempool or miner address changed since the last save
+    #[serde(skip)]
+    metadata_changed: bool,
+    #[serde(skip)]
+    last_saved: Instant,
+}
+
+impl Default for Blockchain {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
+// Chains that are not given a store keep their blocks in memory.
 impl Blockchain {
+    // A chain starting from the default genesis block, the same on every node.
     pub fn new() -> Self {
-        Blockchain {
-            chain: vec![Self::create_genesis_block()],
-            mempool: Vec::new(),
+        Self::from_genesis(&GenesisSpec::default())
+    }
+
+    // A chain with the default genesis block but other consensus parameters.
+    pub fn with_config(config: ChainConfig) -> Self {
+        Self::from_genesis(&GenesisSpec {
+            consensus: config,
+            ..GenesisSpec::default()
+        })
+    }
+
+    // A chain starting from the genesis block of the spec, with its consensus
+    // parameters and its allocations already credited. Panics if the
+    // consensus parameters are invalid, the memory store itself never fails.
+    pub fn from_genesis(spec: &GenesisSpec) -> Self {
+        Self::with_store(spec, Box::new(MemoryStore::default())).unwrap_or_else(|error| panic!("{}", error))
+    }
+}
+
+impl<S: BlockStore> Blockchain<S> {
+    // A new chain from the genesis block of the spec, written to an empty store.
+    pub fn with_store(spec: &GenesisSpec, store: S) -> Result<Self, Box<dyn std::error::Error>> {
+        spec.validate()?;
+        let genesis_block = spec.block();
+        let config = spec.consensus.clone();
+        let state = LedgerState::from_blocks(config.ledger, std::slice::from_ref(&genesis_block))
+            .expect("genesis allocations are coinbases, which always apply");
+        let mut blockchain = Blockchain {
+            unsaved_blocks: vec![genesis_block.clone()],
+            chain: vec![genesis_block],
+            side_blocks: HashMap::new(),
+            mempool: Mempool::default(),
+            chain_id: spec.chain_id.clone(),
+            state,
+            config,
+            miner_address: None,
+            store: Some(store),
+            policy: PersistencePolicy::default(),
+            best_chain_changed: Some(0),
+            metadata_changed: true,
+            last_saved: Instant::now(),
+        };
+        blockchain.save()?;
+        Ok(blockchain)
+    }
+
+    // Open the chain in a store: read the metadata and replay the blocks in
+    // the order they were stored, which rebuilds the side branches and picks
+    // the best chain the same way as when the blocks first arrived.
+    pub fn open(store: S) -> Result<Self, Box<dyn std::error::Error>> {
+        let metadata = store.load_metadata()?.ok_or("the store holds no chain")?;
+        let metadata: ChainMetadata = serde_json::from_slice(&metadata)?;
+        metadata.config.validate()?;
+        let mut blocks = store.iterate().map(|block| block.and_then(check_transaction_ids));
+        let genesis_block = blocks.next().ok_or("the store has no genesis block")??;
+
+        let mut blockchain = Blockchain {
+            state: LedgerState::from_blocks(metadata.config.ledger, std::slice::from_ref(&genesis_block))?,
+            chain: vec![genesis_block],
+            side_blocks: HashMap::new(),
+            mempool: Mempool::default(),
+            chain_id: metadata.chain_id,
+            config: metadata.config,
+            miner_address: metadata.miner_address,
+            store: None,
+            policy: PersistencePolicy::default(),
+            unsaved_blocks: Vec::new(),
+            best_chain_changed: None,
+            metadata_changed: false,
+            last_saved: Instant::now(),
+        };
+        for block in blocks {
+            // Stale forks were stored before the chain moved past them
+            match blockchain.submit_block(block?) {
+                Ok(_) | Err(BlockError::StaleFork) => {}
+                Err(error) => return Err(error.into()),
+            }
+        }
+        blockchain.mempool = metadata.mempool;
+        blockchain.metadata_changed = false;
+
+        // The best chain the store had may be behind, for example when a
+        // block log does not keep it on disk
+        let tip = store.tip()?.and_then(|block| block.hash);
+        blockchain.store = Some(store);
+        if tip != blockchain.get_latest_block().hash {
+            blockchain.best_chain_changed = Some(0);
+            blockchain.save()?;
         }
+        Ok(blockchain)
     }
 
-    fn create_genesis_block() -> Block {
-        // Create a block with fixed data and empty previous_hash
-        let timestamp = chrono::Utc::now().timestamp();
+    // Difficulty retargeting: every `retarget_window` blocks, compare how long
+    // the last window of blocks took against the configured block interval.
+    // Difficulty counts leading zero bits, so each step up doubles the expected
+    // work and each step down halves it. Blocks in between keep the difficulty
+    // of their parent.
+    fn next_difficulty(&self, ancestors: &[Block]) -> u32 {
+        let config = &self.config;
+        let previous = match ancestors.last() {
+            Some(block) => block,
+            None => return config.initial_difficulty,
+        };
 
-        let mut genesis_block = Block {
-            id: 0,
-            timestamp,
-            previous_hash: None,
-            hash: None,
-            merkle_root: None,
-            transactions: Vec::new(),
+        let window = config.retarget_window.max(2) as usize;
+        if ancestors.len() < window || !ancestors.len().is_multiple_of(window) {
+            return previous.difficulty;
+        }
+
+        let first = &ancestors[ancestors.len() - window];
+        let actual_time = previous.timestamp - first.timestamp;
+        let expected_time = config.target_block_time * (window as i64 - 1);
+
+        let difficulty = if actual_time * 2 <= expected_time {
+            // Blocks came in at least twice as fast as wanted
+            previous.difficulty + 1
+        } else if actual_time >= expected_time * 2 {
+            // Blocks took at least twice as long as wanted
+            previous.difficulty.saturating_sub(1)
+        } else {
+            previous.difficulty
         };
 
-        genesis_block.merkle_root = genesis_block.calculate_merkle_root();
+        difficulty.clamp(config.min_difficulty, config.max_difficulty)
+    }
 
-        // Finally, calculate the hash of the genesis block including its Merkle root
-        genesis_block.hash = genesis_block.calculate_hash();
+    // The difficulty a block at the given height must declare.
+    pub fn expected_difficulty(&self, height: u32) -> u32 {
+        let height = (height as usize).min(self.chain.len());
+        self.next_difficulty(&self.chain[..height])
+    }
 
-        genesis_block
+    pub fn get_config(&self) -> &ChainConfig {
+        &self.config
     }
 
-    fn is_valid_block(&self, block: &Block) -> bool {
-        block.calculate_hash() == block.hash
+    pub fn chain_id(&self) -> &str {
+        &self.chain_id
     }
 
     // Helper functions for the Blockchain struct:
@@ -64,87 +273,534 @@ impl Blockchain {
         self.chain.len() as u32 - 1
     }
 
-    pub fn validate_chain(&self) -> bool {
+    pub fn get_block_by_hash(&self, hash: &[u8]) -> Option<&Block> {
+        self.chain
+            .iter()
+            .find(|block| block.hash.as_deref() == Some(hash))
+            .or_else(|| self.side_blocks.get(&to_hex_string(hash)))
+    }
+
+    // A transaction on the best chain by its hash, with the block it is in.
+    pub fn find_transaction(&self, hash: &[u8]) -> Option<(&Block, &Transaction)> {
+        self.chain.iter().find_map(|block| {
+            block
+                .transactions
+                .iter()
+                .find(|transaction| transaction.hash == hash)
+                .map(|transaction| (block, transaction))
+        })
+    }
+
+    // Total work of the best chain, see Block::work.
+    pub fn chain_work(&self) -> u128 {
+        self.chain.iter().map(Block::work).sum()
+    }
+
+    // Check a block against the consensus rules, on top of the branch it
+    // extends. `ancestors` are the blocks before it, starting at genesis, and
+    // `state` is the ledger state after them, which the block's transactions
+    // are applied to.
+    fn validate_block(&self, ancestors: &[Block], block: &Block, state: &mut LedgerState) -> Result<(), ValidationError> {
+        let now = chrono::Utc::now().timestamp();
+        consensus::check_link(ancestors, block)?;
+        consensus::check_id(ancestors, block)?;
+        consensus::check_timestamp(ancestors, block, now)?;
+        consensus::check_version(block, self.config.block_version)?;
+        consensus::check_transaction_ids(block)?;
+        consensus::check_merkle_root(block)?;
+        consensus::check_hash(block)?;
+        consensus::check_difficulty(block, self.next_difficulty(ancestors))?;
+        consensus::check_proof_of_work(block)?;
+        consensus::check_block_size(block, self.config.max_block_transactions)?;
+        consensus::check_coinbase(block, self.config.ledger, self.block_reward(block.id))?;
+        consensus::check_signatures(block)?;
+
+        // Replaying the block must not overdraw any account, reuse or skip a
+        // nonce, or spend any output that is missing or already spent
+        state.apply_block(block).map_err(|error| ValidationError::InvalidTransaction {
+            id: block.id,
+            hash: block_hash(block),
+            error,
+        })
+    }
+
+    // Validate every block after genesis on top of the ones before it, and
+    // report the first problem found. Genesis is taken as given, so the
+    // replay starts from its allocations.
+    pub fn validate_chain(&self) -> Result<(), ValidationError> {
+        let mut state = LedgerState::from_blocks(self.config.ledger, &self.chain[..1])
+            .expect("genesis allocations are coinbases, which always apply");
+        let mut seen_hashes = HashSet::new();
         for (i, block) in self.chain.iter().enumerate().skip(1) {
-            let prev_block = self.chain.get(i - 1).unwrap();
+            self.validate_block(&self.chain[..i], block, &mut state)?;
+
+            // The same transaction can not be included twice
+            if let Some(duplicate) = block.transactions.iter().find(|tx| !seen_hashes.insert(tx.hash.clone())) {
+                return Err(ValidationError::DuplicateTransaction {
+                    id: block.id,
+                    hash: block_hash(block),
+                    transaction: to_hex_string(&duplicate.hash),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    // Add a block built elsewhere, for example by a peer. The block may extend
+    // the tip, or any block of the tree, which starts or grows a side branch.
+    // Whichever branch has the most cumulative work is the best chain; on a tie
+    // the chain seen first stays. When a side branch overtakes the best chain
+    // the ledger state is rebuilt along the new chain, and the transactions of
+    // the blocks that dropped out go back to the mempool if still valid.
+    pub fn submit_block(&mut self, block: Block) -> Result<SubmitOutcome, BlockError> {
+        let hash = block.hash.clone().ok_or_else(|| {
+            BlockError::Invalid(ValidationError::HashMismatch {
+                id: block.id,
+                hash: String::new(),
+            })
+        })?;
+        if self.get_block_by_hash(&hash).is_some() {
+            return Err(BlockError::AlreadyKnown);
+        }
 
-            let hash = &prev_block.hash;
-            match block.previous_hash != *hash || !self.is_valid_block(block) {
-                true => return false,
-                false => (),
+        // Walk back through the side branches until reaching the best chain
+        let mut branch = Vec::new();
+        let mut parent_hash = block.previous_hash.clone().ok_or_else(|| {
+            BlockError::Invalid(ValidationError::BrokenLink {
+                id: block.id,
+                hash: block_hash(&block),
+            })
+        })?;
+        let fork_height = loop {
+            if let Some(height) = self
+                .chain
+                .iter()
+                .position(|ancestor| ancestor.hash.as_ref() == Some(&parent_hash))
+            {
+                break height;
+            }
+            match self.side_blocks.get(&to_hex_string(&parent_hash)) {
+                Some(side_block) => {
+                    parent_hash = side_block.previous_hash.clone().unwrap_or_default();
+                    branch.push(side_block.clone());
+                }
+                None => return Err(BlockError::UnknownParent),
+            }
+        };
+        branch.reverse();
+        if fork_height + MAX_REORG_DEPTH < self.chain.len() - 1 {
+            return Err(BlockError::StaleFork);
+        }
+
+        let extends_tip = branch.is_empty() && fork_height + 1 == self.chain.len();
+        let mut ancestors = self.chain[..=fork_height].to_vec();
+        ancestors.extend(branch.iter().cloned());
+
+        let mut state = if extends_tip {
+            self.state.clone()
+        } else {
+            LedgerState::from_blocks(self.config.ledger, &ancestors)
+                .expect("blocks in the tree were validated when they were added")
+        };
+        self.validate_block(&ancestors, &block, &mut state)
+            .map_err(BlockError::Invalid)?;
+        // The block is valid wherever it ends up, so it is stored either way
+        self.block_accepted(&block);
+
+        if extends_tip {
+            for transaction in &block.transactions {
+                self.mempool.remove(&transaction.hash);
+            }
+            self.state = state;
+            self.chain.push(block);
+            self.mark_best_chain_changed(self.chain.len() - 1);
+            self.prune_side_blocks();
+            return Ok(SubmitOutcome::ExtendedTip);
+        }
+
+        let branch_work: u128 = ancestors.iter().chain([&block]).map(Block::work).sum();
+        if branch_work <= self.chain_work() {
+            self.side_blocks.insert(to_hex_string(&hash), block);
+            return Ok(SubmitOutcome::SideBranch);
+        }
+
+        // Reorg: swap the blocks after the fork point for the new branch
+        let disconnected = self.chain.split_off(fork_height + 1);
+        for side_block in &branch {
+            self.side_blocks.remove(&to_hex_string(side_block.hash.as_deref().unwrap_or_default()));
+        }
+        branch.push(block);
+        let connected = branch.len();
+        for connected_block in &branch {
+            for transaction in &connected_block.transactions {
+                self.mempool.remove(&transaction.hash);
+            }
+        }
+        self.chain.extend(branch);
+        self.state = state;
+        self.mark_best_chain_changed(fork_height + 1);
+
+        // Put the orphaned transactions back in chain order, so the nonces of
+        // each sender stay in sequence. Ones the new chain already includes or
+        // made invalid are rejected here and dropped.
+        for orphaned_block in &disconnected {
+            for transaction in orphaned_block.transactions.iter().filter(|tx| !tx.is_coinbase()) {
+                let _ = self.accept_transaction(transaction.clone());
             }
         }
-        true
+        let disconnected_count = disconnected.len();
+        for orphaned_block in disconnected {
+            let orphaned_hash = orphaned_block.hash.as_deref().map(to_hex_string).unwrap_or_default();
+            self.side_blocks.insert(orphaned_hash, orphaned_block);
+        }
+        self.prune_side_blocks();
+
+        Ok(SubmitOutcome::Reorg {
+            disconnected: disconnected_count,
+            connected,
+        })
+    }
+
+    // Drop the side blocks more than MAX_REORG_DEPTH below the tip. Nothing
+    // can build on them anymore, see submit_block. They stay in the store.
+    fn prune_side_blocks(&mut self) {
+        let tip_height = self.chain.len() - 1;
+        self.side_blocks
+            .retain(|_, block| block.id as usize + MAX_REORG_DEPTH >= tip_height);
     }
 
     pub fn get_chain_length(&self) -> usize {
         self.chain.len()
     }
 
-    pub fn load_from_file(&mut self, path: &str) -> Result<(), Box<dyn std::error::Error>> {
-        if std::path::Path::new(path).exists() {
-            let data = std::fs::read_to_string(path)?;
-            if !data.trim().is_empty() {
-                // Check if the file is not just whitespace
-                *self = serde_json::from_str(&data)?;
-            }
-            // If the file is empty or only contains whitespace, do nothing
+    // Write what changed since the last save to the store: the new blocks,
+    // the best chain, and everything that is not in the blocks, which is the
+    // config, the chain id, the miner address and the mempool.
+    pub fn save(&mut self) -> Result<(), StoreError> {
+        let metadata = ChainMetadata {
+            chain_id: self.chain_id.clone(),
+            config: self.config.clone(),
+            miner_address: self.miner_address.clone(),
+            mempool: self.mempool.clone(),
+        };
+        let store = match &mut self.store {
+            Some(store) => store,
+            None => return Ok(()),
+        };
+
+        // Parents are always accepted before their children, so the store
+        // gets them in an order it can replay. Blocks stay unsaved until they
+        // are all written, a failed save is tried again by the next one.
+        for block in &self.unsaved_blocks {
+            store.put_block(block)?;
+        }
+        if let Some(height) = self.best_chain_changed {
+            let hashes = self.chain[height..]
+                .iter()
+                .map(|block| block.hash.clone().unwrap_or_default())
+                .collect::<Vec<Vec<u8>>>();
+            store.set_best_chain(height as u32, &hashes)?;
         }
+        store.save_metadata(&serde_json::to_vec_pretty(&metadata).map_err(StoreError::Metadata)?)?;
+
+        self.unsaved_blocks.clear();
+        self.best_chain_changed = None;
+        self.metadata_changed = false;
+        self.last_saved = Instant::now();
         Ok(())
     }
 
-    pub fn save_to_file(&self, path: &str) -> Result<(), Box<dyn std::error::Error>> {
-        let data = serde_json::to_string_pretty(&self)?;
-        let mut file = File::create(path)?;
-        file.write_all(data.as_bytes())?;
+    // True if anything changed since the last save.
+    pub fn has_unsaved_changes(&self) -> bool {
+        self.store.is_some()
+            && (!self.unsaved_blocks.is_empty() || self.best_chain_changed.is_some() || self.metadata_changed)
+    }
+
+    // Save if there are unsaved changes and the persistence policy says it is
+    // time to, and tell whether it did. Whoever changes the chain calls this
+    // afterwards. Under Periodic nothing may change for a while, so long
+    // running processes also call it on a timer.
+    pub fn persist(&mut self) -> Result<bool, StoreError> {
+        let due = self.has_unsaved_changes()
+            && match self.policy {
+                PersistencePolicy::Manual => false,
+                PersistencePolicy::OnEveryBlock => true,
+                PersistencePolicy::Periodic(interval) => self.last_saved.elapsed() >= interval,
+            };
+        if due {
+            self.save()?;
+        }
+        Ok(due)
+    }
+
+    pub fn set_persistence_policy(&mut self, policy: PersistencePolicy) {
+        self.policy = policy;
+    }
+
+    pub fn persistence_policy(&self) -> PersistencePolicy {
+        self.policy
+    }
+
+    pub fn store(&self) -> Option<&S> {
+        self.store.as_ref()
+    }
+
+    // Stop writing to the store, for example once the chain was modified in
+    // memory for a demonstration. Changes that were not saved are dropped.
+    pub fn detach_from_file(&mut self) {
+        self.store = None;
+        self.unsaved_blocks.clear();
+        self.best_chain_changed = None;
+        self.metadata_changed = false;
+    }
+
+    // Remember an accepted block for the next save.
+    fn block_accepted(&mut self, block: &Block) {
+        if self.store.is_some() {
+            self.unsaved_blocks.push(block.clone());
+        }
+    }
+
+    // Remember that the best chain changed from `height` on.
+    fn mark_best_chain_changed(&mut self, height: usize) {
+        if self.store.is_some() {
+            self.best_chain_changed = Some(self.best_chain_changed.map_or(height, |changed| changed.min(height)));
+        }
+    }
+
+    pub fn add_transaction(&mut self, transaction: Transaction) -> Result<(), TransactionError> {
+        self.accept_transaction(transaction)?;
+
+        // Check if the mempool has reached the threshold to create a new block,
+        // this needs a miner address for the coinbase to pay
+        if self.mempool.len() >= self.config.max_block_transactions {
+            if let Some(miner_address) = self.miner_address.clone() {
+                self.create_block_from_mempool(miner_address);
+            }
+        }
         Ok(())
     }
 
-    pub fn add_transaction(&mut self, sender: String, receiver: String, amount: u64) {
-        let transaction = Transaction::new(sender, receiver, amount);
+    // Check a transaction and put it in the mempool, without mining.
+    fn accept_transaction(&mut self, transaction: Transaction) -> Result<(), TransactionError> {
+        // Only transactions signed by the sender's key, and whose hash is
+        // their id, are accepted
+        transaction.verify_id()?;
+        transaction.verify_signature()?;
+
+        let now = chrono::Utc::now().timestamp();
+        let pending = self.mempool.len();
+        self.mempool.expire(now);
+        self.metadata_changed |= self.mempool.len() != pending;
+
+        if self.mempool.contains(&transaction.hash) {
+            return Err(TransactionError::DuplicateTransaction);
+        }
+
+        // Replace-by-fee: on an account chain a transaction with the same sender
+        // and nonce as a pending one replaces it if it pays a higher fee
+        let replaced = match self.config.ledger {
+            Ledger::Account => self
+                .mempool
+                .find_by_nonce(transaction.sender(), transaction.nonce())
+                .cloned(),
+            Ledger::Utxo => None,
+        };
+        if let Some(pending) = &replaced {
+            if transaction.fee() <= pending.fee() {
+                return Err(TransactionError::ReplacementUnderpriced {
+                    fee: transaction.fee(),
+                    required: pending.fee(),
+                });
+            }
+        }
+
+        // An output can only be spent by one pending transaction
+        if let Some(input) = transaction
+            .inputs()
+            .iter()
+            .find(|input| self.mempool.spends(&input.previous_output))
+        {
+            return Err(TransactionError::DoubleSpend(input.previous_output.clone()));
+        }
 
-        // Add the new transaction to the mempool
-        self.mempool.push(transaction);
+        // The sender must be able to pay for this transaction on top of the
+        // ones already waiting in the mempool
+        let excluded = replaced.as_ref().map(|pending| pending.hash.as_slice());
+        self.select_transactions(usize::MAX, excluded)
+            .0
+            .apply_transaction(&transaction)?;
 
-        // Check if the mempool has reached the threshold to create a new block
-        if self.mempool.len() >= MAX_TRANSACTIONS_PER_BLOCK {
-            self.create_block_from_mempool();
+        match replaced {
+            Some(pending) => self.mempool.replace(&pending.hash, transaction, now)?,
+            None => self.mempool.insert(transaction, now)?,
         }
+        self.metadata_changed = true;
+        Ok(())
     }
 
-    fn create_block_from_mempool(&mut self) {
-        assert!(
-            self.mempool.len() >= MAX_TRANSACTIONS_PER_BLOCK,
-            "Not enough transactions in the mempool to create a block"
-        );
+    pub fn mempool(&self) -> &Mempool {
+        &self.mempool
+    }
+
+    pub fn mempool_mut(&mut self) -> &mut Mempool {
+        self.metadata_changed = true;
+        &mut self.mempool
+    }
+
+    // Evictions from the mempool since the last call, see MempoolEvent.
+    pub fn take_mempool_events(&mut self) -> Vec<MempoolEvent> {
+        self.mempool.take_events()
+    }
 
+    // Mine a block right away with up to `max_block_transactions` transactions
+    // from the mempool, possibly none, paying the block reward to `miner_address`.
+    pub fn mine_block(&mut self, miner_address: &str) -> &Block {
+        self.create_block_from_mempool(miner_address.to_string());
+        self.get_latest_block()
+    }
+
+    // Address paid by the coinbase of blocks created automatically once the
+    // mempool holds `max_block_transactions` transactions.
+    pub fn set_miner_address(&mut self, miner_address: String) {
+        self.miner_address = Some(miner_address);
+        self.metadata_changed = true;
+    }
+
+    pub fn miner_address(&self) -> Option<&str> {
+        self.miner_address.as_deref()
+    }
+
+    // Block subsidy at the given height, halved every `halving_interval` blocks.
+    pub fn block_reward(&self, height: u32) -> u64 {
+        let halvings = height / self.config.halving_interval.max(1);
+        self.config.block_reward.checked_shr(halvings).unwrap_or(0)
+    }
+
+    fn create_block_from_mempool(&mut self, miner_address: String) {
         // Assuming you have a method to get the previous block's hash
         let previous_hash = Some(self.get_latest_block_hash());
-        let timestamp = chrono::Utc::now().timestamp();
-        let transactions = self
-            .mempool
-            .drain(..MAX_TRANSACTIONS_PER_BLOCK)
+        // Never before the median time past, even if the clock went back
+        let timestamp = chrono::Utc::now()
+            .timestamp()
+            .max(consensus::median_time_past(&self.chain));
+        let height = self.chain.len() as u32;
+        self.mempool.expire(timestamp);
+
+        // Take the best paying transactions out of the mempool, in an order
+        // they can be applied in
+        let (_, selected) = self.select_transactions(self.config.max_block_transactions, None);
+        let included = selected
+            .iter()
+            .filter_map(|hash| self.mempool.remove(hash))
             .collect::<Vec<Transaction>>();
 
+        // The coinbase always comes first and collects the reward plus the fees
+        let reward = included
+            .iter()
+            .fold(self.block_reward(height), |reward, tx| reward.saturating_add(tx.fee()));
+        let coinbase = match self.config.ledger {
+            Ledger::Account => Transaction::coinbase(miner_address, reward, height),
+            Ledger::Utxo => Transaction::utxo_coinbase(miner_address, reward, height),
+        };
+        let mut transactions = vec![coinbase];
+        transactions.extend(included);
+
         let block_hash = Some(vec![0, 32]); // Placeholder
 
         let mut new_block = Block {
-            id: self.chain.len() as u32,
+            id: height,
             timestamp,
             transactions,
             previous_hash,
             hash: block_hash, // This should be calculated based on block content
             merkle_root: None,
+            difficulty: self.next_difficulty(&self.chain),
+            nonce: 0,
+            version: self.config.block_version,
         };
 
-        new_block.merkle_root = new_block.calculate_merkle_root();
-
-        new_block.hash = new_block.calculate_hash();
+        // Search for a nonce that satisfies the difficulty, this sets the hash
+        new_block.mine();
 
         // print_json method for Block
         //new_block.print_json().unwrap();
 
+        self.state
+            .apply_block(&new_block)
+            .expect("mempool transactions are checked against the balances before they are accepted");
+
+        self.block_accepted(&new_block);
         self.chain.push(new_block);
+        self.mark_best_chain_changed(self.chain.len() - 1);
+        self.prune_side_blocks();
+    }
+
+    // Balance of an address after all blocks in the chain. Pending transactions
+    // in the mempool are not counted.
+    pub fn balance_of(&self, address: &str) -> u64 {
+        self.state.balance_of(address)
+    }
+
+    // Nonce the next transaction of `address` must carry, counting the ones
+    // already waiting in the mempool that follow on from the chain without a
+    // gap.
+    pub fn next_nonce(&self, address: &str) -> u64 {
+        if let LedgerState::Utxo(_) = self.state {
+            return 0;
+        }
+        let mut nonce = self.state.next_nonce(address);
+        while self.mempool.find_by_nonce(address, nonce).is_some() {
+            nonce += 1;
+        }
+        nonce
+    }
+
+    // Unspent outputs of an address on a UTXO chain, leaving out the ones a
+    // mempool transaction already spends. Always empty on an account chain.
+    pub fn spendable_outputs(&self, address: &str) -> Vec<(OutPoint, TxOutput)> {
+        match &self.state {
+            LedgerState::Utxo(utxos) => utxos
+                .outputs_of(address)
+                .into_iter()
+                .filter(|(outpoint, _)| !self.mempool.spends(outpoint))
+                .collect(),
+            LedgerState::Account(_) => Vec::new(),
+        }
+    }
+
+    // Pick mempool transactions for a block, highest fee per byte first, and
+    // return their hashes with the state after applying them. The mempool is
+    // kept in fee order, so scan it from the top and take the first transaction
+    // that applies on top of the ones already picked, which also keeps the
+    // nonces of each sender in sequence. Start over after every pick since it
+    // may have made a better paying transaction valid. `excluded` is left out,
+    // it is the pending transaction being replaced by fee.
+    fn select_transactions(&self, limit: usize, excluded: Option<&[u8]>) -> (LedgerState, Vec<Vec<u8>>) {
+        let mut state = self.state.clone();
+        let mut selected: Vec<Vec<u8>> = Vec::new();
+
+        while selected.len() < limit {
+            let mut picked = None;
+            for transaction in self.mempool.iter() {
+                let hash = &transaction.hash;
+                if Some(hash.as_slice()) == excluded || selected.contains(hash) {
+                    continue;
+                }
+                // A failed apply leaves the state unchanged
+                if state.apply_transaction(transaction).is_ok() {
+                    picked = Some(hash.clone());
+                    break;
+                }
+            }
+
+            match picked {
+                Some(hash) => selected.push(hash),
+                None => break,
+            }
+        }
+
+        (state, selected)
     }
 
     fn get_latest_block_hash(&self) -> Vec<u8> {
@@ -179,35 +835,293 @@ impl Blockchain {
             if block
                 .transactions
                 .iter()
-                .any(|tx| tx.calculate_hash() == *transaction_hash)
+                .any(|tx| tx.id() == *transaction_hash)
             {
-                // Generate the Merkle path for that transaction
-                if let Some(path) = block.generate_merkle_path(transaction_hash) {
-                    // Construct and return the MerkleProof object
-                    return Some(MerkleProof {
-                        leaf: transaction_hash.clone(),
-                        path,
-                    });
-                }
-                break;
+                // Build the block's Merkle tree and read the path off it
+                return block.merkle_tree().proof_for(transaction_hash);
             }
         }
         None
     }
+
+    // Merkle proof that a transaction on the best chain, given by its hash, is
+    // in its block, along with the block's height and Merkle root.
+    pub fn prove_transaction(&self, hash: &[u8]) -> Option<InclusionProof> {
+        let (block, transaction) = self.find_transaction(hash)?;
+        // The Merkle leaves are the transaction ids, which is the stored hash
+        // unless the transaction was tampered with
+        let proof = self.generate_merkle_proof(&transaction.id())?;
+        Some(InclusionProof::new(block.id, block.merkle_root.as_deref()?, &proof))
+    }
 }
 
-impl Drop for Blockchain {
-    fn drop(&mut self) {
-        // Print a message that this is the final Blockchain
-        //println!("Final Blockchain instance:");
-        //self.print_json();
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec;
+    use crate::wallet::Wallet;
+    use std::time::Duration;
 
-        // Print a message when the Blockchain instance is dropped and saved to a file
-        println!("Dropping Blockchain instance pesrsistently to blockchain.json.");
-        // Save the blockchain to a file before dropping the instance
+    // Consensus parameters that keep mining fast
+    fn test_config(ledger: Ledger) -> ChainConfig {
+        ChainConfig {
+            initial_difficulty: 4,
+            min_difficulty: 1,
+            max_difficulty: 8,
+            ledger,
+            ..ChainConfig::default()
+        }
+    }
 
-        // Attempt to save to file here
-        let _ = self.save_to_file("./blockchain.json");
+    fn spec_allocating(address: &str, amount: u64, ledger: Ledger) -> GenesisSpec {
+        GenesisSpec {
+            allocations: [(address.to_string(), amount)].into_iter().collect(),
+            consensus: test_config(ledger),
+            ..GenesisSpec::default()
+        }
+    }
+
+    #[test]
+    fn next_nonce_skips_only_contiguous_pending_nonces() {
+        let alice = Wallet::generate();
+        let mut blockchain = Blockchain::from_genesis(&spec_allocating(&alice.address(), 100, Ledger::Account));
+        for nonce in 0..2 {
+            blockchain
+                .add_transaction(alice.create_transaction("bob", 10, 1, nonce))
+                .unwrap();
+        }
+        assert_eq!(blockchain.next_nonce(&alice.address()), 2);
+
+        // Without nonce 0 the pending nonce 1 does not count
+        let first = blockchain.mempool().find_by_nonce(&alice.address(), 0).unwrap().hash.clone();
+        blockchain.mempool_mut().remove(&first);
+        assert_eq!(blockchain.next_nonce(&alice.address()), 0);
+    }
+
+    fn stored_tip<S: BlockStore>(blockchain: &Blockchain<S>) -> Option<Vec<u8>> {
+        blockchain.store().unwrap().tip().unwrap().and_then(|block| block.hash)
+    }
+
+    fn stored_mempool_len<S: BlockStore>(blockchain: &Blockchain<S>) -> usize {
+        let metadata = blockchain.store().unwrap().load_metadata().unwrap().unwrap();
+        serde_json::from_slice::<ChainMetadata>(&metadata).unwrap().mempool.len()
+    }
+
+    fn chain_in_memory_store(alice: &Wallet) -> Blockchain<MemoryStore> {
+        let spec = spec_allocating(&alice.address(), 100, Ledger::Account);
+        Blockchain::with_store(&spec, MemoryStore::default()).unwrap()
+    }
+
+    #[test]
+    fn manual_policy_only_saves_when_asked() {
+        let alice = Wallet::generate();
+        let mut blockchain = chain_in_memory_store(&alice);
+        let genesis = stored_tip(&blockchain);
+
+        blockchain.mine_block("miner");
+        assert!(!blockchain.persist().unwrap());
+        assert_eq!(stored_tip(&blockchain), genesis);
+
+        blockchain.save().unwrap();
+        assert_eq!(stored_tip(&blockchain), blockchain.get_latest_block().hash);
+        assert!(!blockchain.has_unsaved_changes());
+    }
+
+    #[test]
+    fn on_every_block_policy_saves_blocks_and_mempool_changes() {
+        let alice = Wallet::generate();
+        let mut blockchain = chain_in_memory_store(&alice);
+        blockchain.set_persistence_policy(PersistencePolicy::OnEveryBlock);
+        assert!(!blockchain.persist().unwrap());
+
+        blockchain
+            .add_transaction(alice.create_transaction("bob", 10, 1, 0))
+            .unwrap();
+        assert!(blockchain.persist().unwrap());
+        assert_eq!(stored_mempool_len(&blockchain), 1);
+
+        blockchain.mine_block("miner");
+        assert!(blockchain.persist().unwrap());
+        assert_eq!(stored_tip(&blockchain), blockchain.get_latest_block().hash);
+        assert_eq!(stored_mempool_len(&blockchain), 0);
+        assert!(!blockchain.persist().unwrap());
+    }
+
+    #[test]
+    fn periodic_policy_saves_changes_once_the_interval_passed() {
+        let alice = Wallet::generate();
+        let mut blockchain = chain_in_memory_store(&alice);
+        blockchain.set_persistence_policy(PersistencePolicy::Periodic(Duration::from_secs(3600)));
+        blockchain.mine_block("miner");
+        assert!(!blockchain.persist().unwrap());
+        assert!(blockchain.has_unsaved_changes());
+
+        blockchain.set_persistence_policy(PersistencePolicy::Periodic(Duration::ZERO));
+        assert!(blockchain.persist().unwrap());
+        assert_eq!(stored_tip(&blockchain), blockchain.get_latest_block().hash);
+        // Nothing changed since, so there is nothing to save
+        assert!(!blockchain.persist().unwrap());
+    }
+
+    #[test]
+    fn genesis_is_the_same_for_the_same_spec() {
+        let spec = spec_allocating("alice", 100, Ledger::Account);
+        let first = Blockchain::from_genesis(&spec);
+        let second = Blockchain::from_genesis(&spec);
+        assert_eq!(
+            codec::encode(first.get_latest_block()),
+            codec::encode(second.get_latest_block())
+        );
+
+        let other = Blockchain::from_genesis(&GenesisSpec {
+            chain_id: "other".to_string(),
+            ..spec
+        });
+        assert_ne!(first.get_latest_block().hash, other.get_latest_block().hash);
+    }
+
+    #[test]
+    fn chains_spending_genesis_allocations_validate() {
+        let alice = Wallet::generate();
+        let mut blockchain = Blockchain::from_genesis(&spec_allocating(&alice.address(), 100, Ledger::Account));
+        assert_eq!(blockchain.balance_of(&alice.address()), 100);
+
+        blockchain
+            .add_transaction(alice.create_transaction("bob", 10, 1, 0))
+            .unwrap();
+        blockchain.mine_block("miner");
+        assert_eq!(blockchain.balance_of("bob"), 10);
+        assert_eq!(blockchain.validate_chain(), Ok(()));
+    }
+
+    #[test]
+    fn utxo_chains_spending_genesis_allocations_validate() {
+        let alice = Wallet::generate();
+        let mut blockchain = Blockchain::from_genesis(&spec_allocating(&alice.address(), 100, Ledger::Utxo));
+
+        let spendable = blockchain.spendable_outputs(&alice.address());
+        let transaction = alice.create_utxo_transaction(&spendable, "bob", 10, 1).unwrap();
+        blockchain.add_transaction(transaction).unwrap();
+        blockchain.mine_block("miner");
+        assert_eq!(blockchain.balance_of("bob"), 10);
+        assert_eq!(blockchain.balance_of(&alice.address()), 89);
+        assert_eq!(blockchain.validate_chain(), Ok(()));
     }
-}
 
+    #[test]
+    fn stores_from_before_canonical_transaction_ids_are_too_old() {
+        let alice = Wallet::generate();
+        let mut blockchain = chain_in_memory_store(&alice);
+        blockchain.mine_block("miner");
+        blockchain.save().unwrap();
+        let metadata = blockchain.store().unwrap().load_metadata().unwrap().unwrap();
+
+        // The same blocks with transaction hashes that are not their ids
+        let mut store = MemoryStore::default();
+        let mut hashes = Vec::new();
+        for block in &blockchain.chain {
+            let mut block = block.clone();
+            for transaction in &mut block.transactions {
+                transaction.hash = vec![0; 32];
+            }
+            store.put_block(&block).unwrap();
+            hashes.push(block.hash.unwrap());
+        }
+        store.set_best_chain(0, &hashes).unwrap();
+        store.save_metadata(&metadata).unwrap();
+        let error = Blockchain::open(store).err().unwrap();
+        assert!(matches!(error.downcast_ref::<StoreError>(), Some(StoreError::FormatTooOld)));
+
+        // Stores older than the binary format hold JSON blocks
+        let json = serde_json::to_vec(&blockchain.chain[1]).unwrap();
+        assert!(matches!(crate::storage::decode_block(&json), Err(StoreError::FormatTooOld)));
+    }
+
+    // Two chains from the same genesis, mining `mined` blocks each
+    fn rival_chains(spec: &GenesisSpec, mined: (usize, usize)) -> (Blockchain, Blockchain) {
+        let (mut first, mut second) = (Blockchain::from_genesis(spec), Blockchain::from_genesis(spec));
+        for _ in 0..mined.0 {
+            first.mine_block("first");
+        }
+        for _ in 0..mined.1 {
+            second.mine_block("second");
+        }
+        (first, second)
+    }
+
+    #[test]
+    fn blocks_with_less_work_stay_on_a_side_branch() {
+        let (mut blockchain, other) = rival_chains(&spec_allocating("alice", 0, Ledger::Account), (2, 1));
+        let tip = blockchain.get_latest_block().hash.clone();
+
+        let side_block = other.get_chain()[1].clone();
+        assert_eq!(blockchain.submit_block(side_block.clone()), Ok(SubmitOutcome::SideBranch));
+        assert_eq!(blockchain.get_latest_block().hash, tip);
+        assert!(blockchain.side_blocks.contains_key(&block_hash(&side_block)));
+        assert_eq!(blockchain.submit_block(side_block), Err(BlockError::AlreadyKnown));
+        assert_eq!(blockchain.submit_block(blockchain.chain[1].clone()), Err(BlockError::AlreadyKnown));
+    }
+
+    #[test]
+    fn the_first_seen_chain_wins_a_tie() {
+        let (mut blockchain, other) = rival_chains(&spec_allocating("alice", 0, Ledger::Account), (1, 1));
+        let tip = blockchain.get_latest_block().hash.clone();
+
+        assert_eq!(blockchain.submit_block(other.get_chain()[1].clone()), Ok(SubmitOutcome::SideBranch));
+        assert_eq!(blockchain.get_latest_block().hash, tip);
+    }
+
+    #[test]
+    fn reorgs_return_orphaned_transactions_to_the_mempool() {
+        let alice = Wallet::generate();
+        let spec = spec_allocating(&alice.address(), 100, Ledger::Account);
+        let (mut blockchain, other) = rival_chains(&spec, (0, 2));
+        let transaction = alice.create_transaction("bob", 10, 1, 0);
+        blockchain.add_transaction(transaction.clone()).unwrap();
+        blockchain.mine_block("first");
+        assert_eq!(blockchain.balance_of("bob"), 10);
+        assert!(blockchain.mempool().is_empty());
+
+        assert_eq!(blockchain.submit_block(other.get_chain()[1].clone()), Ok(SubmitOutcome::SideBranch));
+        assert_eq!(
+            blockchain.submit_block(other.get_chain()[2].clone()),
+            Ok(SubmitOutcome::Reorg {
+                disconnected: 1,
+                connected: 2
+            })
+        );
+        assert_eq!(blockchain.get_latest_block().hash, other.get_latest_block().hash);
+        assert_eq!(blockchain.balance_of("bob"), 0);
+        assert!(blockchain.mempool().contains(&transaction.hash));
+        assert_eq!(blockchain.validate_chain(), Ok(()));
+
+        // The orphaned transaction goes into the next block on the new chain
+        blockchain.mine_block("first");
+        assert_eq!(blockchain.balance_of("bob"), 10);
+    }
+
+    #[test]
+    fn blocks_with_an_unknown_parent_are_rejected() {
+        let (mut blockchain, other) = rival_chains(&spec_allocating("alice", 0, Ledger::Account), (0, 2));
+        assert_eq!(blockchain.submit_block(other.get_chain()[2].clone()), Err(BlockError::UnknownParent));
+        assert_eq!(blockchain.get_chain_length(), 1);
+        assert!(blockchain.side_blocks.is_empty());
+    }
+
+    #[test]
+    fn forks_deeper_than_the_reorg_depth_are_dropped_and_refused() {
+        let (mut blockchain, other) = rival_chains(&spec_allocating("alice", 0, Ledger::Account), (1, 2));
+        assert_eq!(blockchain.submit_block(other.get_chain()[1].clone()), Ok(SubmitOutcome::SideBranch));
+
+        for _ in 0..MAX_REORG_DEPTH {
+            blockchain.mine_block("first");
+        }
+        assert_eq!(blockchain.side_blocks.len(), 1);
+        blockchain.mine_block("first");
+        assert!(blockchain.side_blocks.is_empty());
+
+        assert_eq!(blockchain.submit_block(other.get_chain()[2].clone()), Err(BlockError::UnknownParent));
+        assert_eq!(blockchain.submit_block(other.get_chain()[1].clone()), Err(BlockError::StaleFork));
+        assert_eq!(blockchain.validate_chain(), Ok(()));
+    }
+}
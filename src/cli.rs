@@ -0,0 +1,587 @@
+use clap::ValueEnum;
+use rust_chain::block::Block;
+use rust_chain::block_log::{BlockLog, BLOCK_LOG_FILE, METADATA_FILE};
+use rust_chain::blockchain::Blockchain;
+use rust_chain::codec;
+use rust_chain::config::{ChainConfig, Ledger};
+use rust_chain::genesis::GenesisSpec;
+use rust_chain::merkle_proof::InclusionProof;
+use rust_chain::p2p::SharedChain;
+use rust_chain::redb_store::{RedbStore, DATABASE_FILE};
+use rust_chain::rpc::{RpcHandler, RpcServer};
+use rust_chain::storage::{DynBlockStore, PersistencePolicy, StoreError};
+use rust_chain::transaction::Transaction;
+use rust_chain::utils::{from_hex_string, to_hex_string};
+use rust_chain::wallet::{Keystore, Wallet};
+use serde_json::json;
+use std::error::Error;
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+// Exit codes besides 0 for success. Usage errors exit with 2, from clap.
+pub const EXIT_FAILURE: u8 = 1;
+// The chain or a proof did not verify
+pub const EXIT_INVALID: u8 = 3;
+
+pub type CommandResult = Result<ExitCode, Box<dyn Error>>;
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    Json,
+    Table,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LedgerArg {
+    Account,
+    Utxo,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Encoding {
+    Json,
+    Binary,
+}
+
+// How the data directory stores the blocks, see storage.rs.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StoreArg {
+    // An append-only log file, blocks.log
+    Log,
+    // A redb database, chain.redb
+    Redb,
+}
+
+// Options shared by every command.
+pub struct Context {
+    pub data_dir: PathBuf,
+    pub format: Format,
+    pub password: Option<String>,
+}
+
+impl Context {
+    // The keystore password, which only commands that unlock or add wallets need.
+    pub fn password(&self) -> Result<&str, Box<dyn Error>> {
+        self.password
+            .as_deref()
+            .ok_or_else(|| "the keystore password is needed, set RUST_CHAIN_PASSWORD or give --password".into())
+    }
+
+    pub fn chain_dir(&self) -> String {
+        self.data_dir.to_string_lossy().into_owned()
+    }
+
+    pub fn has_chain(&self) -> bool {
+        self.data_dir.join(BLOCK_LOG_FILE).exists() || self.data_dir.join(DATABASE_FILE).exists()
+    }
+
+    // A new, empty store of the given kind in the data directory, replacing
+    // the chain there whatever kind of store it had.
+    pub fn create_store(&self, kind: StoreArg) -> Result<DynBlockStore, StoreError> {
+        for file in [BLOCK_LOG_FILE, METADATA_FILE, DATABASE_FILE] {
+            let path = self.data_dir.join(file);
+            if path.exists() {
+                std::fs::remove_file(path)?;
+            }
+        }
+        Ok(match kind {
+            StoreArg::Log => Box::new(BlockLog::create(&self.data_dir)?),
+            StoreArg::Redb => Box::new(RedbStore::create(&self.data_dir)?),
+        })
+    }
+
+    // The store of the chain in the data directory, of whichever kind it is.
+    pub fn open_store(&self) -> Result<DynBlockStore, StoreError> {
+        Ok(if self.data_dir.join(DATABASE_FILE).exists() {
+            Box::new(RedbStore::open(&self.data_dir)?)
+        } else {
+            let log = BlockLog::open(&self.data_dir)?;
+            if log.truncated_bytes() > 0 {
+                eprintln!(
+                    "Truncated a torn record at the end of {}, {} bytes",
+                    self.data_dir.join(BLOCK_LOG_FILE).display(),
+                    log.truncated_bytes()
+                );
+            }
+            Box::new(log)
+        })
+    }
+
+    pub fn keystore_path(&self) -> String {
+        self.data_dir.join("keystore.json").to_string_lossy().into_owned()
+    }
+
+    // The chain in the data directory, which `init` must have created.
+    pub fn load_chain(&self) -> Result<Blockchain, Box<dyn Error>> {
+        if !self.has_chain() {
+            return Err(format!("no chain in {}, run `rust_chain init` first", self.chain_dir()).into());
+        }
+        Blockchain::open(self.open_store()?)
+    }
+
+    pub fn load_keystore(&self) -> Result<Keystore, Box<dyn Error>> {
+        Keystore::load_from_file(&self.keystore_path())
+    }
+
+    pub fn print_json(&self, value: &impl serde::Serialize) -> Result<(), Box<dyn Error>> {
+        println!("{}", serde_json::to_string_pretty(value)?);
+        Ok(())
+    }
+}
+
+// A wallet name from the keystore, or else an address as is.
+pub fn resolve_address(keystore: &Keystore, name_or_address: &str) -> String {
+    keystore
+        .address_of(name_or_address)
+        .unwrap_or(name_or_address)
+        .to_string()
+}
+
+// Sign a transaction from the named wallet for the ledger model of the chain.
+pub fn build_transaction(
+    ctx: &Context,
+    blockchain: &Blockchain,
+    keystore: &Keystore,
+    from: &str,
+    to: &str,
+    amount: u64,
+    fee: u64,
+) -> Result<Transaction, Box<dyn Error>> {
+    let sender = keystore.unlock(from, ctx.password()?)?;
+    let receiver = resolve_address(keystore, to);
+
+    let transaction = match blockchain.get_config().ledger {
+        Ledger::Account => {
+            let nonce = blockchain.next_nonce(&sender.address());
+            sender.create_transaction(&receiver, amount, fee, nonce)
+        }
+        Ledger::Utxo => {
+            let spendable = blockchain.spendable_outputs(&sender.address());
+            sender.create_utxo_transaction(&spendable, &receiver, amount, fee)?
+        }
+    };
+    Ok(transaction)
+}
+
+// A new chain in the data directory, from a genesis spec file if one is
+// given, else from the default genesis with the chosen ledger.
+pub fn init(ctx: &Context, ledger: LedgerArg, genesis: Option<&PathBuf>, store: StoreArg, force: bool) -> CommandResult {
+    let path = ctx.chain_dir();
+    if ctx.has_chain() && !force {
+        return Err(format!("{} already holds a chain, pass --force to replace it", path).into());
+    }
+
+    let spec = match genesis {
+        Some(genesis) => GenesisSpec::load_from_file(&genesis.to_string_lossy())?,
+        None => GenesisSpec {
+            consensus: ChainConfig {
+                ledger: match ledger {
+                    LedgerArg::Account => Ledger::Account,
+                    LedgerArg::Utxo => Ledger::Utxo,
+                },
+                ..ChainConfig::default()
+            },
+            ..GenesisSpec::default()
+        },
+    };
+    let blockchain = Blockchain::with_store(&spec, ctx.create_store(store)?)?;
+
+    let ledger = blockchain.get_config().ledger;
+    let genesis = block_hash(blockchain.get_latest_block());
+    match ctx.format {
+        Format::Json => ctx.print_json(&json!({
+            "path": path,
+            "chain_id": blockchain.chain_id(),
+            "ledger": ledger,
+            "store": store.to_possible_value().map(|value| value.get_name().to_string()),
+            "genesis": genesis,
+        }))?,
+        Format::Table => {
+            println!("Created a new {:?} chain {} in {}, stored as {:?}", ledger, blockchain.chain_id(), path, store);
+            println!("Genesis block {}", genesis);
+        }
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+pub fn wallet_new(ctx: &Context, name: &str) -> CommandResult {
+    let mut keystore = ctx.load_keystore()?;
+    let wallet = Wallet::generate();
+    keystore.add(name, &wallet, ctx.password()?)?;
+    keystore.save_to_file(&ctx.keystore_path())?;
+
+    match ctx.format {
+        Format::Json => ctx.print_json(&json!({ "name": name, "address": wallet.address() }))?,
+        Format::Table => println!("Created wallet {} with address {}", name, wallet.address()),
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+pub fn wallet_list(ctx: &Context) -> CommandResult {
+    let keystore = ctx.load_keystore()?;
+    let blockchain = ctx.load_chain()?;
+    let wallets = keystore
+        .names()
+        .map(|name| {
+            let address = keystore.address_of(name).unwrap_or_default();
+            (name.clone(), address.to_string(), blockchain.balance_of(address))
+        })
+        .collect::<Vec<_>>();
+
+    match ctx.format {
+        Format::Json => ctx.print_json(
+            &wallets
+                .iter()
+                .map(|(name, address, balance)| json!({ "name": name, "address": address, "balance": balance }))
+                .collect::<Vec<_>>(),
+        )?,
+        Format::Table => print_table(
+            &["NAME", "ADDRESS", "BALANCE"],
+            wallets
+                .into_iter()
+                .map(|(name, address, balance)| vec![name, address, balance.to_string()])
+                .collect(),
+        ),
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+pub fn send(ctx: &Context, from: &str, to: &str, amount: u64, fee: u64) -> CommandResult {
+    let mut blockchain = ctx.load_chain()?;
+    let keystore = ctx.load_keystore()?;
+    let transaction = build_transaction(ctx, &blockchain, &keystore, from, to, amount, fee)?;
+    let hash = to_hex_string(&transaction.hash);
+    blockchain.add_transaction(transaction)?;
+    blockchain.save()?;
+
+    match ctx.format {
+        Format::Json => ctx.print_json(&json!({ "hash": hash }))?,
+        Format::Table => println!("Transaction {} added to the mempool", hash),
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+pub fn mine(ctx: &Context, miner: Option<&str>) -> CommandResult {
+    let mut blockchain = ctx.load_chain()?;
+    let keystore = ctx.load_keystore()?;
+    let miner_address = match miner {
+        Some(miner) => resolve_address(&keystore, miner),
+        None => blockchain
+            .miner_address()
+            .ok_or("the chain has no miner address, pass --miner")?
+            .to_string(),
+    };
+
+    let block = blockchain.mine_block(&miner_address).clone();
+    blockchain.save()?;
+    print_block(ctx, &block)?;
+    Ok(ExitCode::SUCCESS)
+}
+
+pub fn show_block(ctx: &Context, id_or_hash: &str) -> CommandResult {
+    let blockchain = ctx.load_chain()?;
+    let block = match id_or_hash.parse::<u32>() {
+        Ok(id) => blockchain.get_block_by_id(id),
+        Err(_) => from_hex_string(id_or_hash).and_then(|hash| blockchain.get_block_by_hash(&hash)),
+    };
+    let block = block.ok_or_else(|| format!("block {} not found", id_or_hash))?;
+    print_block(ctx, block)?;
+    Ok(ExitCode::SUCCESS)
+}
+
+pub fn show_chain(ctx: &Context) -> CommandResult {
+    let blockchain = ctx.load_chain()?;
+    match ctx.format {
+        Format::Json => ctx.print_json(
+            &blockchain
+                .get_chain()
+                .iter()
+                .map(Block::to_serializable)
+                .collect::<Vec<_>>(),
+        )?,
+        Format::Table => print_table(
+            &["ID", "TIME", "TXS", "DIFFICULTY", "HASH"],
+            blockchain
+                .get_chain()
+                .iter()
+                .map(|block| {
+                    vec![
+                        block.id.to_string(),
+                        format_time(block.timestamp),
+                        block.transactions.len().to_string(),
+                        block.difficulty.to_string(),
+                        block_hash(block),
+                    ]
+                })
+                .collect(),
+        ),
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+pub fn validate(ctx: &Context) -> CommandResult {
+    let blockchain = ctx.load_chain()?;
+    let result = blockchain.validate_chain();
+    match (ctx.format, &result) {
+        (Format::Json, _) => ctx.print_json(&json!({
+            "valid": result.is_ok(),
+            "height": blockchain.get_block_height(),
+            "invalid_block": result.as_ref().err().map(|error| error.block_id()),
+            "error": result.as_ref().err().map(|error| error.to_string()),
+        }))?,
+        (Format::Table, Ok(())) => println!("Chain of {} blocks is valid", blockchain.get_chain_length()),
+        (Format::Table, Err(error)) => println!("Chain is invalid, {}", error),
+    }
+    Ok(exit_code(result.is_ok()))
+}
+
+pub fn prove(ctx: &Context, tx_hash: &str, output: Option<&PathBuf>) -> CommandResult {
+    let blockchain = ctx.load_chain()?;
+    let hash = from_hex_string(tx_hash).ok_or("the transaction hash must be hex")?;
+    let proof = blockchain
+        .prove_transaction(&hash)
+        .ok_or_else(|| format!("transaction {} is not on the chain", tx_hash))?;
+
+    if let Some(output) = output {
+        std::fs::write(output, serde_json::to_string_pretty(&proof)?)?;
+        if ctx.format == Format::Table {
+            println!("Proof for block {} written to {}", proof.block_height, output.display());
+        }
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    match ctx.format {
+        Format::Json => ctx.print_json(&proof)?,
+        Format::Table => {
+            println!("Block        {}", proof.block_height);
+            println!("Merkle root  {}", proof.merkle_root);
+            println!("Tree         {:?}", proof.variant);
+            println!("Leaf         {}", proof.leaf);
+            print_table(
+                &["STEP", "SIBLING", "HASH"],
+                proof
+                    .path
+                    .iter()
+                    .enumerate()
+                    .map(|(i, step)| {
+                        let side = if step.is_right { "left" } else { "right" };
+                        vec![i.to_string(), side.to_string(), step.hash.clone()]
+                    })
+                    .collect(),
+            );
+        }
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+// Check a proof written by `prove`: the path has to hash up to its Merkle
+// root, and that root has to be the one of the block it names on our chain.
+pub fn verify_proof(ctx: &Context, file: &PathBuf) -> CommandResult {
+    let proof: InclusionProof = serde_json::from_str(&std::fs::read_to_string(file)?)?;
+    let (merkle_root, merkle_proof) = proof.decode().ok_or("the proof contains a hash that is not hex")?;
+    let blockchain = ctx.load_chain()?;
+
+    let verifies = merkle_proof.verify(&merkle_root);
+    let in_chain = blockchain
+        .get_block_by_id(proof.block_height)
+        .is_some_and(|block| block.merkle_root.as_ref() == Some(&merkle_root));
+
+    match ctx.format {
+        Format::Json => ctx.print_json(&json!({
+            "valid": verifies && in_chain,
+            "verifies": verifies,
+            "in_chain": in_chain,
+        }))?,
+        Format::Table if !verifies => println!("Proof does not hash up to its Merkle root"),
+        Format::Table if !in_chain => {
+            println!("Merkle root is not the one of block {} on the chain", proof.block_height)
+        }
+        Format::Table => println!("Proof is valid, the transaction is in block {}", proof.block_height),
+    }
+    Ok(exit_code(verifies && in_chain))
+}
+
+pub fn mempool(ctx: &Context) -> CommandResult {
+    let blockchain = ctx.load_chain()?;
+    let mempool = blockchain.mempool();
+    match ctx.format {
+        Format::Json => ctx.print_json(&mempool.iter().map(Transaction::to_serializable).collect::<Vec<_>>())?,
+        Format::Table => {
+            println!("{} pending transactions, {} bytes", mempool.len(), mempool.total_bytes());
+            print_transactions(&mempool.iter().collect::<Vec<_>>());
+        }
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+// Write the best chain to a file, as JSON or in the binary format.
+pub fn export(ctx: &Context, output: &PathBuf, encoding: Encoding) -> CommandResult {
+    let blockchain = ctx.load_chain()?;
+    let chain = blockchain.get_chain();
+    let bytes = match encoding {
+        Encoding::Json => serde_json::to_vec_pretty(chain)?,
+        Encoding::Binary => codec::encode(chain),
+    };
+    std::fs::write(output, &bytes)?;
+
+    match ctx.format {
+        Format::Json => ctx.print_json(&json!({
+            "path": output,
+            "blocks": chain.len(),
+            "bytes": bytes.len(),
+        }))?,
+        Format::Table => println!("Wrote {} blocks to {}, {} bytes", chain.len(), output.display(), bytes.len()),
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+// Convert a chain, block, transaction or Merkle proof file from JSON to the
+// binary format, or back.
+pub fn convert(ctx: &Context, input: &PathBuf, output: &PathBuf) -> CommandResult {
+    let bytes = std::fs::read(input)?;
+    let (converted, encoding) = if codec::is_binary(&bytes) {
+        (codec::binary_to_json(&bytes)?, Encoding::Json)
+    } else {
+        (codec::json_to_binary(&bytes)?, Encoding::Binary)
+    };
+    std::fs::write(output, &converted)?;
+
+    match ctx.format {
+        Format::Json => ctx.print_json(&json!({
+            "path": output,
+            "encoding": encoding.to_possible_value().map(|value| value.get_name().to_string()),
+            "bytes_in": bytes.len(),
+            "bytes_out": converted.len(),
+        }))?,
+        Format::Table => println!(
+            "Converted {} ({} bytes) to {:?} in {} ({} bytes)",
+            input.display(),
+            bytes.len(),
+            encoding,
+            output.display(),
+            converted.len()
+        ),
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+// Serve the chain over JSON-RPC until the process is interrupted or
+// terminated, saving it after every change or every `save_every` seconds, and
+// once more on the way out.
+pub fn rpc(ctx: &Context, address: &str, save_every: Option<u64>) -> CommandResult {
+    let mut blockchain = ctx.load_chain()?;
+    blockchain.set_persistence_policy(match save_every {
+        Some(seconds) => PersistencePolicy::Periodic(Duration::from_secs(seconds)),
+        None => PersistencePolicy::OnEveryBlock,
+    });
+    let chain: SharedChain = Arc::new(Mutex::new(blockchain));
+    let server = RpcServer::start(RpcHandler::new(chain.clone()), address)?;
+    eprintln!("JSON-RPC server listening on http://{}", server.local_addr());
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let stopping = stop.clone();
+    ctrlc::set_handler(move || stopping.store(true, Ordering::SeqCst))?;
+    // Requests only save when they change something, a periodic save is
+    // due with time alone
+    while !stop.load(Ordering::SeqCst) {
+        std::thread::sleep(Duration::from_millis(250));
+        if let Err(e) = chain.lock().unwrap().persist() {
+            eprintln!("Failed to save the chain: {}", e);
+        }
+    }
+
+    server.shutdown();
+    chain.lock().unwrap().save()?;
+    eprintln!("Saved the chain, shutting down");
+    Ok(ExitCode::SUCCESS)
+}
+
+fn exit_code(valid: bool) -> ExitCode {
+    if valid {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::from(EXIT_INVALID)
+    }
+}
+
+pub fn print_block(ctx: &Context, block: &Block) -> Result<(), Box<dyn Error>> {
+    if ctx.format == Format::Json {
+        return ctx.print_json(&block.to_serializable());
+    }
+    let hex = |hash: &Option<Vec<u8>>| hash.as_deref().map_or_else(String::new, to_hex_string);
+    println!("Block        {}", block.id);
+    println!("Version      {}", block.version);
+    println!("Time         {}", format_time(block.timestamp));
+    println!("Hash         {}", hex(&block.hash));
+    println!("Previous     {}", hex(&block.previous_hash));
+    println!("Merkle root  {}", hex(&block.merkle_root));
+    println!("Difficulty   {}", block.difficulty);
+    println!("Nonce        {}", block.nonce);
+    print_transactions(&block.transactions.iter().collect::<Vec<_>>());
+    Ok(())
+}
+
+pub fn print_transactions(transactions: &[&Transaction]) {
+    print_table(
+        &["HASH", "FROM", "TO", "AMOUNT", "FEE", "NONCE"],
+        transactions
+            .iter()
+            .map(|transaction| {
+                // UTXO transactions pay their outputs instead of a receiver
+                let (to, amount) = if transaction.outputs().is_empty() {
+                    (transaction.receiver().to_string(), transaction.amount().to_string())
+                } else {
+                    let addresses = transaction.outputs().iter().map(|output| output.address.as_str());
+                    let total = transaction
+                        .output_total()
+                        .map_or_else(|| "overflow".to_string(), |total| total.to_string());
+                    (addresses.collect::<Vec<_>>().join(","), total)
+                };
+                let from = if transaction.is_coinbase() { "coinbase" } else { transaction.sender() };
+                vec![
+                    to_hex_string(&transaction.hash),
+                    from.to_string(),
+                    to,
+                    amount,
+                    transaction.fee().to_string(),
+                    transaction.nonce().to_string(),
+                ]
+            })
+            .collect(),
+    );
+}
+
+// Print rows in columns padded to the widest cell.
+pub fn print_table(headers: &[&str], rows: Vec<Vec<String>>) {
+    let mut widths = headers.iter().map(|header| header.len()).collect::<Vec<_>>();
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.len());
+        }
+    }
+
+    let format_row = |cells: Vec<&str>| {
+        let padded = cells
+            .iter()
+            .zip(&widths)
+            .map(|(cell, width)| format!("{:<width$}", cell, width = width))
+            .collect::<Vec<_>>();
+        padded.join("  ").trim_end().to_string()
+    };
+    println!("{}", format_row(headers.to_vec()));
+    for row in &rows {
+        println!("{}", format_row(row.iter().map(String::as_str).collect()));
+    }
+}
+
+pub fn format_time(timestamp: i64) -> String {
+    chrono::DateTime::from_timestamp(timestamp, 0)
+        .map_or_else(|| timestamp.to_string(), |time| time.format("%Y-%m-%d %H:%M:%S").to_string())
+}
+
+pub fn block_hash(block: &Block) -> String {
+    block.hash.as_deref().map_or_else(String::new, to_hex_string)
+}
@@ -0,0 +1,537 @@
+use crate::block::Block;
+use crate::merkle_proof::MerkleProof;
+use crate::transaction::Transaction;
+use std::fmt;
+
+// Compact binary encoding of blocks, transactions, Merkle proofs and whole
+// chains. Every encoded value starts with a header:
+//
+//   magic    4 bytes  "RCHN"
+//   version  1 byte   FORMAT_VERSION
+//   kind     1 byte   what follows, see Kind
+//
+// followed by the fields of the value in a fixed order. Integers are little
+// endian with a fixed width, byte strings and UTF-8 strings are prefixed with
+// their length as a u32, optional values with a 0 or 1 byte, and lists with
+// their number of items as a u32. There is exactly one encoding of each
+// value, so encoding the decoded bytes gives the same bytes back.
+//
+// Version 2 added the version of a block and the tree variant of a Merkle
+// proof. Version 1 data is still decoded, as legacy blocks and proofs. Chains
+// from before canonical transaction ids were version 1 as well, but their
+// blocks do not validate, see StoreError::FormatTooOld.
+pub const MAGIC: [u8; 4] = *b"RCHN";
+pub const FORMAT_VERSION: u8 = 2;
+const OLDEST_FORMAT_VERSION: u8 = 1;
+const HEADER_LEN: usize = 6;
+
+// What an encoded value holds, the last byte of the header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    Block = 1,
+    Transaction = 2,
+    MerkleProof = 3,
+    // The blocks of a chain from genesis to the tip
+    Chain = 4,
+}
+
+impl Kind {
+    fn from_byte(byte: u8) -> Option<Kind> {
+        match byte {
+            1 => Some(Kind::Block),
+            2 => Some(Kind::Transaction),
+            3 => Some(Kind::MerkleProof),
+            4 => Some(Kind::Chain),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum CodecError {
+    // The data does not start with MAGIC
+    BadMagic,
+    UnsupportedVersion(u8),
+    UnknownKind(u8),
+    WrongKind { expected: Kind, found: Kind },
+    // The data ends in the middle of a value
+    Truncated,
+    // An option or bool byte other than 0 or 1
+    InvalidTag(u8),
+    InvalidUtf8,
+    // Bytes left over after the value
+    TrailingBytes(usize),
+}
+
+impl fmt::Display for CodecError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CodecError::BadMagic => write!(f, "data is not in the rust_chain binary format"),
+            CodecError::UnsupportedVersion(version) => write!(f, "binary format version {} is not supported", version),
+            CodecError::UnknownKind(kind) => write!(f, "unknown kind of value {}", kind),
+            CodecError::WrongKind { expected, found } => write!(f, "expected a {:?} but found a {:?}", expected, found),
+            CodecError::Truncated => write!(f, "data ends in the middle of a value"),
+            CodecError::InvalidTag(tag) => write!(f, "invalid tag byte {}", tag),
+            CodecError::InvalidUtf8 => write!(f, "string is not valid UTF-8"),
+            CodecError::TrailingBytes(count) => write!(f, "{} bytes left over after the value", count),
+        }
+    }
+}
+
+impl std::error::Error for CodecError {}
+
+// A value with a binary encoding. Types implement it next to their
+// definition, writing their fields in declaration order.
+pub trait Encode {
+    fn encode_to(&self, writer: &mut Writer);
+}
+
+pub trait Decode: Sized {
+    fn decode_from(reader: &mut Reader) -> Result<Self, CodecError>;
+}
+
+// A value that can be encoded on its own, with a header.
+pub trait Encodable: Encode + Decode {
+    const KIND: Kind;
+}
+
+impl Encodable for Block {
+    const KIND: Kind = Kind::Block;
+}
+
+impl Encodable for Transaction {
+    const KIND: Kind = Kind::Transaction;
+}
+
+impl Encodable for MerkleProof {
+    const KIND: Kind = Kind::MerkleProof;
+}
+
+impl Encodable for Vec<Block> {
+    const KIND: Kind = Kind::Chain;
+}
+
+// Encode a value with its header.
+pub fn encode<T: Encodable>(value: &T) -> Vec<u8> {
+    let mut writer = Writer::default();
+    writer.bytes_raw(&MAGIC);
+    writer.u8(FORMAT_VERSION);
+    writer.u8(T::KIND as u8);
+    value.encode_to(&mut writer);
+    writer.into_bytes()
+}
+
+// Decode a value encoded with `encode`, which must use all of `bytes`.
+pub fn decode<T: Encodable>(bytes: &[u8]) -> Result<T, CodecError> {
+    let found = kind_of(bytes)?;
+    if found != T::KIND {
+        return Err(CodecError::WrongKind {
+            expected: T::KIND,
+            found,
+        });
+    }
+    let mut reader = Reader::with_format_version(&bytes[HEADER_LEN..], bytes[4]);
+    let value = T::decode_from(&mut reader)?;
+    match reader.remaining() {
+        0 => Ok(value),
+        count => Err(CodecError::TrailingBytes(count)),
+    }
+}
+
+// True if `bytes` start with the header of the binary format.
+pub fn is_binary(bytes: &[u8]) -> bool {
+    bytes.starts_with(&MAGIC)
+}
+
+// Check the header and tell what kind of value follows it.
+pub fn kind_of(bytes: &[u8]) -> Result<Kind, CodecError> {
+    if !is_binary(bytes) {
+        return Err(CodecError::BadMagic);
+    }
+    let header = bytes.get(..HEADER_LEN).ok_or(CodecError::Truncated)?;
+    if !(OLDEST_FORMAT_VERSION..=FORMAT_VERSION).contains(&header[4]) {
+        return Err(CodecError::UnsupportedVersion(header[4]));
+    }
+    Kind::from_byte(header[5]).ok_or(CodecError::UnknownKind(header[5]))
+}
+
+// Convert a value in the binary format to the JSON serde gives it.
+pub fn binary_to_json(bytes: &[u8]) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let json = match kind_of(bytes)? {
+        Kind::Block => serde_json::to_vec_pretty(&decode::<Block>(bytes)?)?,
+        Kind::Transaction => serde_json::to_vec_pretty(&decode::<Transaction>(bytes)?)?,
+        Kind::MerkleProof => serde_json::to_vec_pretty(&decode::<MerkleProof>(bytes)?)?,
+        Kind::Chain => serde_json::to_vec_pretty(&decode::<Vec<Block>>(bytes)?)?,
+    };
+    Ok(json)
+}
+
+// Convert the JSON of a chain, block, transaction or Merkle proof to the
+// binary format. JSON does not say what it holds, so the first kind it
+// parses as wins; they have no required fields in common.
+pub fn json_to_binary(json: &[u8]) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    if let Ok(chain) = serde_json::from_slice::<Vec<Block>>(json) {
+        return Ok(encode(&chain));
+    }
+    if let Ok(block) = serde_json::from_slice::<Block>(json) {
+        return Ok(encode(&block));
+    }
+    if let Ok(transaction) = serde_json::from_slice::<Transaction>(json) {
+        return Ok(encode(&transaction));
+    }
+    match serde_json::from_slice::<MerkleProof>(json) {
+        Ok(proof) => Ok(encode(&proof)),
+        Err(_) => Err("not the JSON of a chain, block, transaction or Merkle proof".into()),
+    }
+}
+
+#[derive(Default)]
+pub struct Writer {
+    bytes: Vec<u8>,
+}
+
+impl Writer {
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.bytes
+    }
+
+    fn bytes_raw(&mut self, bytes: &[u8]) {
+        self.bytes.extend_from_slice(bytes);
+    }
+
+    pub fn u8(&mut self, value: u8) {
+        self.bytes.push(value);
+    }
+
+    pub fn bool(&mut self, value: bool) {
+        self.u8(u8::from(value));
+    }
+
+    pub fn u32(&mut self, value: u32) {
+        self.bytes_raw(&value.to_le_bytes());
+    }
+
+    pub fn u64(&mut self, value: u64) {
+        self.bytes_raw(&value.to_le_bytes());
+    }
+
+    pub fn i64(&mut self, value: i64) {
+        self.bytes_raw(&value.to_le_bytes());
+    }
+
+    pub fn bytes(&mut self, value: &[u8]) {
+        self.u32(value.len() as u32);
+        self.bytes_raw(value);
+    }
+
+    pub fn string(&mut self, value: &str) {
+        self.bytes(value.as_bytes());
+    }
+
+    pub fn option_bytes(&mut self, value: Option<&[u8]>) {
+        match value {
+            Some(bytes) => {
+                self.u8(1);
+                self.bytes(bytes);
+            }
+            None => self.u8(0),
+        }
+    }
+
+    pub fn list<T: Encode>(&mut self, items: &[T]) {
+        self.u32(items.len() as u32);
+        for item in items {
+            item.encode_to(self);
+        }
+    }
+}
+
+pub struct Reader<'a> {
+    data: &'a [u8],
+    format_version: u8,
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self::with_format_version(data, FORMAT_VERSION)
+    }
+
+    // A reader of data written in an older version of the format.
+    pub fn with_format_version(data: &'a [u8], format_version: u8) -> Self {
+        Reader { data, format_version }
+    }
+
+    // Version of the format the data is in, for the fields added since.
+    pub fn format_version(&self) -> u8 {
+        self.format_version
+    }
+
+    pub fn remaining(&self) -> usize {
+        self.data.len()
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], CodecError> {
+        if len > self.data.len() {
+            return Err(CodecError::Truncated);
+        }
+        let (taken, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(taken)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], CodecError> {
+        let mut array = [0; N];
+        array.copy_from_slice(self.take(N)?);
+        Ok(array)
+    }
+
+    pub fn u8(&mut self) -> Result<u8, CodecError> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn bool(&mut self) -> Result<bool, CodecError> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            tag => Err(CodecError::InvalidTag(tag)),
+        }
+    }
+
+    pub fn u32(&mut self) -> Result<u32, CodecError> {
+        Ok(u32::from_le_bytes(self.array()?))
+    }
+
+    pub fn u64(&mut self) -> Result<u64, CodecError> {
+        Ok(u64::from_le_bytes(self.array()?))
+    }
+
+    pub fn i64(&mut self) -> Result<i64, CodecError> {
+        Ok(i64::from_le_bytes(self.array()?))
+    }
+
+    pub fn bytes(&mut self) -> Result<Vec<u8>, CodecError> {
+        let len = self.u32()? as usize;
+        Ok(self.take(len)?.to_vec())
+    }
+
+    pub fn string(&mut self) -> Result<String, CodecError> {
+        String::from_utf8(self.bytes()?).map_err(|_| CodecError::InvalidUtf8)
+    }
+
+    pub fn option_bytes(&mut self) -> Result<Option<Vec<u8>>, CodecError> {
+        match self.u8()? {
+            0 => Ok(None),
+            1 => Ok(Some(self.bytes()?)),
+            tag => Err(CodecError::InvalidTag(tag)),
+        }
+    }
+
+    pub fn list<T: Decode>(&mut self) -> Result<Vec<T>, CodecError> {
+        let count = self.u32()? as usize;
+        // Every item takes at least a byte, so a corrupt count can not make
+        // us allocate more than the data could hold
+        let mut items = Vec::with_capacity(count.min(self.remaining()));
+        for _ in 0..count {
+            items.push(T::decode_from(self)?);
+        }
+        Ok(items)
+    }
+}
+
+impl<T: Encode> Encode for Vec<T> {
+    fn encode_to(&self, writer: &mut Writer) {
+        writer.list(self);
+    }
+}
+
+impl<T: Decode> Decode for Vec<T> {
+    fn decode_from(reader: &mut Reader) -> Result<Self, CodecError> {
+        reader.list()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::LEGACY_BLOCK_VERSION;
+    use crate::merkle_proof::MerkleVariant;
+    use crate::test_utils::mined_block;
+    use crate::utxo::{OutPoint, TxInput, TxOutput};
+    use crate::wallet::Wallet;
+
+    const NOW: i64 = 1_700_000_000;
+
+    fn genesis() -> Block {
+        mined_block(None, NOW, vec![Transaction::coinbase("miner".to_string(), 50, 0)])
+    }
+
+    fn account_transaction() -> Transaction {
+        Wallet::generate().create_transaction("bob", 10, 2, 7)
+    }
+
+    fn utxo_transaction() -> Transaction {
+        let input = TxInput {
+            previous_output: OutPoint {
+                txid: vec![3; 32],
+                index: 1,
+            },
+        };
+        let output = TxOutput {
+            address: "bob".to_string(),
+            amount: 40,
+        };
+        Transaction::new_utxo("alice".to_string(), vec![input], vec![output], 1)
+    }
+
+    // Decoding gives the value back, and encoding that again the same bytes
+    fn round_trip<T: Encodable + fmt::Debug>(value: &T) -> T {
+        let bytes = encode(value);
+        let decoded: T = decode(&bytes).unwrap();
+        assert_eq!(encode(&decoded), bytes);
+        decoded
+    }
+
+    #[test]
+    fn transactions_round_trip() {
+        for transaction in [account_transaction(), utxo_transaction(), Transaction::coinbase("miner".to_string(), 50, 3)] {
+            let decoded = round_trip(&transaction);
+            assert_eq!(
+                serde_json::to_value(&decoded).unwrap(),
+                serde_json::to_value(&transaction).unwrap()
+            );
+            assert_eq!(decoded.verify_signature(), transaction.verify_signature());
+            // The id does not depend on the hash field or the encoding
+            assert_eq!(decoded.id(), transaction.hash);
+            assert_eq!(serde_json::from_value::<Transaction>(serde_json::to_value(&transaction).unwrap()).unwrap().id(), transaction.hash);
+        }
+    }
+
+    #[test]
+    fn blocks_round_trip() {
+        let genesis = genesis();
+        let decoded = round_trip(&genesis);
+        assert_eq!(decoded.previous_hash, None);
+        assert_eq!(decoded.hash, genesis.hash);
+        assert_eq!(decoded.calculate_hash(), genesis.hash);
+
+        let transactions = vec![Transaction::coinbase("miner".to_string(), 53, 1), account_transaction()];
+        let next = mined_block(Some(&genesis), NOW + 1, transactions);
+        let decoded = round_trip(&next);
+        assert_eq!(decoded.previous_hash, genesis.hash);
+        assert_eq!(decoded.merkle_root, next.calculate_merkle_root());
+        assert_eq!(decoded.transactions.len(), 2);
+    }
+
+    #[test]
+    fn chains_round_trip() {
+        let genesis = genesis();
+        let next = mined_block(Some(&genesis), NOW + 1, vec![Transaction::coinbase("miner".to_string(), 50, 1)]);
+        let chain = vec![genesis, next];
+        let decoded = round_trip(&chain);
+        assert_eq!(
+            decoded.iter().map(|block| block.hash.clone()).collect::<Vec<_>>(),
+            chain.iter().map(|block| block.hash.clone()).collect::<Vec<_>>()
+        );
+        assert!(round_trip(&Vec::<Block>::new()).is_empty());
+    }
+
+    #[test]
+    fn merkle_proofs_round_trip() {
+        let proof = MerkleProof::new(vec![1; 32], vec![(vec![2; 32], true), (vec![3; 32], false)]);
+        let decoded = round_trip(&proof);
+        assert_eq!(decoded.leaf, proof.leaf);
+        assert_eq!(decoded.path, proof.path);
+
+        let proof = MerkleProof {
+            variant: MerkleVariant::Rfc6962,
+            ..proof
+        };
+        assert_eq!(round_trip(&proof).variant, MerkleVariant::Rfc6962);
+    }
+
+    #[test]
+    fn version_1_data_decodes_as_legacy() {
+        let mut legacy = genesis();
+        legacy.version = LEGACY_BLOCK_VERSION;
+        legacy.mine();
+
+        // Version 1 had no block version after the nonce
+        let mut bytes = encode(&legacy);
+        bytes[4] = 1;
+        bytes.truncate(bytes.len() - 4);
+        let decoded: Block = decode(&bytes).unwrap();
+        assert_eq!(decoded.version, LEGACY_BLOCK_VERSION);
+        assert_eq!(decoded.calculate_hash(), legacy.hash);
+
+        // nor a proof variant after the path
+        let mut bytes = encode(&MerkleProof::new(vec![1; 32], vec![(vec![2; 32], true)]));
+        bytes[4] = 1;
+        bytes.pop();
+        assert_eq!(decode::<MerkleProof>(&bytes).unwrap().variant, MerkleVariant::Legacy);
+    }
+
+    #[test]
+    fn binary_is_smaller_than_json() {
+        let transactions = vec![Transaction::coinbase("miner".to_string(), 53, 1), account_transaction()];
+        let block = mined_block(Some(&genesis()), NOW + 1, transactions);
+        let (binary, json) = (encode(&block).len(), serde_json::to_vec(&block).unwrap().len());
+        assert!(binary * 2 < json, "{} bytes binary, {} bytes JSON", binary, json);
+    }
+
+    #[test]
+    fn json_converts_to_binary_and_back() {
+        let genesis = genesis();
+        let proof = MerkleProof::new(vec![1; 32], vec![(vec![2; 32], true)]);
+        let values = [
+            serde_json::to_vec(&vec![genesis.clone()]).unwrap(),
+            serde_json::to_vec(&genesis).unwrap(),
+            serde_json::to_vec(&account_transaction()).unwrap(),
+            serde_json::to_vec(&proof).unwrap(),
+        ];
+        let kinds = [Kind::Chain, Kind::Block, Kind::Transaction, Kind::MerkleProof];
+        for (json, kind) in values.iter().zip(kinds) {
+            let binary = json_to_binary(json).unwrap();
+            assert_eq!(kind_of(&binary), Ok(kind));
+            let back = binary_to_json(&binary).unwrap();
+            assert_eq!(
+                serde_json::from_slice::<serde_json::Value>(&back).unwrap(),
+                serde_json::from_slice::<serde_json::Value>(json).unwrap()
+            );
+        }
+        assert!(json_to_binary(b"{\"name\": \"alice\"}").is_err());
+    }
+
+    #[test]
+    fn header_is_checked() {
+        let bytes = encode(&account_transaction());
+        assert_eq!(kind_of(&bytes), Ok(Kind::Transaction));
+        assert!(matches!(
+            decode::<Block>(&bytes),
+            Err(CodecError::WrongKind {
+                expected: Kind::Block,
+                found: Kind::Transaction
+            })
+        ));
+        assert_eq!(decode::<Transaction>(b"{\"sender\":1}").err(), Some(CodecError::BadMagic));
+
+        let mut newer = bytes.clone();
+        newer[4] = FORMAT_VERSION + 1;
+        assert_eq!(decode::<Transaction>(&newer).err(), Some(CodecError::UnsupportedVersion(FORMAT_VERSION + 1)));
+    }
+
+    #[test]
+    fn truncated_and_padded_data_is_rejected() {
+        let bytes = encode(&genesis());
+        for len in [3, HEADER_LEN, bytes.len() / 2, bytes.len() - 1] {
+            assert_eq!(decode::<Block>(&bytes[..len]).err(), Some(if len < HEADER_LEN {
+                CodecError::BadMagic
+            } else {
+                CodecError::Truncated
+            }));
+        }
+
+        let mut padded = bytes.clone();
+        padded.push(0);
+        assert_eq!(decode::<Block>(&padded).err(), Some(CodecError::TrailingBytes(1)));
+    }
+}
@@ -0,0 +1,137 @@
+use crate::block::{BLOCK_VERSION, LEGACY_BLOCK_VERSION};
+use crate::{DIFFICULTY, MAX_TRANSACTIONS_PER_BLOCK};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+// How a chain tracks who owns which coins.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Ledger {
+    // Balances per address, transactions move `amount` from sender to receiver
+    #[default]
+    Account,
+    // Bitcoin style, transactions spend earlier outputs into new outputs
+    Utxo,
+}
+
+// Consensus parameters of a chain. These are persisted with the chain so a
+// reopened chain keeps validating against the rules it was built with.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct ChainConfig {
+    // Difficulty (leading zero bits) of the genesis block and of every block
+    // until the first retarget.
+    pub initial_difficulty: u32,
+    pub min_difficulty: u32,
+    pub max_difficulty: u32,
+    // Block interval in seconds the retargeting tries to hold.
+    pub target_block_time: i64,
+    // Number of blocks between retargets, and the number of block timestamps
+    // looked at when retargeting.
+    pub retarget_window: u32,
+    // Coins minted by the coinbase of each block, halved every
+    // `halving_interval` blocks.
+    pub block_reward: u64,
+    pub halving_interval: u32,
+    // Most transactions a block may hold besides its coinbase. A block is
+    // mined automatically once the mempool holds this many.
+    pub max_block_transactions: usize,
+    pub ledger: Ledger,
+    // Version of every block after genesis, and of genesis itself. Chains
+    // and genesis files from before block versions get legacy blocks, so
+    // they keep their genesis hash.
+    #[serde(default = "crate::block::legacy_block_version")]
+    pub block_version: u32,
+}
+
+impl Default for ChainConfig {
+    fn default() -> Self {
+        ChainConfig {
+            initial_difficulty: DIFFICULTY,
+            min_difficulty: 8,
+            max_difficulty: 18,
+            target_block_time: 2,
+            retarget_window: 4,
+            block_reward: 50,
+            halving_interval: 100,
+            max_block_transactions: MAX_TRANSACTIONS_PER_BLOCK,
+            ledger: Ledger::Account,
+            block_version: BLOCK_VERSION,
+        }
+    }
+}
+
+impl ChainConfig {
+    // Check the parameters a chain could not run with, such as a difficulty
+    // range retargeting can not clamp to or a block interval of zero.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.min_difficulty > self.max_difficulty {
+            return Err(ConfigError::DifficultyRange {
+                min: self.min_difficulty,
+                max: self.max_difficulty,
+            });
+        }
+        // A hash has 256 bits, no nonce gives more leading zeros than that
+        let difficulty = self.initial_difficulty.max(self.max_difficulty);
+        if difficulty > 256 {
+            return Err(ConfigError::DifficultyTooHigh(difficulty));
+        }
+        if self.target_block_time <= 0 {
+            return Err(ConfigError::TargetBlockTime(self.target_block_time));
+        }
+        if self.retarget_window < 2 {
+            return Err(ConfigError::RetargetWindow(self.retarget_window));
+        }
+        if self.halving_interval == 0 {
+            return Err(ConfigError::HalvingInterval);
+        }
+        if self.max_block_transactions == 0 {
+            return Err(ConfigError::MaxBlockTransactions);
+        }
+        if !(LEGACY_BLOCK_VERSION..=BLOCK_VERSION).contains(&self.block_version) {
+            return Err(ConfigError::BlockVersion(self.block_version));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConfigError {
+    DifficultyRange { min: u32, max: u32 },
+    DifficultyTooHigh(u32),
+    TargetBlockTime(i64),
+    // Retargeting compares the first and last block of the window
+    RetargetWindow(u32),
+    HalvingInterval,
+    MaxBlockTransactions,
+    BlockVersion(u32),
+    // The genesis allocations add up past u64::MAX
+    AllocationOverflow,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::DifficultyRange { min, max } => write!(
+                f,
+                "min_difficulty {} is above max_difficulty {}",
+                min, max
+            ),
+            ConfigError::DifficultyTooHigh(difficulty) => {
+                write!(f, "difficulty {} is above 256 bits", difficulty)
+            }
+            ConfigError::TargetBlockTime(time) => {
+                write!(f, "target_block_time must be positive, got {}", time)
+            }
+            ConfigError::RetargetWindow(window) => {
+                write!(f, "retarget_window must be at least 2, got {}", window)
+            }
+            ConfigError::HalvingInterval => write!(f, "halving_interval must not be 0"),
+            ConfigError::MaxBlockTransactions => write!(f, "max_block_transactions must not be 0"),
+            ConfigError::BlockVersion(version) => write!(f, "unknown block_version {}", version),
+            ConfigError::AllocationOverflow => write!(f, "genesis allocations add up past the largest amount"),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
@@ -0,0 +1,609 @@
+use crate::block::Block;
+use crate::config::Ledger;
+use crate::transaction::TransactionError;
+use crate::utils::to_hex_string;
+use std::fmt;
+
+// Consensus rules every block after genesis must follow. Each rule is its own
+// function so Blockchain can check them in order and report the first one a
+// block breaks. Rules that depend on the ledger state, like balances and
+// nonces, are checked by replaying the block, see LedgerState::apply_block.
+
+// Number of blocks before a new one whose median timestamp it must not be
+// older than, as in Bitcoin.
+pub const MEDIAN_TIME_SPAN: usize = 11;
+// How far in seconds a block timestamp may be ahead of the validating node's
+// clock, to allow for clocks that are not quite in sync.
+pub const MAX_FUTURE_BLOCK_TIME: i64 = 2 * 60 * 60;
+
+// Why a block does not validate. Every variant names the block by its id
+// and hex hash.
+#[derive(Debug, PartialEq)]
+pub enum ValidationError {
+    // previous_hash is not the hash of the block before it
+    BrokenLink { id: u32, hash: String },
+    BadIdSequence { id: u32, hash: String, expected: u32 },
+    // The timestamp is before the median timestamp of the blocks before it
+    TimestampTooOld {
+        id: u32,
+        hash: String,
+        timestamp: i64,
+        median_time_past: i64,
+    },
+    TimestampTooFarAhead {
+        id: u32,
+        hash: String,
+        timestamp: i64,
+        latest: i64,
+    },
+    // The block version is not the one the chain uses
+    WrongVersion {
+        id: u32,
+        hash: String,
+        expected: u32,
+        got: u32,
+    },
+    // A transaction's hash is not its id
+    TransactionIdMismatch { id: u32, hash: String, transaction: String },
+    // The stored merkle_root is not the root of the block's transactions
+    MerkleRootMismatch { id: u32, hash: String },
+    // The stored hash is not the hash of the block header
+    HashMismatch { id: u32, hash: String },
+    WrongDifficulty {
+        id: u32,
+        hash: String,
+        expected: u32,
+        got: u32,
+    },
+    // The hash does not have as many leading zero bits as the difficulty
+    InsufficientWork { id: u32, hash: String, difficulty: u32 },
+    // More transactions besides the coinbase than the chain allows
+    TooManyTransactions {
+        id: u32,
+        hash: String,
+        count: usize,
+        max: usize,
+    },
+    // No coinbase first, more than one, or one minting the wrong amount
+    InvalidCoinbase { id: u32, hash: String },
+    BadSignature {
+        id: u32,
+        hash: String,
+        transaction: String,
+        error: TransactionError,
+    },
+    DuplicateTransaction { id: u32, hash: String, transaction: String },
+    // Replaying the block overdraws an account, uses a wrong nonce or spends
+    // an output that is missing or already spent
+    InvalidTransaction { id: u32, hash: String, error: TransactionError },
+}
+
+impl ValidationError {
+    pub fn block_id(&self) -> u32 {
+        match self {
+            ValidationError::BrokenLink { id, .. }
+            | ValidationError::BadIdSequence { id, .. }
+            | ValidationError::TimestampTooOld { id, .. }
+            | ValidationError::TimestampTooFarAhead { id, .. }
+            | ValidationError::WrongVersion { id, .. }
+            | ValidationError::TransactionIdMismatch { id, .. }
+            | ValidationError::MerkleRootMismatch { id, .. }
+            | ValidationError::HashMismatch { id, .. }
+            | ValidationError::WrongDifficulty { id, .. }
+            | ValidationError::InsufficientWork { id, .. }
+            | ValidationError::TooManyTransactions { id, .. }
+            | ValidationError::InvalidCoinbase { id, .. }
+            | ValidationError::BadSignature { id, .. }
+            | ValidationError::DuplicateTransaction { id, .. }
+            | ValidationError::InvalidTransaction { id, .. } => *id,
+        }
+    }
+
+    pub fn block_hash(&self) -> &str {
+        match self {
+            ValidationError::BrokenLink { hash, .. }
+            | ValidationError::BadIdSequence { hash, .. }
+            | ValidationError::TimestampTooOld { hash, .. }
+            | ValidationError::TimestampTooFarAhead { hash, .. }
+            | ValidationError::WrongVersion { hash, .. }
+            | ValidationError::TransactionIdMismatch { hash, .. }
+            | ValidationError::MerkleRootMismatch { hash, .. }
+            | ValidationError::HashMismatch { hash, .. }
+            | ValidationError::WrongDifficulty { hash, .. }
+            | ValidationError::InsufficientWork { hash, .. }
+            | ValidationError::TooManyTransactions { hash, .. }
+            | ValidationError::InvalidCoinbase { hash, .. }
+            | ValidationError::BadSignature { hash, .. }
+            | ValidationError::DuplicateTransaction { hash, .. }
+            | ValidationError::InvalidTransaction { hash, .. } => hash,
+        }
+    }
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "block {} ({}): ", self.block_id(), self.block_hash())?;
+        match self {
+            ValidationError::BrokenLink { .. } => {
+                write!(f, "previous_hash is not the hash of the block before it")
+            }
+            ValidationError::BadIdSequence { expected, .. } => write!(f, "id should be {}", expected),
+            ValidationError::TimestampTooOld { timestamp, median_time_past, .. } => write!(
+                f,
+                "timestamp {} is before the median time {} of the blocks before it",
+                timestamp, median_time_past
+            ),
+            ValidationError::TimestampTooFarAhead { timestamp, latest, .. } => write!(
+                f,
+                "timestamp {} is too far in the future, the latest accepted is {}",
+                timestamp, latest
+            ),
+            ValidationError::WrongVersion { expected, got, .. } => {
+                write!(f, "block version {} should be {}", got, expected)
+            }
+            ValidationError::TransactionIdMismatch { transaction, .. } => {
+                write!(f, "transaction {} has a hash that is not its id", transaction)
+            }
+            ValidationError::MerkleRootMismatch { .. } => {
+                write!(f, "merkle_root does not match the transactions")
+            }
+            ValidationError::HashMismatch { .. } => write!(f, "hash does not match the block contents"),
+            ValidationError::WrongDifficulty { expected, got, .. } => {
+                write!(f, "difficulty is {} but should be {}", got, expected)
+            }
+            ValidationError::InsufficientWork { difficulty, .. } => {
+                write!(f, "hash has fewer than {} leading zero bits", difficulty)
+            }
+            ValidationError::TooManyTransactions { count, max, .. } => {
+                write!(f, "{} transactions besides the coinbase, at most {} are allowed", count, max)
+            }
+            ValidationError::InvalidCoinbase { .. } => write!(f, "coinbase transaction is missing or invalid"),
+            ValidationError::BadSignature { transaction, error, .. } => {
+                write!(f, "transaction {}: {}", transaction, error)
+            }
+            ValidationError::DuplicateTransaction { transaction, .. } => {
+                write!(f, "transaction {} is already in an earlier block", transaction)
+            }
+            ValidationError::InvalidTransaction { error, .. } => write!(f, "{}", error),
+        }
+    }
+}
+
+impl std::error::Error for ValidationError {}
+
+// The block must point at the last of `ancestors`.
+pub fn check_link(ancestors: &[Block], block: &Block) -> Result<(), ValidationError> {
+    match ancestors.last() {
+        Some(parent) if block.previous_hash == parent.hash => Ok(()),
+        _ => Err(ValidationError::BrokenLink {
+            id: block.id,
+            hash: block_hash(block),
+        }),
+    }
+}
+
+// Ids count up by one from genesis, so a block's id is its height.
+pub fn check_id(ancestors: &[Block], block: &Block) -> Result<(), ValidationError> {
+    let expected = ancestors.len() as u32;
+    if block.id != expected {
+        return Err(ValidationError::BadIdSequence {
+            id: block.id,
+            hash: block_hash(block),
+            expected,
+        });
+    }
+    Ok(())
+}
+
+// Median timestamp of the last MEDIAN_TIME_SPAN blocks, or of all of them on
+// a shorter chain. A single block with a wrong clock can not move it much.
+pub fn median_time_past(ancestors: &[Block]) -> i64 {
+    let start = ancestors.len().saturating_sub(MEDIAN_TIME_SPAN);
+    let mut timestamps = ancestors[start..].iter().map(|block| block.timestamp).collect::<Vec<i64>>();
+    timestamps.sort_unstable();
+    timestamps.get(timestamps.len() / 2).copied().unwrap_or(0)
+}
+
+// The timestamp may go back a little from its parent, but not before the
+// median time past, and may be at most MAX_FUTURE_BLOCK_TIME ahead of `now`.
+pub fn check_timestamp(ancestors: &[Block], block: &Block, now: i64) -> Result<(), ValidationError> {
+    let median_time_past = median_time_past(ancestors);
+    if block.timestamp < median_time_past {
+        return Err(ValidationError::TimestampTooOld {
+            id: block.id,
+            hash: block_hash(block),
+            timestamp: block.timestamp,
+            median_time_past,
+        });
+    }
+    let latest = now + MAX_FUTURE_BLOCK_TIME;
+    if block.timestamp > latest {
+        return Err(ValidationError::TimestampTooFarAhead {
+            id: block.id,
+            hash: block_hash(block),
+            timestamp: block.timestamp,
+            latest,
+        });
+    }
+    Ok(())
+}
+
+// Every block of a chain has the version in its config, which decides how
+// the block builds its Merkle tree.
+pub fn check_version(block: &Block, expected: u32) -> Result<(), ValidationError> {
+    if block.version != expected {
+        return Err(ValidationError::WrongVersion {
+            id: block.id,
+            hash: block_hash(block),
+            expected,
+            got: block.version,
+        });
+    }
+    Ok(())
+}
+
+// Every transaction, the coinbase too, must store its id as its hash, since
+// the hash is what the mempool, proofs and UTXO inputs refer to it by.
+pub fn check_transaction_ids(block: &Block) -> Result<(), ValidationError> {
+    if let Some(transaction) = block.transactions.iter().find(|tx| tx.verify_id().is_err()) {
+        return Err(ValidationError::TransactionIdMismatch {
+            id: block.id,
+            hash: block_hash(block),
+            transaction: to_hex_string(&transaction.hash),
+        });
+    }
+    Ok(())
+}
+
+pub fn check_merkle_root(block: &Block) -> Result<(), ValidationError> {
+    if block.merkle_root != block.calculate_merkle_root() {
+        return Err(ValidationError::MerkleRootMismatch {
+            id: block.id,
+            hash: block_hash(block),
+        });
+    }
+    Ok(())
+}
+
+pub fn check_hash(block: &Block) -> Result<(), ValidationError> {
+    if block.calculate_hash() != block.hash {
+        return Err(ValidationError::HashMismatch {
+            id: block.id,
+            hash: block_hash(block),
+        });
+    }
+    Ok(())
+}
+
+// The declared difficulty must be the one retargeting gives for this height.
+pub fn check_difficulty(block: &Block, expected: u32) -> Result<(), ValidationError> {
+    if block.difficulty != expected {
+        return Err(ValidationError::WrongDifficulty {
+            id: block.id,
+            hash: block_hash(block),
+            expected,
+            got: block.difficulty,
+        });
+    }
+    Ok(())
+}
+
+pub fn check_proof_of_work(block: &Block) -> Result<(), ValidationError> {
+    if !block.meets_difficulty() {
+        return Err(ValidationError::InsufficientWork {
+            id: block.id,
+            hash: block_hash(block),
+            difficulty: block.difficulty,
+        });
+    }
+    Ok(())
+}
+
+// At most `max` transactions besides the coinbase.
+pub fn check_block_size(block: &Block, max: usize) -> Result<(), ValidationError> {
+    let count = block.transactions.len().saturating_sub(1);
+    if count > max {
+        return Err(ValidationError::TooManyTransactions {
+            id: block.id,
+            hash: block_hash(block),
+            count,
+            max,
+        });
+    }
+    Ok(())
+}
+
+// Exactly one coinbase, first in the block, minting `reward` plus the fees of
+// the other transactions, with the block height as its nonce.
+pub fn check_coinbase(block: &Block, ledger: Ledger, reward: u64) -> Result<(), ValidationError> {
+    let valid = match block.transactions.first() {
+        Some(coinbase) if coinbase.is_coinbase() => {
+            let minted = match ledger {
+                Ledger::Account => Some(coinbase.amount()),
+                Ledger::Utxo => coinbase.output_total(),
+            };
+            // Fees come from the block, which may be anyone's, so adding them
+            // up must not overflow
+            let total = block.transactions[1..]
+                .iter()
+                .try_fold(reward, |total, tx| total.checked_add(tx.fee()));
+            total.is_some()
+                && minted == total
+                && coinbase.nonce() == block.id as u64
+                && !block.transactions[1..].iter().any(|tx| tx.is_coinbase())
+        }
+        _ => false,
+    };
+    if !valid {
+        return Err(ValidationError::InvalidCoinbase {
+            id: block.id,
+            hash: block_hash(block),
+        });
+    }
+    Ok(())
+}
+
+// Every transaction after the coinbase must carry a valid signature.
+pub fn check_signatures(block: &Block) -> Result<(), ValidationError> {
+    for transaction in block.transactions.iter().skip(1) {
+        transaction
+            .verify_signature()
+            .map_err(|error| ValidationError::BadSignature {
+                id: block.id,
+                hash: block_hash(block),
+                transaction: to_hex_string(&transaction.hash),
+                error,
+            })?;
+    }
+    Ok(())
+}
+
+pub(crate) fn block_hash(block: &Block) -> String {
+    block.hash.as_deref().map_or_else(String::new, to_hex_string)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::{BLOCK_VERSION, LEGACY_BLOCK_VERSION};
+    use crate::test_utils::mined_block;
+    use crate::transaction::Transaction;
+    use crate::utxo::TxOutput;
+    use crate::wallet::Wallet;
+
+    const REWARD: u64 = 50;
+    const NOW: i64 = 1_700_000_000;
+
+    // A mined block on top of `parent` with just a coinbase
+    fn block_on(parent: Option<&Block>, timestamp: i64) -> Block {
+        let id = parent.map_or(0, |parent| parent.id + 1);
+        mined_block(parent, timestamp, vec![Transaction::coinbase("miner".to_string(), REWARD, id)])
+    }
+
+    // A chain with the given block timestamps
+    fn chain(timestamps: &[i64]) -> Vec<Block> {
+        let mut blocks: Vec<Block> = Vec::new();
+        for &timestamp in timestamps {
+            let block = block_on(blocks.last(), timestamp);
+            blocks.push(block);
+        }
+        blocks
+    }
+
+    #[test]
+    fn link_must_point_at_the_parent() {
+        let blocks = chain(&[NOW, NOW + 1]);
+        assert_eq!(check_link(&blocks[..1], &blocks[1]), Ok(()));
+
+        let mut block = blocks[1].clone();
+        block.previous_hash = Some(vec![0; 32]);
+        assert!(matches!(check_link(&blocks[..1], &block), Err(ValidationError::BrokenLink { id: 1, .. })));
+        assert!(check_link(&[], &blocks[1]).is_err());
+    }
+
+    #[test]
+    fn ids_count_up_by_one() {
+        let blocks = chain(&[NOW, NOW + 1, NOW + 2]);
+        assert_eq!(check_id(&blocks[..2], &blocks[2]), Ok(()));
+
+        let mut block = blocks[2].clone();
+        block.id = 5;
+        assert!(matches!(
+            check_id(&blocks[..2], &block),
+            Err(ValidationError::BadIdSequence { id: 5, expected: 2, .. })
+        ));
+    }
+
+    #[test]
+    fn median_time_past_uses_the_last_eleven_blocks() {
+        assert_eq!(median_time_past(&chain(&[NOW, NOW + 10, NOW + 5])), NOW + 5);
+
+        // The oldest timestamps drop out of the window
+        let timestamps = (0..15).map(|i| NOW + i * 10).collect::<Vec<i64>>();
+        assert_eq!(median_time_past(&chain(&timestamps)), NOW + 90);
+    }
+
+    #[test]
+    fn timestamp_may_not_be_before_the_median_time_past() {
+        let blocks = chain(&[NOW, NOW + 10, NOW + 20]);
+        let median = median_time_past(&blocks);
+
+        // Going back from the parent is fine as long as the median is kept
+        let block = block_on(blocks.last(), median);
+        assert_eq!(check_timestamp(&blocks, &block, NOW), Ok(()));
+
+        let block = block_on(blocks.last(), median - 1);
+        assert!(matches!(
+            check_timestamp(&blocks, &block, NOW),
+            Err(ValidationError::TimestampTooOld { median_time_past, .. }) if median_time_past == median
+        ));
+    }
+
+    #[test]
+    fn timestamp_may_not_be_too_far_in_the_future() {
+        let blocks = chain(&[NOW]);
+        let block = block_on(blocks.last(), NOW + MAX_FUTURE_BLOCK_TIME);
+        assert_eq!(check_timestamp(&blocks, &block, NOW), Ok(()));
+
+        let block = block_on(blocks.last(), NOW + MAX_FUTURE_BLOCK_TIME + 1);
+        assert!(matches!(
+            check_timestamp(&blocks, &block, NOW),
+            Err(ValidationError::TimestampTooFarAhead { .. })
+        ));
+    }
+
+    #[test]
+    fn version_must_be_the_chains() {
+        let block = block_on(None, NOW);
+        assert_eq!(check_version(&block, BLOCK_VERSION), Ok(()));
+        assert!(matches!(
+            check_version(&block, LEGACY_BLOCK_VERSION),
+            Err(ValidationError::WrongVersion { expected: LEGACY_BLOCK_VERSION, got: BLOCK_VERSION, .. })
+        ));
+    }
+
+    #[test]
+    fn transaction_hashes_must_be_their_ids() {
+        let alice = Wallet::generate();
+        let mut block = block_on(None, NOW);
+        block.transactions.push(alice.create_transaction("bob", 5, 1, 0));
+        assert_eq!(check_transaction_ids(&block), Ok(()));
+
+        block.transactions[1].hash = vec![0; 32];
+        assert!(matches!(
+            check_transaction_ids(&block),
+            Err(ValidationError::TransactionIdMismatch { transaction, .. }) if transaction == to_hex_string(&[0; 32])
+        ));
+    }
+
+    #[test]
+    fn merkle_root_must_match_the_transactions() {
+        let mut block = block_on(None, NOW);
+        assert_eq!(check_merkle_root(&block), Ok(()));
+
+        block.merkle_root = Some(vec![0; 32]);
+        assert!(matches!(check_merkle_root(&block), Err(ValidationError::MerkleRootMismatch { .. })));
+    }
+
+    #[test]
+    fn hash_must_match_the_header() {
+        let mut block = block_on(None, NOW);
+        assert_eq!(check_hash(&block), Ok(()));
+
+        block.timestamp += 1;
+        assert!(matches!(check_hash(&block), Err(ValidationError::HashMismatch { .. })));
+    }
+
+    #[test]
+    fn hash_commits_to_the_transactions_through_the_stored_root() {
+        let mut block = block_on(None, NOW);
+        block.transactions.push(Transaction::coinbase("miner".to_string(), 1, 0));
+        // The header is unchanged, the root no longer matches the transactions
+        assert_eq!(check_hash(&block), Ok(()));
+        assert!(check_merkle_root(&block).is_err());
+
+        block.merkle_root = block.calculate_merkle_root();
+        assert!(check_hash(&block).is_err());
+    }
+
+    #[test]
+    fn difficulty_must_be_the_expected_one() {
+        let block = block_on(None, NOW);
+        assert_eq!(check_difficulty(&block, 4), Ok(()));
+        assert!(matches!(
+            check_difficulty(&block, 5),
+            Err(ValidationError::WrongDifficulty { expected: 5, got: 4, .. })
+        ));
+    }
+
+    #[test]
+    fn hash_must_meet_the_difficulty() {
+        let mut block = block_on(None, NOW);
+        assert_eq!(check_proof_of_work(&block), Ok(()));
+
+        block.hash = Some(vec![0xff; 32]);
+        assert!(matches!(
+            check_proof_of_work(&block),
+            Err(ValidationError::InsufficientWork { difficulty: 4, .. })
+        ));
+    }
+
+    #[test]
+    fn blocks_hold_at_most_the_maximum_of_transactions() {
+        let alice = Wallet::generate();
+        let mut block = block_on(None, NOW);
+        block.transactions.push(alice.create_transaction("bob", 5, 1, 0));
+        block.transactions.push(alice.create_transaction("bob", 5, 1, 1));
+        assert_eq!(check_block_size(&block, 2), Ok(()));
+        assert!(matches!(
+            check_block_size(&block, 1),
+            Err(ValidationError::TooManyTransactions { count: 2, max: 1, .. })
+        ));
+    }
+
+    #[test]
+    fn coinbase_must_come_first_and_mint_the_reward() {
+        let mut block = block_on(None, NOW);
+        assert_eq!(check_coinbase(&block, Ledger::Account, REWARD), Ok(()));
+        assert!(check_coinbase(&block, Ledger::Account, REWARD + 1).is_err());
+
+        // A second coinbase
+        block.transactions.push(Transaction::coinbase("miner".to_string(), 0, 0));
+        assert!(matches!(
+            check_coinbase(&block, Ledger::Account, REWARD),
+            Err(ValidationError::InvalidCoinbase { .. })
+        ));
+
+        // No coinbase at all
+        block.transactions.clear();
+        assert!(check_coinbase(&block, Ledger::Account, REWARD).is_err());
+    }
+
+    #[test]
+    fn coinbase_fees_may_not_overflow() {
+        let alice = Wallet::generate();
+        let mut block = block_on(None, NOW);
+        block.transactions.push(alice.create_transaction("bob", 5, u64::MAX, 0));
+        block.transactions.push(alice.create_transaction("bob", 5, 2, 1));
+
+        // Wrapping around, REWARD + u64::MAX + 2 would be REWARD + 1
+        block.transactions[0] = Transaction::coinbase("miner".to_string(), REWARD + 1, 0);
+        assert!(matches!(
+            check_coinbase(&block, Ledger::Account, REWARD),
+            Err(ValidationError::InvalidCoinbase { .. })
+        ));
+        block.transactions[0] = Transaction::coinbase("miner".to_string(), u64::MAX, 0);
+        assert!(check_coinbase(&block, Ledger::Account, REWARD).is_err());
+    }
+
+    #[test]
+    fn utxo_coinbase_outputs_may_not_overflow() {
+        let mut block = block_on(None, NOW);
+        assert!(check_coinbase(&block, Ledger::Utxo, REWARD).is_err());
+
+        block.transactions[0] = Transaction::utxo_coinbase("miner".to_string(), REWARD, 0);
+        assert_eq!(check_coinbase(&block, Ledger::Utxo, REWARD), Ok(()));
+
+        // Two outputs adding up to REWARD once wrapped around
+        let outputs = vec![
+            TxOutput { address: "miner".to_string(), amount: u64::MAX },
+            TxOutput { address: "miner".to_string(), amount: REWARD + 1 },
+        ];
+        block.transactions[0] = Transaction::new_utxo(String::new(), Vec::new(), outputs, 0);
+        assert!(check_coinbase(&block, Ledger::Utxo, REWARD).is_err());
+    }
+
+    #[test]
+    fn transactions_must_be_signed() {
+        let alice = Wallet::generate();
+        let mut block = block_on(None, NOW);
+        block.transactions.push(alice.create_transaction("bob", 5, 1, 0));
+        assert_eq!(check_signatures(&block), Ok(()));
+
+        block.transactions.push(Transaction::new(alice.address(), "bob".to_string(), 5, 1, 1));
+        assert!(matches!(
+            check_signatures(&block),
+            Err(ValidationError::BadSignature { error: TransactionError::Unsigned, .. })
+        ));
+    }
+}
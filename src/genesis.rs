@@ -0,0 +1,196 @@
+use crate::block::{Block, LEGACY_BLOCK_VERSION};
+use crate::config::{ChainConfig, ConfigError, Ledger};
+use crate::transaction::Transaction;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+// Timestamp of the default genesis block, 2023-11-14 22:13:20 UTC.
+pub const DEFAULT_GENESIS_TIMESTAMP: i64 = 1_700_000_000;
+pub const DEFAULT_CHAIN_ID: &str = "rust_chain";
+
+// Everything the genesis block of a chain is built from. The block only
+// depends on the spec, so every node given the same spec builds the same
+// genesis block, byte for byte, and they agree on the chain. For example:
+//
+// {
+//   "chain_id": "rust_chain-testnet",
+//   "timestamp": 1700000000,
+//   "allocations": { "<address>": 1000 },
+//   "consensus": { "block_reward": 25, "initial_difficulty": 10, "max_block_transactions": 8 }
+// }
+//
+// Consensus parameters that are left out keep their ChainConfig defaults,
+// except block_version, which is legacy so that specs written before block
+// versions keep their genesis block, whether they have a consensus object
+// or not. Set it to 2 for the Rfc6962 tree.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct GenesisSpec {
+    pub chain_id: String,
+    pub timestamp: i64,
+    // Coins credited to addresses in the genesis block. A BTreeMap keeps them
+    // in address order, so the genesis transactions come out the same
+    // whatever order the file lists them in.
+    #[serde(default)]
+    pub allocations: BTreeMap<String, u64>,
+    #[serde(default = "legacy_consensus")]
+    pub consensus: ChainConfig,
+}
+
+// Consensus of a spec without a consensus object, which predates block
+// versions.
+fn legacy_consensus() -> ChainConfig {
+    ChainConfig {
+        block_version: LEGACY_BLOCK_VERSION,
+        ..ChainConfig::default()
+    }
+}
+
+impl Default for GenesisSpec {
+    fn default() -> Self {
+        GenesisSpec {
+            chain_id: DEFAULT_CHAIN_ID.to_string(),
+            timestamp: DEFAULT_GENESIS_TIMESTAMP,
+            allocations: BTreeMap::new(),
+            consensus: ChainConfig::default(),
+        }
+    }
+}
+
+impl GenesisSpec {
+    pub fn load_from_file(path: &str) -> Result<GenesisSpec, Box<dyn std::error::Error>> {
+        let data = std::fs::read_to_string(path)?;
+        let spec: GenesisSpec = serde_json::from_str(&data)?;
+        spec.validate()?;
+        Ok(spec)
+    }
+
+    // Check the consensus parameters, and that the allocations fit in an
+    // amount together so no balance can overflow.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        self.consensus.validate()?;
+        self.allocations
+            .values()
+            .try_fold(0u64, |total, amount| total.checked_add(*amount))
+            .ok_or(ConfigError::AllocationOverflow)?;
+        Ok(())
+    }
+
+    pub fn save_to_file(&self, path: &str) -> Result<(), Box<dyn std::error::Error>> {
+        std::fs::write(path, serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+
+    // The genesis block: an empty coinbase to the chain id, so chains with
+    // different ids never share a genesis hash, then one coinbase per
+    // allocation, mined at the initial difficulty.
+    pub fn block(&self) -> Block {
+        let coinbase = |receiver: &str, amount: u64| match self.consensus.ledger {
+            Ledger::Account => Transaction::coinbase(receiver.to_string(), amount, 0),
+            Ledger::Utxo => Transaction::utxo_coinbase(receiver.to_string(), amount, 0),
+        };
+        let mut transactions = vec![coinbase(&self.chain_id, 0)];
+        transactions.extend(
+            self.allocations
+                .iter()
+                .map(|(address, amount)| coinbase(address, *amount)),
+        );
+
+        let mut genesis_block = Block {
+            id: 0,
+            timestamp: self.timestamp,
+            previous_hash: None,
+            hash: None,
+            merkle_root: None,
+            transactions,
+            difficulty: self.consensus.initial_difficulty,
+            nonce: 0,
+            version: self.consensus.block_version,
+        };
+
+        // Mining fills in the Merkle root and hash. The nonce search always
+        // starts at 0, so it finds the same nonce everywhere.
+        genesis_block.mine();
+
+        genesis_block
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::BLOCK_VERSION;
+    use crate::blockchain::Blockchain;
+    use crate::storage::MemoryStore;
+    use crate::test_utils::TempDir;
+
+    #[test]
+    fn specs_without_a_block_version_get_legacy_blocks() {
+        let without_consensus: GenesisSpec =
+            serde_json::from_str(r#"{"chain_id": "x", "timestamp": 1700000000}"#).unwrap();
+        let empty_consensus: GenesisSpec =
+            serde_json::from_str(r#"{"chain_id": "x", "timestamp": 1700000000, "consensus": {}}"#).unwrap();
+        assert_eq!(without_consensus.consensus.block_version, LEGACY_BLOCK_VERSION);
+        assert_eq!(empty_consensus.consensus.block_version, LEGACY_BLOCK_VERSION);
+        assert_eq!(without_consensus.block().hash, empty_consensus.block().hash);
+
+        let versioned: GenesisSpec = serde_json::from_str(
+            r#"{"chain_id": "x", "timestamp": 1700000000, "consensus": {"block_version": 2}}"#,
+        )
+        .unwrap();
+        assert_eq!(versioned.consensus.block_version, BLOCK_VERSION);
+    }
+
+    #[test]
+    fn specs_with_unusable_consensus_are_rejected() {
+        let dir = TempDir::new("genesis");
+        let path = dir.path().join("genesis.json");
+        let path = path.to_str().unwrap();
+        std::fs::write(
+            path,
+            r#"{"chain_id": "x", "timestamp": 1700000000, "consensus": {"min_difficulty": 9, "max_difficulty": 8}}"#,
+        )
+        .unwrap();
+        let error = GenesisSpec::load_from_file(path).err().unwrap();
+        assert_eq!(error.downcast_ref(), Some(&ConfigError::DifficultyRange { min: 9, max: 8 }));
+
+        let spec = |consensus: ChainConfig| GenesisSpec {
+            consensus,
+            ..GenesisSpec::default()
+        };
+        let invalid = [
+            (ChainConfig { target_block_time: 0, ..ChainConfig::default() }, ConfigError::TargetBlockTime(0)),
+            (ChainConfig { retarget_window: 1, ..ChainConfig::default() }, ConfigError::RetargetWindow(1)),
+            (ChainConfig { halving_interval: 0, ..ChainConfig::default() }, ConfigError::HalvingInterval),
+            (ChainConfig { max_block_transactions: 0, ..ChainConfig::default() }, ConfigError::MaxBlockTransactions),
+            (ChainConfig { max_difficulty: 257, ..ChainConfig::default() }, ConfigError::DifficultyTooHigh(257)),
+            (ChainConfig { block_version: 3, ..ChainConfig::default() }, ConfigError::BlockVersion(3)),
+        ];
+        for (consensus, expected) in invalid {
+            let error = Blockchain::with_store(&spec(consensus), MemoryStore::default()).err().unwrap();
+            assert_eq!(error.downcast_ref(), Some(&expected));
+        }
+        assert!(Blockchain::with_store(&spec(ChainConfig::default()), MemoryStore::default()).is_ok());
+    }
+
+    #[test]
+    fn allocations_past_the_maximum_are_rejected() {
+        let dir = TempDir::new("genesis-allocations");
+        let path = dir.path().join("genesis.json");
+        let path = path.to_str().unwrap();
+        let spec = GenesisSpec {
+            allocations: [("alice".to_string(), u64::MAX), ("bob".to_string(), 1)].into_iter().collect(),
+            ..GenesisSpec::default()
+        };
+        spec.save_to_file(path).unwrap();
+        let error = GenesisSpec::load_from_file(path).err().unwrap();
+        assert_eq!(error.downcast_ref(), Some(&ConfigError::AllocationOverflow));
+        let error = Blockchain::with_store(&spec, MemoryStore::default()).err().unwrap();
+        assert_eq!(error.downcast_ref(), Some(&ConfigError::AllocationOverflow));
+
+        let spec = GenesisSpec {
+            allocations: [("alice".to_string(), u64::MAX - 1), ("bob".to_string(), 1)].into_iter().collect(),
+            ..spec
+        };
+        assert_eq!(spec.validate(), Ok(()));
+    }
+}
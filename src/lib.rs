@@ -0,0 +1,26 @@
+pub mod blockchain;
+pub mod codec;
+pub mod config;
+pub mod consensus;
+pub mod genesis;
+pub mod transaction;
+pub mod block;
+pub mod block_log;
+pub mod mempool;
+pub mod merkle_proof;
+pub mod p2p;
+pub mod redb_store;
+pub mod rpc;
+pub mod state;
+pub mod storage;
+pub mod utils;
+#[cfg(test)]
+mod test_utils;
+pub mod utxo;
+pub mod wallet;
+
+// Default for the most transactions per block, see ChainConfig.
+pub const MAX_TRANSACTIONS_PER_BLOCK: usize = 4;
+// Number of leading zero bits a block hash needs for Proof of Work, until
+// difficulty retargeting adjusts it. See ChainConfig.
+pub const DIFFICULTY: u32 = 12;
@@ -1,52 +1,222 @@
-pub mod blockchain;
-pub mod transaction;
-pub mod block;
-pub mod merkle_proof;
-pub mod utils;
-use std::fs::File;
-use std::path::Path;
-use std::io::Read;
+mod cli;
+mod shell;
 
-pub const MAX_TRANSACTIONS_PER_BLOCK: usize = 4;
+use clap::{Parser, Subcommand};
+use cli::{Context, Encoding, Format, LedgerArg, StoreArg};
+use rust_chain::genesis::GenesisSpec;
+use rust_chain::mempool::MempoolEvent;
+use rust_chain::transaction::Transaction;
+use rust_chain::wallet::{Keystore, Wallet, WalletError};
+use rust_chain::{blockchain, utils};
+use std::path::PathBuf;
+use std::process::ExitCode;
 
+#[derive(Parser)]
+#[command(name = "rust_chain", version, about = "An educational blockchain")]
+struct Cli {
+    #[arg(long, global = true, default_value = ".", help = "Directory holding the chain (blocks.log and chain.json, or chain.redb) and keystore.json")]
+    data_dir: PathBuf,
+    #[arg(long, global = true, value_enum, default_value_t = Format::Table, help = "Output format")]
+    format: Format,
+    #[arg(long, global = true, env = "RUST_CHAIN_PASSWORD", hide_env_values = true, help = "Password of the wallets in the keystore. Prefer the environment variable, other users can see arguments in ps")]
+    password: Option<String>,
+    #[command(subcommand)]
+    command: Command,
+}
 
+#[derive(Subcommand)]
+enum Command {
+    #[command(about = "Create a new chain from the default or a given genesis block")]
+    Init {
+        #[arg(long, value_enum, default_value_t = LedgerArg::Account, conflicts_with = "genesis")]
+        ledger: LedgerArg,
+        #[arg(long, help = "Genesis spec JSON file with the chain id, timestamp, allocations and consensus parameters")]
+        genesis: Option<PathBuf>,
+        #[arg(long, value_enum, default_value_t = StoreArg::Log, help = "How to store the blocks")]
+        store: StoreArg,
+        #[arg(long, help = "Replace an existing chain")]
+        force: bool,
+    },
+    #[command(subcommand, about = "Manage the wallets in the keystore")]
+    Wallet(WalletCommand),
+    #[command(about = "Add a transaction to the mempool")]
+    Send {
+        #[arg(help = "Wallet name of the sender")]
+        from: String,
+        #[arg(help = "Wallet name or address of the receiver")]
+        to: String,
+        amount: u64,
+        #[arg(long, default_value_t = 1)]
+        fee: u64,
+    },
+    #[command(about = "Mine a block with the best paying mempool transactions")]
+    Mine {
+        #[arg(long, help = "Wallet name or address paid by the coinbase, defaults to the chain's miner address")]
+        miner: Option<String>,
+    },
+    #[command(about = "Show a block by id or hash")]
+    ShowBlock { block: String },
+    #[command(about = "List the blocks of the chain")]
+    ShowChain,
+    #[command(about = "Validate the whole chain")]
+    Validate,
+    #[command(about = "Build a Merkle proof that a transaction is in its block")]
+    Prove {
+        tx_hash: String,
+        #[arg(long, help = "Write the proof to this file instead of printing it")]
+        output: Option<PathBuf>,
+    },
+    #[command(about = "Check a proof written by prove against the chain")]
+    VerifyProof { file: PathBuf },
+    #[command(about = "List the pending transactions")]
+    Mempool,
+    #[command(about = "Write the blocks of the chain to a file")]
+    Export {
+        output: PathBuf,
+        #[arg(long, value_enum, default_value_t = Encoding::Binary)]
+        encoding: Encoding,
+    },
+    #[command(about = "Convert a chain, block, transaction or Merkle proof file between JSON and the binary format")]
+    Convert { input: PathBuf, output: PathBuf },
+    #[command(about = "Serve the chain over JSON-RPC")]
+    Rpc {
+        #[arg(default_value = "127.0.0.1:8545")]
+        address: String,
+        #[arg(long, value_name = "SECONDS", help = "Save the chain this often instead of after every change")]
+        save_every: Option<u64>,
+    },
+    #[command(about = "Explore the chain in an interactive shell")]
+    Shell,
+    #[command(about = "Run the demo script of transactions between alice, bob and friends")]
+    Demo,
+}
 
+#[derive(Subcommand)]
+enum WalletCommand {
+    #[command(about = "Generate a wallet and store it encrypted in the keystore")]
+    New { name: String },
+    #[command(about = "List the wallets with their balances")]
+    List,
+}
 
+// Password protecting the demo wallets in keystore.json
+const DEMO_PASSWORD: &str = "rust_chain";
 
-// Run test
-fn run_test() -> Result<(), Box<dyn std::error::Error>> {
-    println!("Running test");
-    let mut blockchain = blockchain::Blockchain::new();
+// Unlock the named wallet from the keystore, or create and store it on first use.
+fn wallet_for(keystore: &mut Keystore, name: &str) -> Result<Wallet, WalletError> {
+    if keystore.address_of(name).is_some() {
+        return keystore.unlock(name, DEMO_PASSWORD);
+    }
+    let wallet = Wallet::generate();
+    keystore.add(name, &wallet, DEMO_PASSWORD)?;
+    Ok(wallet)
+}
 
-    blockchain.load_from_file("./blockchain.json")?;
-    println!("Blockchain loaded from file");
+// Send coins between two wallets with the sender's next nonce, reporting it
+// if the chain rejects the transaction, for example because the sender cannot
+// afford it.
+fn send(blockchain: &mut blockchain::Blockchain, sender: &Wallet, receiver: &Wallet, amount: u64, fee: u64) {
+    let nonce = blockchain.next_nonce(&sender.address());
+    let transaction = sender.create_transaction(&receiver.address(), amount, fee, nonce);
+    if let Err(e) = blockchain.add_transaction(transaction) {
+        println!("Transaction rejected: {}", e);
+    }
+}
+
+// Run the demo script against the chain and keystore in the data directory
+fn run_demo(ctx: &Context) -> Result<(), Box<dyn std::error::Error>> {
+    println!("Running test");
+    let mut blockchain = if ctx.has_chain() {
+        ctx.load_chain()?
+    } else {
+        blockchain::Blockchain::with_store(&GenesisSpec::default(), ctx.create_store(StoreArg::Log)?)?
+    };
+    println!("Blockchain loaded from {}", ctx.chain_dir());
     //blockchain.print_json();
 
     println!("Begin Transactions to mempool");
 
+    // Every participant has a wallet in the keystore next to the chain,
+    // created on the first run and unlocked with the demo password afterwards
+    let mut keystore = Keystore::load_from_file(&ctx.keystore_path())?;
+    let alice = wallet_for(&mut keystore, "alice")?;
+    let bob = wallet_for(&mut keystore, "bob")?;
+    let charlie = wallet_for(&mut keystore, "charlie")?;
+    let dana = wallet_for(&mut keystore, "dana")?;
+    let fred = wallet_for(&mut keystore, "fred")?;
+    let barney = wallet_for(&mut keystore, "barney")?;
+    let george = wallet_for(&mut keystore, "george")?;
+    let henry = wallet_for(&mut keystore, "henry")?;
+    keystore.save_to_file(&ctx.keystore_path())?;
+
+    // Alice mines the blocks, the coinbase of each one pays her the block reward.
+    // Mine one block straight away so she has coins to hand out.
+    blockchain.set_miner_address(alice.address());
+    blockchain.mine_block(&alice.address());
+    println!("Alice mined block {}", blockchain.get_block_height());
+
     // Add 2 * MAX_TRANSACTIONS_PER_BLOCK transactions to the mempool
-    blockchain.add_transaction("Alice".to_string(), "Bob".to_string(), 5);
-    blockchain.add_transaction("Charlie".to_string(), "Dana".to_string(), 3);
-    blockchain.add_transaction("Alice".to_string(), "Bob".to_string(), 5);
-    blockchain.add_transaction("Charlie".to_string(), "Dana".to_string(), 3);
-    blockchain.add_transaction("Alice".to_string(), "Bob".to_string(), 5);
-    blockchain.add_transaction("Charlie".to_string(), "Dana".to_string(), 3);
-    blockchain.add_transaction("Alice".to_string(), "Bob".to_string(), 5);
-    blockchain.add_transaction("Fred".to_string(), "Barney".to_string(), 3);
+    send(&mut blockchain, &alice, &charlie, 10, 1);
+    send(&mut blockchain, &alice, &fred, 5, 1);
+    send(&mut blockchain, &alice, &george, 10, 1);
+    send(&mut blockchain, &alice, &bob, 5, 2);
+    send(&mut blockchain, &charlie, &dana, 3, 1);
+    send(&mut blockchain, &alice, &bob, 5, 1);
+    send(&mut blockchain, &charlie, &dana, 3, 1);
+    send(&mut blockchain, &fred, &barney, 3, 1);
 
     // Create a dangling transaction that should be persisted, it won't create a block
-    blockchain.add_transaction("George".to_string(), "Henry".to_string(), 5);
+    send(&mut blockchain, &george, &henry, 5, 1);
+
+    // George bumps the fee of his pending transaction, same nonce and a higher
+    // fee replaces it in the mempool
+    let nonce = blockchain.next_nonce(&george.address()) - 1;
+    blockchain.add_transaction(george.create_transaction(&henry.address(), 5, 2, nonce))?;
+    for event in blockchain.take_mempool_events() {
+        let MempoolEvent::Evicted { transaction, reason } = event;
+        println!("Evicted from the mempool ({:?}): {}", reason, utils::to_hex_string(transaction.hash()));
+    }
+
+    // A transaction claiming to be from Alice but signed by Fred is rejected
+    let mut forged = Transaction::new(
+        alice.address(),
+        fred.address(),
+        100,
+        1,
+        blockchain.next_nonce(&alice.address()),
+    );
+    fred.sign_transaction(&mut forged);
+    assert!(
+        blockchain.add_transaction(forged).is_err(),
+        "A transaction signed with the wrong key should be rejected."
+    );
 
-    // Validation check
+    // Replaying a transaction that is already in a block is rejected because its nonce was used
+    let replayed = blockchain.chain[blockchain.chain.len() - 1].transactions[1].clone();
     assert!(
-        blockchain.validate_chain(),
-        "Blockchain should be valid after adding transactions and creating blocks."
+        blockchain.add_transaction(replayed).is_err(),
+        "A replayed transaction should be rejected."
     );
 
+    // Validation check, the chain should be valid after adding transactions
+    // and creating blocks
+    blockchain.validate_chain()?;
+    blockchain.save()?;
+    println!("Blockchain saved to {}", ctx.chain_dir());
+
+    println!("Balance of alice: {}", blockchain.balance_of(&alice.address()));
+    println!("Balance of bob: {}", blockchain.balance_of(&bob.address()));
+
+    if blockchain.get_chain_length() < 2 {
+        println!("No blocks were mined, skipping the Merkle proof");
+        println!("Test completed");
+        return Ok(());
+    }
+
     // Generate a Merkle proof for a transaction
     // Select a transaction hash for which to generate a Merkle proof
     // For simplicity, using the hash of the first transaction in the first non-genesis block
-    let transaction_hash = blockchain.chain[1].transactions[0].calculate_hash();
+    let transaction_hash = blockchain.chain[1].transactions[0].id();
 
     // Generate a Merkle proof for the selected transaction
     let merkle_proof = blockchain
@@ -73,21 +243,48 @@ fn run_test() -> Result<(), Box<dyn std::error::Error>> {
 }
 
 
-fn main() {
-    if let Err(e) = run_test() {
-        println!("Error running test: {}", e);
-    }
+fn demo(ctx: &Context) -> cli::CommandResult {
+    std::fs::create_dir_all(&ctx.data_dir)?;
+    run_demo(ctx)?;
+
+    // Print the chain as stored in the data directory
+    println!("{} contains:", ctx.chain_dir());
+    ctx.load_chain()?.print_json()?;
+    Ok(ExitCode::SUCCESS)
+}
 
-    // Print the contents of the blockchain.json file
-    let path = Path::new("blockchain.json");
-    let display = path.display();
-    let mut file = match File::open(&path) {
-        Err(why) => panic!("couldn't open {}: {}", display, why),
-        Ok(file) => file,
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+    let ctx = Context {
+        data_dir: cli.data_dir,
+        format: cli.format,
+        password: cli.password,
     };
-    let mut s = String::new();
-    match file.read_to_string(&mut s) {
-        Err(why) => panic!("couldn't read {}: {}", display, why),
-        Ok(_) => print!("{} contains:\n{}", display, s),
+
+    let result = match &cli.command {
+        Command::Init { ledger, genesis, store, force } => cli::init(&ctx, *ledger, genesis.as_ref(), *store, *force),
+        Command::Wallet(WalletCommand::New { name }) => cli::wallet_new(&ctx, name),
+        Command::Wallet(WalletCommand::List) => cli::wallet_list(&ctx),
+        Command::Send { from, to, amount, fee } => cli::send(&ctx, from, to, *amount, *fee),
+        Command::Mine { miner } => cli::mine(&ctx, miner.as_deref()),
+        Command::ShowBlock { block } => cli::show_block(&ctx, block),
+        Command::ShowChain => cli::show_chain(&ctx),
+        Command::Validate => cli::validate(&ctx),
+        Command::Prove { tx_hash, output } => cli::prove(&ctx, tx_hash, output.as_ref()),
+        Command::VerifyProof { file } => cli::verify_proof(&ctx, file),
+        Command::Mempool => cli::mempool(&ctx),
+        Command::Export { output, encoding } => cli::export(&ctx, output, *encoding),
+        Command::Convert { input, output } => cli::convert(&ctx, input, output),
+        Command::Rpc { address, save_every } => cli::rpc(&ctx, address, *save_every),
+        Command::Shell => shell::run(&ctx),
+        Command::Demo => demo(&ctx),
+    };
+
+    match result {
+        Ok(code) => code,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            ExitCode::from(cli::EXIT_FAILURE)
+        }
     }
 }
@@ -0,0 +1,482 @@
+use crate::transaction::{Transaction, TransactionError};
+use crate::utxo::OutPoint;
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet, VecDeque};
+
+// Bounds on the pending transactions a node keeps around.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+#[serde(default)]
+pub struct MempoolLimits {
+    pub max_transactions: usize,
+    // Sum of Transaction::size over all pending transactions
+    pub max_bytes: usize,
+    // Seconds a transaction may wait before it is dropped
+    pub ttl: i64,
+}
+
+impl Default for MempoolLimits {
+    fn default() -> Self {
+        MempoolLimits {
+            max_transactions: 1000,
+            max_bytes: 256 * 1024,
+            ttl: 60 * 60,
+        }
+    }
+}
+
+// Most eviction events kept for take_events. Nodes that never collect them
+// only lose the oldest ones instead of growing without bound.
+pub const MAX_MEMPOOL_EVENTS: usize = 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EvictionReason {
+    // Waited longer than the TTL
+    Expired,
+    // Lowest fee rate while the mempool was over its limits
+    Full,
+    // Replaced by a transaction with the same sender and nonce and a higher fee
+    Replaced,
+}
+
+#[derive(Debug, Clone)]
+pub enum MempoolEvent {
+    Evicted {
+        transaction: Transaction,
+        reason: EvictionReason,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct MempoolEntry {
+    transaction: Transaction,
+    // Unix timestamp of when the transaction was accepted
+    inserted_at: i64,
+}
+
+// Transactions waiting to be mined, kept ordered by fee per byte, highest
+// first. Equal fee rates keep their arrival order.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Mempool {
+    entries: Vec<MempoolEntry>,
+    #[serde(default)]
+    limits: MempoolLimits,
+    // Evictions not yet collected with take_events, the latest
+    // MAX_MEMPOOL_EVENTS of them
+    #[serde(skip)]
+    events: VecDeque<MempoolEvent>,
+}
+
+impl Mempool {
+    pub fn new(limits: MempoolLimits) -> Self {
+        Mempool {
+            entries: Vec::new(),
+            limits,
+            events: VecDeque::new(),
+        }
+    }
+
+    pub fn limits(&self) -> &MempoolLimits {
+        &self.limits
+    }
+
+    pub fn set_limits(&mut self, limits: MempoolLimits) {
+        self.limits = limits;
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn total_bytes(&self) -> usize {
+        self.entries.iter().map(|entry| entry.transaction.size()).sum()
+    }
+
+    pub fn contains(&self, hash: &[u8]) -> bool {
+        self.get(hash).is_some()
+    }
+
+    pub fn get(&self, hash: &[u8]) -> Option<&Transaction> {
+        self.iter().find(|transaction| transaction.hash == hash)
+    }
+
+    // All pending transactions, highest fee rate first.
+    pub fn iter(&self) -> impl Iterator<Item = &Transaction> {
+        self.entries.iter().map(|entry| &entry.transaction)
+    }
+
+    pub fn iter_by_sender<'a>(&'a self, sender: &'a str) -> impl Iterator<Item = &'a Transaction> {
+        self.iter().filter(move |transaction| transaction.sender() == sender)
+    }
+
+    // The pending transaction of `sender` with the given nonce, if any.
+    pub fn find_by_nonce(&self, sender: &str, nonce: u64) -> Option<&Transaction> {
+        self.iter()
+            .find(|transaction| transaction.sender() == sender && transaction.nonce() == nonce)
+    }
+
+    // True if a pending transaction already spends the output.
+    pub fn spends(&self, outpoint: &OutPoint) -> bool {
+        self.iter()
+            .flat_map(|transaction| transaction.inputs())
+            .any(|input| &input.previous_output == outpoint)
+    }
+
+    // Add a transaction in fee order, then evict the lowest paying ones while
+    // the mempool is over its limits. If the new transaction itself would be
+    // the one evicted, or it would follow a nonce of its sender that is, it is
+    // rejected instead and nothing changes.
+    pub fn insert(&mut self, transaction: Transaction, now: i64) -> Result<(), TransactionError> {
+        let position = self
+            .entries
+            .iter()
+            .position(|entry| transaction.cmp_fee_rate(&entry.transaction) == Ordering::Greater)
+            .unwrap_or(self.entries.len());
+
+        let size = transaction.size();
+        let mut evicted = HashSet::new();
+        let mut bytes = self.total_bytes() + size;
+        let mut count = self.entries.len() + 1;
+        // Walk up from the lowest fee rate to see what has to go
+        for entry in self.entries[position..].iter().rev() {
+            if count <= self.limits.max_transactions && bytes <= self.limits.max_bytes {
+                break;
+            }
+            evicted.insert(entry.transaction.hash.clone());
+            count -= 1;
+            bytes -= entry.transaction.size();
+        }
+        if count > self.limits.max_transactions || bytes > self.limits.max_bytes {
+            return Err(TransactionError::MempoolFull);
+        }
+        // The new transaction would be left behind a nonce that is evicted
+        let lowest = self.lowest_nonces(|entry| evicted.contains(&entry.transaction.hash));
+        if lowest
+            .get(transaction.sender())
+            .is_some_and(|&nonce| transaction.nonce() > nonce)
+        {
+            return Err(TransactionError::MempoolFull);
+        }
+
+        self.evict(|entry| evicted.contains(&entry.transaction.hash), EvictionReason::Full);
+        let position = self
+            .entries
+            .iter()
+            .position(|entry| transaction.cmp_fee_rate(&entry.transaction) == Ordering::Greater)
+            .unwrap_or(self.entries.len());
+        self.entries.insert(
+            position,
+            MempoolEntry {
+                transaction,
+                inserted_at: now,
+            },
+        );
+        Ok(())
+    }
+
+    // Take a transaction out of the mempool, for example once it is mined.
+    pub fn remove(&mut self, hash: &[u8]) -> Option<Transaction> {
+        let index = self
+            .entries
+            .iter()
+            .position(|entry| entry.transaction.hash == hash)?;
+        Some(self.entries.remove(index).transaction)
+    }
+
+    // Put `transaction` in place of the pending transaction `hash` that it
+    // replaces by fee, and record the eviction. If the replacement does not
+    // fit, the pending transaction stays and nothing changes.
+    pub fn replace(&mut self, hash: &[u8], transaction: Transaction, now: i64) -> Result<(), TransactionError> {
+        let index = match self.entries.iter().position(|entry| entry.transaction.hash == hash) {
+            Some(index) => index,
+            None => return self.insert(transaction, now),
+        };
+        let replaced = self.entries.remove(index);
+        if let Err(error) = self.insert(transaction, now) {
+            self.entries.insert(index, replaced);
+            return Err(error);
+        }
+        self.record_eviction(replaced.transaction, EvictionReason::Replaced);
+        Ok(())
+    }
+
+    // Drop every transaction that has waited longer than the TTL.
+    pub fn expire(&mut self, now: i64) {
+        let ttl = self.limits.ttl;
+        self.evict(|entry| now - entry.inserted_at > ttl, EvictionReason::Expired);
+    }
+
+    // Lowest nonce per sender among the entries matching `selected`.
+    fn lowest_nonces(&self, selected: impl Fn(&MempoolEntry) -> bool) -> HashMap<String, u64> {
+        let mut lowest: HashMap<String, u64> = HashMap::new();
+        for entry in self.entries.iter().filter(|entry| selected(entry)) {
+            let nonce = lowest
+                .entry(entry.transaction.sender().to_string())
+                .or_insert(u64::MAX);
+            *nonce = (*nonce).min(entry.transaction.nonce());
+        }
+        lowest
+    }
+
+    // Remove the entries matching `selected`, together with the pending
+    // transactions of the same senders with a higher nonce, which can not be
+    // mined anymore without the ones before them.
+    fn evict(&mut self, selected: impl Fn(&MempoolEntry) -> bool, reason: EvictionReason) {
+        let lowest = self.lowest_nonces(&selected);
+        if lowest.is_empty() {
+            return;
+        }
+        let (evicted, kept): (Vec<_>, Vec<_>) = self.entries.drain(..).partition(|entry| {
+            selected(entry)
+                || lowest
+                    .get(entry.transaction.sender())
+                    .is_some_and(|&nonce| entry.transaction.nonce() > nonce)
+        });
+        self.entries = kept;
+        for entry in evicted {
+            self.record_eviction(entry.transaction, reason);
+        }
+    }
+
+    fn record_eviction(&mut self, transaction: Transaction, reason: EvictionReason) {
+        if self.events.len() == MAX_MEMPOOL_EVENTS {
+            self.events.pop_front();
+        }
+        self.events.push_back(MempoolEvent::Evicted { transaction, reason });
+    }
+
+    // Collect the eviction events recorded since the last call.
+    pub fn take_events(&mut self) -> Vec<MempoolEvent> {
+        self.events.drain(..).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::Blockchain;
+    use crate::config::ChainConfig;
+    use crate::genesis::GenesisSpec;
+    use crate::wallet::Wallet;
+
+    const NOW: i64 = 1_700_000_000;
+
+    // Unsigned transactions are fine here, the mempool does not check them.
+    // All of them have the same size, so the fee alone sets the fee rate.
+    fn transaction(sender: &str, fee: u64, nonce: u64) -> Transaction {
+        Transaction::new(sender.to_string(), "bob".to_string(), 10, fee, nonce)
+    }
+
+    fn evictions(mempool: &mut Mempool) -> Vec<(u64, EvictionReason)> {
+        mempool
+            .take_events()
+            .into_iter()
+            .map(|MempoolEvent::Evicted { transaction, reason }| (transaction.fee(), reason))
+            .collect()
+    }
+
+    fn fees(mempool: &Mempool) -> Vec<u64> {
+        mempool.iter().map(Transaction::fee).collect()
+    }
+
+    #[test]
+    fn transactions_are_kept_in_fee_rate_order() {
+        let mut mempool = Mempool::default();
+        for (sender, fee) in [("alice", 2), ("carol", 5), ("david", 2), ("erika", 9)] {
+            mempool.insert(transaction(sender, fee, 0), NOW).unwrap();
+        }
+        assert_eq!(fees(&mempool), vec![9, 5, 2, 2]);
+        // Equal fee rates keep their arrival order
+        assert_eq!(mempool.iter().nth(2).unwrap().sender(), "alice");
+    }
+
+    #[test]
+    fn pending_transactions_are_found_by_sender_and_nonce() {
+        let mut mempool = Mempool::default();
+        mempool.insert(transaction("alice", 1, 0), NOW).unwrap();
+        mempool.insert(transaction("carol", 2, 0), NOW).unwrap();
+        mempool.insert(transaction("alice", 3, 1), NOW).unwrap();
+
+        let nonces = mempool.iter_by_sender("alice").map(Transaction::nonce).collect::<Vec<_>>();
+        assert_eq!(nonces, vec![1, 0]);
+        assert_eq!(mempool.iter_by_sender("nobody").count(), 0);
+        assert_eq!(mempool.find_by_nonce("alice", 1).unwrap().fee(), 3);
+        assert!(mempool.find_by_nonce("carol", 1).is_none());
+    }
+
+    #[test]
+    fn full_mempool_evicts_the_lowest_fee_rate() {
+        let mut mempool = Mempool::new(MempoolLimits {
+            max_transactions: 2,
+            ..MempoolLimits::default()
+        });
+        mempool.insert(transaction("alice", 3, 0), NOW).unwrap();
+        mempool.insert(transaction("carol", 1, 0), NOW).unwrap();
+
+        // Paying no more than the lowest one is not enough to get in
+        assert_eq!(mempool.insert(transaction("david", 1, 0), NOW), Err(TransactionError::MempoolFull));
+        assert_eq!(fees(&mempool), vec![3, 1]);
+
+        mempool.insert(transaction("david", 2, 0), NOW).unwrap();
+        assert_eq!(fees(&mempool), vec![3, 2]);
+        assert_eq!(evictions(&mut mempool), vec![(1, EvictionReason::Full)]);
+    }
+
+    #[test]
+    fn byte_limit_evicts_as_many_as_needed() {
+        let size = transaction("alice", 1, 0).size();
+        let mut mempool = Mempool::new(MempoolLimits {
+            max_bytes: 3 * size,
+            ..MempoolLimits::default()
+        });
+        for (sender, fee) in [("alice", 1), ("carol", 2), ("david", 3)] {
+            mempool.insert(transaction(sender, fee, 0), NOW).unwrap();
+        }
+        assert_eq!(mempool.total_bytes(), 3 * size);
+
+        // A transaction twice the size of the others needs two of them gone
+        let large = Transaction::new("erika".repeat(8), "bob".to_string(), 10, 1000, 0);
+        assert!(large.size() > size && large.size() <= 2 * size);
+        mempool.insert(large, NOW).unwrap();
+        assert_eq!(fees(&mempool), vec![1000, 3]);
+        assert!(mempool.total_bytes() <= 3 * size);
+    }
+
+    #[test]
+    fn transactions_expire_after_the_ttl() {
+        let mut mempool = Mempool::default();
+        let ttl = mempool.limits().ttl;
+        mempool.insert(transaction("alice", 1, 0), NOW).unwrap();
+        mempool.insert(transaction("carol", 2, 0), NOW + 10).unwrap();
+
+        mempool.expire(NOW + ttl);
+        assert_eq!(mempool.len(), 2);
+        mempool.expire(NOW + ttl + 1);
+        assert_eq!(fees(&mempool), vec![2]);
+        assert_eq!(evictions(&mut mempool), vec![(1, EvictionReason::Expired)]);
+        assert!(evictions(&mut mempool).is_empty());
+    }
+
+    #[test]
+    fn only_the_latest_events_are_kept() {
+        let mut mempool = Mempool::new(MempoolLimits {
+            max_transactions: 1,
+            ..MempoolLimits::default()
+        });
+        let count = MAX_MEMPOOL_EVENTS as u64 + 10;
+        for fee in 1..=count {
+            mempool.insert(transaction("alice", fee, 0), NOW).unwrap();
+        }
+        let events = evictions(&mut mempool);
+        assert_eq!(events.len(), MAX_MEMPOOL_EVENTS);
+        assert_eq!(events.last(), Some(&(count - 1, EvictionReason::Full)));
+    }
+
+    #[test]
+    fn replace_by_fee_needs_a_higher_fee() {
+        let alice = Wallet::generate();
+        let spec = GenesisSpec {
+            allocations: [(alice.address(), 100)].into_iter().collect(),
+            consensus: ChainConfig {
+                initial_difficulty: 4,
+                ..ChainConfig::default()
+            },
+            ..GenesisSpec::default()
+        };
+        let mut blockchain = Blockchain::from_genesis(&spec);
+        let original = alice.create_transaction("bob", 10, 2, 0);
+        blockchain.add_transaction(original.clone()).unwrap();
+
+        assert_eq!(
+            blockchain.add_transaction(alice.create_transaction("carol", 10, 2, 0)),
+            Err(TransactionError::ReplacementUnderpriced { fee: 2, required: 2 })
+        );
+
+        let replacement = alice.create_transaction("carol", 10, 3, 0);
+        blockchain.add_transaction(replacement.clone()).unwrap();
+        assert!(!blockchain.mempool().contains(&original.hash));
+        assert!(blockchain.mempool().contains(&replacement.hash));
+        assert_eq!(blockchain.next_nonce(&alice.address()), 1);
+        let events = blockchain.take_mempool_events();
+        assert!(matches!(
+            events.as_slice(),
+            [MempoolEvent::Evicted { transaction, reason: EvictionReason::Replaced }] if transaction.hash == original.hash
+        ));
+    }
+
+    #[test]
+    fn eviction_takes_the_later_nonces_of_the_sender_along() {
+        let mut mempool = Mempool::new(MempoolLimits {
+            max_transactions: 3,
+            ..MempoolLimits::default()
+        });
+        mempool.insert(transaction("alice", 1, 0), NOW).unwrap();
+        mempool.insert(transaction("alice", 5, 1), NOW).unwrap();
+        mempool.insert(transaction("carol", 3, 0), NOW).unwrap();
+
+        // Evicting alice's nonce 0 leaves her nonce 1 unminable, so it goes too
+        mempool.insert(transaction("david", 4, 0), NOW).unwrap();
+        assert_eq!(fees(&mempool), vec![4, 3]);
+        assert_eq!(
+            evictions(&mut mempool),
+            vec![(5, EvictionReason::Full), (1, EvictionReason::Full)]
+        );
+    }
+
+    #[test]
+    fn replacement_that_does_not_fit_keeps_the_original() {
+        let mut mempool = Mempool::default();
+        let original = transaction("alice", 1, 0);
+        mempool.insert(original.clone(), NOW).unwrap();
+
+        // A larger replacement does not fit in a pool that is full by bytes
+        mempool.set_limits(MempoolLimits {
+            max_bytes: original.size(),
+            ..MempoolLimits::default()
+        });
+        let larger = Transaction::new("alice".to_string(), "bob-with-a-longer-address".to_string(), 10, 2, 0);
+        assert_eq!(mempool.replace(&original.hash, larger, NOW), Err(TransactionError::MempoolFull));
+        assert!(mempool.contains(&original.hash));
+        assert!(evictions(&mut mempool).is_empty());
+
+        let replacement = transaction("alice", 2, 0);
+        mempool.replace(&original.hash, replacement.clone(), NOW).unwrap();
+        assert!(!mempool.contains(&original.hash));
+        assert!(mempool.contains(&replacement.hash));
+        assert_eq!(evictions(&mut mempool), vec![(1, EvictionReason::Replaced)]);
+    }
+
+    #[test]
+    fn transactions_after_an_evicted_nonce_are_rejected() {
+        let mut mempool = Mempool::new(MempoolLimits {
+            max_transactions: 2,
+            ..MempoolLimits::default()
+        });
+        mempool.insert(transaction("alice", 1, 0), NOW).unwrap();
+        mempool.insert(transaction("carol", 3, 0), NOW).unwrap();
+
+        assert_eq!(mempool.insert(transaction("alice", 4, 1), NOW), Err(TransactionError::MempoolFull));
+        assert_eq!(mempool.len(), 2);
+        assert!(evictions(&mut mempool).is_empty());
+    }
+
+    #[test]
+    fn expiry_takes_the_later_nonces_of_the_sender_along() {
+        let mut mempool = Mempool::default();
+        let ttl = mempool.limits().ttl;
+        mempool.insert(transaction("alice", 1, 0), NOW).unwrap();
+        mempool.insert(transaction("alice", 2, 1), NOW + ttl).unwrap();
+        mempool.insert(transaction("carol", 3, 0), NOW + ttl).unwrap();
+
+        mempool.expire(NOW + ttl + 1);
+        assert_eq!(fees(&mempool), vec![3]);
+        assert_eq!(
+            evictions(&mut mempool),
+            vec![(2, EvictionReason::Expired), (1, EvictionReason::Expired)]
+        );
+    }
+}
@@ -1,33 +1,154 @@
 // merkle_proof.rs
+use crate::codec::{CodecError, Decode, Encode, Reader, Writer};
+use crate::utils::{from_hex_string, to_hex_string};
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
 
+// Prefixes that keep leaves and inner nodes of an Rfc6962 tree apart.
+pub const LEAF_PREFIX: u8 = 0x00;
+pub const NODE_PREFIX: u8 = 0x01;
+
+// How a Merkle tree hashes its leaves and nodes, chosen by the block version,
+// see Block::merkle_variant.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum MerkleVariant {
+    // The leaves are the transaction ids themselves, nodes are the hash of
+    // their children, and the last node of an odd layer is paired with
+    // itself. Lists like [a, b, c] and [a, b, c, c] get the same root
+    // (CVE-2012-2459), and an inner node can pass for a leaf.
+    #[default]
+    Legacy,
+    // RFC 6962: leaves are hashed with LEAF_PREFIX and nodes with
+    // NODE_PREFIX, and the last node of an odd layer moves up a layer as it
+    // is. Every list of leaves has its own root.
+    Rfc6962,
+}
+
+impl MerkleVariant {
+    pub fn hash_leaf(self, leaf: &[u8]) -> Vec<u8> {
+        match self {
+            MerkleVariant::Legacy => leaf.to_vec(),
+            MerkleVariant::Rfc6962 => MerkleProof::hash_function(&[&[LEAF_PREFIX], leaf].concat()),
+        }
+    }
+
+    pub fn hash_node(self, left: &[u8], right: &[u8]) -> Vec<u8> {
+        match self {
+            MerkleVariant::Legacy => MerkleProof::hash_function(&[left, right].concat()),
+            MerkleVariant::Rfc6962 => MerkleProof::hash_function(&[&[NODE_PREFIX], left, right].concat()),
+        }
+    }
+}
+
+// A Merkle tree over a list of leaves, such as the transaction ids of a
+// block. Every layer is hashed once when the tree is built and kept, so the
+// root and the proof of any leaf are read off the stored layers. The root and
+// the proofs always come from the same layers, so they agree for any number
+// of leaves.
+#[derive(Debug, Clone)]
+pub struct MerkleTree {
+    variant: MerkleVariant,
+    leaves: Vec<Vec<u8>>,
+    // The hashed leaves first, up to the layer holding only the root
+    layers: Vec<Vec<Vec<u8>>>,
+}
+
+impl MerkleTree {
+    pub fn new(variant: MerkleVariant, leaves: Vec<Vec<u8>>) -> Self {
+        let mut layers = vec![leaves.iter().map(|leaf| variant.hash_leaf(leaf)).collect::<Vec<_>>()];
+        while layers.last().unwrap().len() > 1 {
+            let layer = layers.last().unwrap();
+            let next = layer
+                .chunks(2)
+                .map(|pair| match (pair, variant) {
+                    ([left, right], _) => variant.hash_node(left, right),
+                    ([single], MerkleVariant::Legacy) => variant.hash_node(single, single),
+                    ([single], MerkleVariant::Rfc6962) => single.clone(),
+                    _ => unreachable!("chunks of two"),
+                })
+                .collect();
+            layers.push(next);
+        }
+        MerkleTree { variant, leaves, layers }
+    }
+
+    pub fn variant(&self) -> MerkleVariant {
+        self.variant
+    }
+
+    pub fn len(&self) -> usize {
+        self.leaves.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.leaves.is_empty()
+    }
+
+    // None for a tree without leaves.
+    pub fn root(&self) -> Option<&[u8]> {
+        self.layers.last()?.first().map(Vec::as_slice)
+    }
+
+    // Proof that the leaf at `index` is in the tree, None if there is no
+    // such leaf. In a legacy tree the last node of an odd layer is its own
+    // sibling, in an Rfc6962 tree it moves up without a step.
+    pub fn proof(&self, index: usize) -> Option<MerkleProof> {
+        let leaf = self.leaves.get(index)?.clone();
+        let mut path = Vec::new();
+        let mut index = index;
+        for layer in &self.layers[..self.layers.len() - 1] {
+            let sibling = index ^ 1;
+            match (layer.get(sibling), self.variant) {
+                (Some(hash), _) => path.push((hash.clone(), sibling < index)),
+                (None, MerkleVariant::Legacy) => path.push((layer[index].clone(), false)),
+                (None, MerkleVariant::Rfc6962) => {}
+            }
+            index /= 2;
+        }
+        Some(MerkleProof {
+            leaf,
+            path,
+            variant: self.variant,
+        })
+    }
+
+    // Proof for the first leaf equal to `leaf`.
+    pub fn proof_for(&self, leaf: &[u8]) -> Option<MerkleProof> {
+        let index = self.leaves.iter().position(|candidate| candidate == leaf)?;
+        self.proof(index)
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct MerkleProof {
     pub leaf: Vec<u8>,
     pub path: Vec<(Vec<u8>, bool)>, // (hash, is_right)
+    // Tree the proof is a path through, proofs without one are Legacy
+    #[serde(default)]
+    pub variant: MerkleVariant,
 }
 
 impl MerkleProof {
     pub fn new(leaf: Vec<u8>, path: Vec<(Vec<u8>, bool)>) -> Self {
-        MerkleProof { leaf, path }
+        MerkleProof {
+            leaf,
+            path,
+            variant: MerkleVariant::Legacy,
+        }
     }
 
     // Implement the method to verify the proof against a given Merkle root
     pub fn verify(&self, merkle_root: &Vec<u8>) -> bool {
-        let mut current_hash = self.leaf.clone();
+        let mut current_hash = self.variant.hash_leaf(&self.leaf);
         for (hash, is_right) in &self.path {
-            // Combine the current hash with the next hash in the path
-            let combined = if *is_right {
-                // If the current node is supposed to be on the right, append it to the hash from the path
-                [hash.as_slice(), current_hash.as_slice()].concat()
+            // Combine the current hash with the next hash in the path, if the
+            // current node is on the right the path hash goes first
+            current_hash = if *is_right {
+                self.variant.hash_node(hash, &current_hash)
             } else {
-                // If the current node is on the left, append the path hash to it
-                [current_hash.as_slice(), hash.as_slice()].concat()
+                self.variant.hash_node(&current_hash, hash)
             };
-
-            // Hash the combined pair to get the new current hash
-            current_hash = MerkleProof::hash_function(&combined);
         }
 
         // Check if the final hash matches the provided Merkle root
@@ -40,3 +161,180 @@ impl MerkleProof {
         hasher.finalize().to_vec()
     }
 }
+
+impl Encode for MerkleProof {
+    fn encode_to(&self, writer: &mut Writer) {
+        writer.bytes(&self.leaf);
+        writer.u32(self.path.len() as u32);
+        for (hash, is_right) in &self.path {
+            writer.bytes(hash);
+            writer.bool(*is_right);
+        }
+        writer.u8(match self.variant {
+            MerkleVariant::Legacy => 0,
+            MerkleVariant::Rfc6962 => 1,
+        });
+    }
+}
+
+impl Decode for MerkleProof {
+    fn decode_from(reader: &mut Reader) -> Result<Self, CodecError> {
+        let leaf = reader.bytes()?;
+        let steps = reader.u32()?;
+        let mut path = Vec::new();
+        for _ in 0..steps {
+            path.push((reader.bytes()?, reader.bool()?));
+        }
+        // Version 1 of the format had Legacy proofs only
+        let variant = match reader.format_version() {
+            1 => MerkleVariant::Legacy,
+            _ => match reader.u8()? {
+                0 => MerkleVariant::Legacy,
+                1 => MerkleVariant::Rfc6962,
+                tag => return Err(CodecError::InvalidTag(tag)),
+            },
+        };
+        Ok(MerkleProof { leaf, path, variant })
+    }
+}
+
+// A MerkleProof together with the block it proves inclusion in, with every
+// hash hex encoded. This is what `rust_chain prove` writes and the
+// getMerkleProof RPC method returns.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct InclusionProof {
+    pub block_height: u32,
+    pub merkle_root: String,
+    pub leaf: String,
+    pub path: Vec<ProofStep>,
+    #[serde(default)]
+    pub variant: MerkleVariant,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ProofStep {
+    pub hash: String,
+    pub is_right: bool,
+}
+
+impl InclusionProof {
+    pub fn new(block_height: u32, merkle_root: &[u8], proof: &MerkleProof) -> Self {
+        InclusionProof {
+            block_height,
+            merkle_root: to_hex_string(merkle_root),
+            leaf: to_hex_string(&proof.leaf),
+            path: proof
+                .path
+                .iter()
+                .map(|(hash, is_right)| ProofStep {
+                    hash: to_hex_string(hash),
+                    is_right: *is_right,
+                })
+                .collect(),
+            variant: proof.variant,
+        }
+    }
+
+    // Decode the Merkle root and the proof, None if any hash is not valid hex.
+    pub fn decode(&self) -> Option<(Vec<u8>, MerkleProof)> {
+        let path = self
+            .path
+            .iter()
+            .map(|step| from_hex_string(&step.hash).map(|hash| (hash, step.is_right)))
+            .collect::<Option<Vec<_>>>()?;
+        let proof = MerkleProof {
+            leaf: from_hex_string(&self.leaf)?,
+            path,
+            variant: self.variant,
+        };
+        Some((from_hex_string(&self.merkle_root)?, proof))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::{Block, BLOCK_VERSION, LEGACY_BLOCK_VERSION};
+    use crate::test_utils::mined_block;
+    use crate::transaction::Transaction;
+
+    fn leaves(count: u8) -> Vec<Vec<u8>> {
+        (0..count).map(|byte| MerkleProof::hash_function(&[byte])).collect()
+    }
+
+    fn block(version: u32, transactions: Vec<Transaction>) -> Block {
+        Block {
+            version,
+            ..mined_block(None, 1_700_000_000, transactions)
+        }
+    }
+
+    #[test]
+    fn proofs_verify_for_every_leaf() {
+        for variant in [MerkleVariant::Legacy, MerkleVariant::Rfc6962] {
+            for count in 1..=9 {
+                let tree = MerkleTree::new(variant, leaves(count));
+                let root = tree.root().unwrap().to_vec();
+                for index in 0..tree.len() {
+                    let proof = tree.proof(index).unwrap();
+                    assert!(proof.verify(&root), "{:?} leaf {} of {}", variant, index, count);
+                    assert_eq!(tree.proof_for(&proof.leaf).unwrap().path, proof.path);
+
+                    // A proof only verifies through the tree it was made for
+                    let other = match variant {
+                        MerkleVariant::Legacy => MerkleVariant::Rfc6962,
+                        MerkleVariant::Rfc6962 => MerkleVariant::Legacy,
+                    };
+                    assert!(!MerkleProof { variant: other, ..proof }.verify(&root));
+                }
+                assert!(tree.proof(tree.len()).is_none());
+            }
+            assert_eq!(MerkleTree::new(variant, Vec::new()).root(), None);
+        }
+    }
+
+    #[test]
+    fn legacy_trees_pair_the_last_node_of_an_odd_layer_with_itself() {
+        let leaves = leaves(3);
+        let hash = |left: &[u8], right: &[u8]| MerkleProof::hash_function(&[left, right].concat());
+        let root = hash(&hash(&leaves[0], &leaves[1]), &hash(&leaves[2], &leaves[2]));
+
+        let tree = MerkleTree::new(MerkleVariant::Legacy, leaves.clone());
+        assert_eq!(tree.root(), Some(root.as_slice()));
+        assert_eq!(tree.proof(2).unwrap().path[0], (leaves[2].clone(), false));
+    }
+
+    #[test]
+    fn duplicating_the_last_transaction_changes_only_the_rfc6962_root() {
+        let coinbase = Transaction::coinbase("miner".to_string(), 50, 1);
+        let other = Transaction::coinbase("other".to_string(), 50, 1);
+        let three = vec![coinbase.clone(), other.clone(), coinbase.clone()];
+        let four = vec![coinbase.clone(), other, coinbase.clone(), coinbase];
+
+        let (legacy_three, legacy_four) = (block(LEGACY_BLOCK_VERSION, three.clone()), block(LEGACY_BLOCK_VERSION, four.clone()));
+        assert_eq!(legacy_three.calculate_merkle_root(), legacy_four.calculate_merkle_root());
+
+        let (three, four) = (block(BLOCK_VERSION, three), block(BLOCK_VERSION, four));
+        assert_ne!(three.calculate_merkle_root(), four.calculate_merkle_root());
+    }
+
+    #[test]
+    fn inner_nodes_do_not_pass_for_leaves() {
+        let leaves = leaves(4);
+        let tree = MerkleTree::new(MerkleVariant::Rfc6962, leaves.clone());
+        let root = tree.root().unwrap().to_vec();
+        let path = tree.proof(0).unwrap().path;
+
+        // The parent of the first two leaves, proven with the rest of the path
+        let node = MerkleVariant::Rfc6962.hash_node(
+            &MerkleVariant::Rfc6962.hash_leaf(&leaves[0]),
+            &MerkleVariant::Rfc6962.hash_leaf(&leaves[1]),
+        );
+        let proof = MerkleProof {
+            leaf: node,
+            path: path[1..].to_vec(),
+            variant: MerkleVariant::Rfc6962,
+        };
+        assert!(!proof.verify(&root));
+    }
+}
@@ -0,0 +1,449 @@
+use crate::block::Block;
+use crate::blockchain::{BlockError, Blockchain, SubmitOutcome};
+use crate::storage::StoreError;
+use crate::transaction::{Transaction, TransactionError};
+use crate::utils::to_hex_string;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+// Version of the wire protocol. Peers speaking another one are disconnected.
+pub const PROTOCOL_VERSION: u32 = 1;
+// Most blocks sent in answer to a single GetBlocks, the peer asks again for more.
+const MAX_BLOCKS_PER_MESSAGE: usize = 100;
+// Longest message line a peer may send. Peers sending a longer one are
+// disconnected, so a peer can not make us buffer without end.
+pub const MAX_MESSAGE_BYTES: usize = 16 * 1024 * 1024;
+// How long a peer may take to send its Version, or the rest of a message it
+// started. Peers may stay quiet for as long as they like between messages.
+const READ_TIMEOUT: Duration = Duration::from_secs(30);
+// How long writing a message to a peer may block before it is dropped.
+const WRITE_TIMEOUT: Duration = Duration::from_secs(30);
+// Messages queued for a peer that does not keep up with reading them. The
+// peer is dropped when its queue is full.
+const MAX_QUEUED_MESSAGES: usize = 64;
+
+// Messages between nodes, sent as one JSON object per line.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Message {
+    // First message on every connection, in both directions. Nodes only talk
+    // to peers on the same chain, identified by the hex hash of its genesis.
+    Version {
+        protocol: u32,
+        genesis: String,
+        height: u32,
+    },
+    // Ask for the best chain blocks after the first of the locator hashes the
+    // peer has on its best chain, see Node::locator
+    GetBlocks { locator: Vec<String> },
+    Blocks { blocks: Vec<Block> },
+    NewBlock { block: Block },
+    NewTransaction { transaction: Transaction },
+}
+
+// The blockchain of a node, shared between its connection threads.
+pub type SharedChain = Arc<Mutex<Blockchain>>;
+
+// An open connection. Messages to the peer go through its queue to a thread
+// of its own that writes them, so a peer that stops reading only ever holds
+// up that thread.
+struct Peer {
+    stream: TcpStream,
+    outbox: SyncSender<Arc<Vec<u8>>>,
+}
+
+struct Shared {
+    chain: SharedChain,
+    // The open connections, by peer id
+    peers: Mutex<HashMap<u64, Peer>>,
+    next_peer_id: AtomicU64,
+    shutdown: AtomicBool,
+    // Why the node stopped by itself, see Node::take_save_error
+    save_error: Mutex<Option<StoreError>>,
+}
+
+// A node listening for peers on a TCP port. Every connection gets a thread
+// reading its messages and one writing them. The chain is locked while a
+// message is handled but never while sending, and sending only queues the
+// message, so two nodes can not block each other. Clones share the same
+// listener and connections.
+#[derive(Clone)]
+pub struct Node {
+    local_addr: SocketAddr,
+    shared: Arc<Shared>,
+}
+
+impl Node {
+    // Listen on `addr`, which may use port 0 to pick a free port.
+    pub fn start(chain: SharedChain, addr: impl ToSocketAddrs) -> io::Result<Node> {
+        let listener = TcpListener::bind(addr)?;
+        let local_addr = listener.local_addr()?;
+        let shared = Arc::new(Shared {
+            chain,
+            peers: Mutex::new(HashMap::new()),
+            next_peer_id: AtomicU64::new(0),
+            shutdown: AtomicBool::new(false),
+            save_error: Mutex::new(None),
+        });
+
+        let listening = shared.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                if listening.shutdown.load(Ordering::SeqCst) {
+                    break;
+                }
+                if let Ok(stream) = stream {
+                    let _ = listening.add_peer(stream);
+                }
+            }
+        });
+
+        Ok(Node { local_addr, shared })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    pub fn chain(&self) -> &SharedChain {
+        &self.shared.chain
+    }
+
+    // A node whose chain fails to save stops, rather than keep taking
+    // blocks it can not keep. This is the error it stopped on, if any.
+    pub fn take_save_error(&self) -> Option<StoreError> {
+        self.shared.save_error.lock().unwrap().take()
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.shared.shutdown.load(Ordering::SeqCst)
+    }
+
+    pub fn peer_count(&self) -> usize {
+        self.shared.peers.lock().unwrap().len()
+    }
+
+    // Open a connection to another node. The handshake and the sync of any
+    // blocks the other side is ahead by happen in the background.
+    pub fn connect(&self, addr: impl ToSocketAddrs) -> io::Result<()> {
+        let stream = TcpStream::connect(addr)?;
+        self.shared.add_peer(stream)
+    }
+
+    // Add a transaction to the local mempool and gossip it to all peers.
+    pub fn submit_transaction(&self, transaction: Transaction) -> Result<(), TransactionError> {
+        self.shared.accept_transaction(transaction, None)
+    }
+
+    // Mine a block on the local chain and announce it to all peers.
+    pub fn mine_block(&self, miner_address: &str) -> Block {
+        let mut blockchain = self.shared.chain.lock().unwrap();
+        let block = blockchain.mine_block(miner_address).clone();
+        self.shared.persist(&mut blockchain);
+        drop(blockchain);
+        self.shared.broadcast(&Message::NewBlock { block: block.clone() }, None);
+        block
+    }
+
+    // Stop accepting peers and close every connection.
+    pub fn shutdown(&self) {
+        self.shared.stop();
+        // Wake up the accept loop so it sees the flag
+        let _ = TcpStream::connect(self.local_addr);
+    }
+
+    // Hashes of best chain blocks from the tip back to genesis, the first ten
+    // one by one and then with doubling gaps, so a peer can find where its
+    // chain and ours fork in few hashes however long they are.
+    pub fn locator(blockchain: &Blockchain) -> Vec<String> {
+        let chain = blockchain.get_chain();
+        let mut locator = Vec::new();
+        let mut height = chain.len() - 1;
+        let mut step = 1;
+        loop {
+            locator.push(block_hash(&chain[height]));
+            if height == 0 {
+                break;
+            }
+            if locator.len() >= 10 {
+                step *= 2;
+            }
+            height = height.saturating_sub(step);
+        }
+        locator
+    }
+}
+
+impl Shared {
+    fn stop(&self) {
+        self.shutdown.store(true, Ordering::SeqCst);
+        for (_, peer) in self.peers.lock().unwrap().drain() {
+            let _ = peer.stream.shutdown(Shutdown::Both);
+        }
+    }
+
+    // Save the chain if its persistence policy says so. If that fails the
+    // node stops, keeping the error for Node::take_save_error.
+    fn persist(&self, blockchain: &mut Blockchain) {
+        if let Err(error) = blockchain.persist() {
+            self.save_error.lock().unwrap().get_or_insert(error);
+            self.stop();
+        }
+    }
+
+    fn add_peer(self: &Arc<Self>, stream: TcpStream) -> io::Result<()> {
+        if self.shutdown.load(Ordering::SeqCst) {
+            return Err(io::Error::other("the node is stopped"));
+        }
+        let id = self.next_peer_id.fetch_add(1, Ordering::SeqCst);
+        stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+        let (outbox, queue) = mpsc::sync_channel(MAX_QUEUED_MESSAGES);
+        let writer = stream.try_clone()?;
+        self.peers.lock().unwrap().insert(
+            id,
+            Peer {
+                stream: stream.try_clone()?,
+                outbox,
+            },
+        );
+        let shared = self.clone();
+        thread::spawn(move || {
+            write_messages(writer, queue);
+            shared.disconnect(id);
+        });
+
+        let version = {
+            let blockchain = self.chain.lock().unwrap();
+            Message::Version {
+                protocol: PROTOCOL_VERSION,
+                genesis: block_hash(&blockchain.get_chain()[0]),
+                height: blockchain.get_block_height(),
+            }
+        };
+        self.send(id, &version);
+
+        let shared = self.clone();
+        thread::spawn(move || {
+            shared.read_messages(id, stream);
+            shared.disconnect(id);
+        });
+        Ok(())
+    }
+
+    fn disconnect(&self, id: u64) {
+        if let Some(peer) = self.peers.lock().unwrap().remove(&id) {
+            let _ = peer.stream.shutdown(Shutdown::Both);
+        }
+    }
+
+    // Handle messages from a peer until it disconnects or misbehaves.
+    fn read_messages(&self, id: u64, stream: TcpStream) {
+        if stream.set_read_timeout(Some(READ_TIMEOUT)).is_err() {
+            return;
+        }
+        let mut reader = BufReader::new(stream);
+        let mut handshake_done = false;
+        while let Some(line) = read_line(&mut reader, handshake_done) {
+            let message = match serde_json::from_slice(&line) {
+                Ok(message) => message,
+                Err(_) => return,
+            };
+            // The first message must be a matching Version
+            if !handshake_done && !matches!(message, Message::Version { .. }) {
+                return;
+            }
+            if !self.handle_message(id, message) {
+                return;
+            }
+            handshake_done = true;
+        }
+    }
+
+    // Returns false if the connection should be closed.
+    fn handle_message(&self, id: u64, message: Message) -> bool {
+        match message {
+            Message::Version { protocol, genesis, height } => {
+                let blockchain = self.chain.lock().unwrap();
+                if protocol != PROTOCOL_VERSION || genesis != block_hash(&blockchain.get_chain()[0]) {
+                    return false;
+                }
+                // Sync if the peer is ahead
+                if height > blockchain.get_block_height() {
+                    let locator = Node::locator(&blockchain);
+                    drop(blockchain);
+                    self.send(id, &Message::GetBlocks { locator });
+                }
+            }
+            Message::GetBlocks { locator } => {
+                let blocks = {
+                    let blockchain = self.chain.lock().unwrap();
+                    let chain = blockchain.get_chain();
+                    let fork = locator
+                        .iter()
+                        .find_map(|hash| chain.iter().position(|block| block_hash(block) == *hash))
+                        .unwrap_or(0);
+                    // Leave room for the rest of the message, the peer
+                    // drops us if it goes over MAX_MESSAGE_BYTES
+                    let mut size = 0;
+                    chain[fork + 1..]
+                        .iter()
+                        .take(MAX_BLOCKS_PER_MESSAGE)
+                        .take_while(|block| {
+                            size += serde_json::to_vec(block).map_or(usize::MAX, |json| json.len() + 1);
+                            size < MAX_MESSAGE_BYTES - 1024
+                        })
+                        .cloned()
+                        .collect::<Vec<Block>>()
+                };
+                if !blocks.is_empty() {
+                    self.send(id, &Message::Blocks { blocks });
+                }
+            }
+            Message::Blocks { blocks } => {
+                let mut blockchain = self.chain.lock().unwrap();
+                let old_tip = blockchain.get_latest_block().hash.clone();
+                for block in blocks {
+                    if let Err(BlockError::Invalid(_)) = blockchain.submit_block(block) {
+                        self.persist(&mut blockchain);
+                        return false;
+                    }
+                }
+                self.persist(&mut blockchain);
+                let tip = blockchain.get_latest_block().clone();
+                let locator = Node::locator(&blockchain);
+                drop(blockchain);
+
+                // Ask for more until a batch no longer moves the tip. The
+                // batches are cut by count and by size, so a short one does
+                // not mean the peer has nothing left.
+                if tip.hash != old_tip {
+                    self.send(id, &Message::GetBlocks { locator });
+                    // Let the other peers catch up with the new tip too
+                    self.broadcast(&Message::NewBlock { block: tip }, Some(id));
+                }
+            }
+            Message::NewBlock { block } => {
+                let mut blockchain = self.chain.lock().unwrap();
+                let outcome = blockchain.submit_block(block.clone());
+                self.persist(&mut blockchain);
+                match outcome {
+                    Ok(SubmitOutcome::ExtendedTip) | Ok(SubmitOutcome::Reorg { .. }) => {
+                        drop(blockchain);
+                        self.broadcast(&Message::NewBlock { block }, Some(id));
+                    }
+                    Ok(SubmitOutcome::SideBranch) | Err(BlockError::AlreadyKnown) | Err(BlockError::StaleFork) => {}
+                    // We are missing blocks in between, fetch them from the sender
+                    Err(BlockError::UnknownParent) => {
+                        let locator = Node::locator(&blockchain);
+                        drop(blockchain);
+                        self.send(id, &Message::GetBlocks { locator });
+                    }
+                    Err(BlockError::Invalid(_)) => return false,
+                }
+            }
+            Message::NewTransaction { transaction } => {
+                // Transactions we already have or that no longer apply are
+                // dropped here, which also stops the gossip from looping
+                let _ = self.accept_transaction(transaction, Some(id));
+            }
+        }
+        true
+    }
+
+    // Add a transaction to the chain's mempool and pass it on to every peer
+    // except the one it came from. Adding it may fill the mempool enough to
+    // mine a block, which is announced as well.
+    fn accept_transaction(&self, transaction: Transaction, from: Option<u64>) -> Result<(), TransactionError> {
+        let mut blockchain = self.chain.lock().unwrap();
+        let old_tip = blockchain.get_latest_block().hash.clone();
+        blockchain.add_transaction(transaction.clone())?;
+        self.persist(&mut blockchain);
+        let tip = blockchain.get_latest_block().clone();
+        drop(blockchain);
+
+        self.broadcast(&Message::NewTransaction { transaction }, from);
+        if tip.hash != old_tip {
+            self.broadcast(&Message::NewBlock { block: tip }, None);
+        }
+        Ok(())
+    }
+
+    fn send(&self, id: u64, message: &Message) {
+        let line = encode_message(message);
+        let mut peers = self.peers.lock().unwrap();
+        if let Some(peer) = peers.get(&id) {
+            if !peer.queue(&line) {
+                let _ = peer.stream.shutdown(Shutdown::Both);
+                peers.remove(&id);
+            }
+        }
+    }
+
+    fn broadcast(&self, message: &Message, except: Option<u64>) {
+        let line = encode_message(message);
+        let mut peers = self.peers.lock().unwrap();
+        peers.retain(|id, peer| {
+            let keep = Some(*id) == except || peer.queue(&line);
+            if !keep {
+                let _ = peer.stream.shutdown(Shutdown::Both);
+            }
+            keep
+        });
+    }
+}
+
+impl Peer {
+    // Queue a message without waiting. False if the peer's queue is full or
+    // its writer stopped, either way the peer is gone.
+    fn queue(&self, line: &Arc<Vec<u8>>) -> bool {
+        self.outbox.try_send(line.clone()).is_ok()
+    }
+}
+
+// Read the next message line, of at most MAX_MESSAGE_BYTES. None when the
+// peer closed the connection, sent a longer line, or timed out before its
+// handshake or in the middle of a message.
+fn read_line(reader: &mut BufReader<TcpStream>, handshake_done: bool) -> Option<Vec<u8>> {
+    let mut line = Vec::new();
+    loop {
+        let limit = (MAX_MESSAGE_BYTES + 1 - line.len()) as u64;
+        match reader.by_ref().take(limit).read_until(b'\n', &mut line) {
+            Ok(_) if line.ends_with(b"\n") => return Some(line),
+            // The connection closed, or the line hit the limit
+            Ok(_) => return None,
+            Err(e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => {
+                if !handshake_done || !line.is_empty() {
+                    return None;
+                }
+            }
+            Err(_) => return None,
+        }
+    }
+}
+
+fn encode_message(message: &Message) -> Arc<Vec<u8>> {
+    let mut line = serde_json::to_vec(message).expect("messages always serialize");
+    line.push(b'\n');
+    Arc::new(line)
+}
+
+// Write the queued messages of a peer until the queue closes or a write
+// fails or times out.
+fn write_messages(mut stream: TcpStream, queue: Receiver<Arc<Vec<u8>>>) {
+    for line in queue {
+        if stream.write_all(&line).is_err() {
+            return;
+        }
+    }
+}
+
+fn block_hash(block: &Block) -> String {
+    block.hash.as_deref().map(to_hex_string).unwrap_or_default()
+}
@@ -0,0 +1,163 @@
+use crate::block::Block;
+use crate::storage::{decode_block, encode_block, BlockStore, StoreError};
+use redb::{Database, ReadableDatabase, ReadableTable, ReadableTableMetadata, TableDefinition};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// File of a RedbStore data directory.
+pub const DATABASE_FILE: &str = "chain.redb";
+
+// Encoded blocks by hash
+const BLOCKS: TableDefinition<&[u8], &[u8]> = TableDefinition::new("blocks");
+// Block hashes by the order they were put in, for iterate
+const ORDER: TableDefinition<u64, &[u8]> = TableDefinition::new("order");
+// Block hashes of the best chain by height
+const BEST_CHAIN: TableDefinition<u32, &[u8]> = TableDefinition::new("best_chain");
+const METADATA: TableDefinition<&str, &[u8]> = TableDefinition::new("metadata");
+
+// A BlockStore in a redb embedded key-value database. Every write is its own
+// transaction, committed durably before the call returns, so a crash never
+// leaves a half written block or best chain behind.
+#[derive(Debug)]
+pub struct RedbStore {
+    database: Database,
+    dir: PathBuf,
+}
+
+impl RedbStore {
+    // Start a new, empty database in the data directory `dir`, replacing any
+    // there.
+    pub fn create(dir: &Path) -> Result<RedbStore, StoreError> {
+        fs::create_dir_all(dir)?;
+        let path = dir.join(DATABASE_FILE);
+        if path.exists() {
+            fs::remove_file(&path)?;
+        }
+        Self::open(dir)
+    }
+
+    // Open the database in the data directory `dir`, creating it if missing.
+    pub fn open(dir: &Path) -> Result<RedbStore, StoreError> {
+        let database = Database::create(dir.join(DATABASE_FILE))?;
+
+        // Create the tables up front so reads never find one missing
+        let transaction = database.begin_write()?;
+        transaction.open_table(BLOCKS)?;
+        transaction.open_table(ORDER)?;
+        transaction.open_table(BEST_CHAIN)?;
+        transaction.open_table(METADATA)?;
+        transaction.commit()?;
+
+        Ok(RedbStore {
+            database,
+            dir: dir.to_path_buf(),
+        })
+    }
+
+    fn get_by_order(&self, index: u64) -> Result<Block, StoreError> {
+        let transaction = self.database.begin_read()?;
+        let hash = transaction
+            .open_table(ORDER)?
+            .get(index)?
+            .map(|hash| hash.value().to_vec())
+            .unwrap_or_default();
+        self.get_by_hash(&hash)?.ok_or(StoreError::MissingBlock(hash))
+    }
+
+    fn len(&self) -> Result<u64, StoreError> {
+        let transaction = self.database.begin_read()?;
+        let len = transaction.open_table(ORDER)?.len()?;
+        Ok(len)
+    }
+}
+
+impl BlockStore for RedbStore {
+    fn put_block(&mut self, block: &Block) -> Result<(), StoreError> {
+        let hash = block.hash.as_deref().unwrap_or_default();
+        let transaction = self.database.begin_write()?;
+        {
+            let mut blocks = transaction.open_table(BLOCKS)?;
+            if blocks.get(hash)?.is_some() {
+                return Ok(());
+            }
+            blocks.insert(hash, encode_block(block).as_slice())?;
+            let mut order = transaction.open_table(ORDER)?;
+            let next = order.len()?;
+            order.insert(next, hash)?;
+        }
+        transaction.commit()?;
+        Ok(())
+    }
+
+    fn get_by_hash(&self, hash: &[u8]) -> Result<Option<Block>, StoreError> {
+        let transaction = self.database.begin_read()?;
+        let blocks = transaction.open_table(BLOCKS)?;
+        let block = blocks.get(hash)?;
+        block.map(|bytes| decode_block(bytes.value())).transpose()
+    }
+
+    fn get_by_height(&self, height: u32) -> Result<Option<Block>, StoreError> {
+        let transaction = self.database.begin_read()?;
+        let hash = transaction
+            .open_table(BEST_CHAIN)?
+            .get(height)?
+            .map(|hash| hash.value().to_vec());
+        match hash {
+            Some(hash) => self.get_by_hash(&hash),
+            None => Ok(None),
+        }
+    }
+
+    fn tip(&self) -> Result<Option<Block>, StoreError> {
+        let transaction = self.database.begin_read()?;
+        let hash = transaction
+            .open_table(BEST_CHAIN)?
+            .last()?
+            .map(|(_, hash)| hash.value().to_vec());
+        match hash {
+            Some(hash) => self.get_by_hash(&hash),
+            None => Ok(None),
+        }
+    }
+
+    fn set_best_chain(&mut self, height: u32, hashes: &[Vec<u8>]) -> Result<(), StoreError> {
+        let transaction = self.database.begin_write()?;
+        {
+            let blocks = transaction.open_table(BLOCKS)?;
+            if let Some(missing) = hashes.iter().find(|hash| !matches!(blocks.get(hash.as_slice()), Ok(Some(_)))) {
+                return Err(StoreError::MissingBlock(missing.clone()));
+            }
+            let mut best_chain = transaction.open_table(BEST_CHAIN)?;
+            best_chain.retain(|block_height, _| block_height < height)?;
+            for (block_height, hash) in (height..).zip(hashes) {
+                best_chain.insert(block_height, hash.as_slice())?;
+            }
+        }
+        transaction.commit()?;
+        Ok(())
+    }
+
+    fn iterate(&self) -> Box<dyn Iterator<Item = Result<Block, StoreError>> + '_> {
+        match self.len() {
+            Ok(len) => Box::new((0..len).map(|index| self.get_by_order(index))),
+            Err(error) => Box::new(std::iter::once(Err(error))),
+        }
+    }
+
+    fn save_metadata(&mut self, metadata: &[u8]) -> Result<(), StoreError> {
+        let transaction = self.database.begin_write()?;
+        transaction.open_table(METADATA)?.insert("chain", metadata)?;
+        transaction.commit()?;
+        Ok(())
+    }
+
+    fn load_metadata(&self) -> Result<Option<Vec<u8>>, StoreError> {
+        let transaction = self.database.begin_read()?;
+        let metadata = transaction.open_table(METADATA)?.get("chain")?.map(|metadata| metadata.value().to_vec());
+        Ok(metadata)
+    }
+
+    fn path(&self) -> Option<&Path> {
+        Some(&self.dir)
+    }
+}
@@ -0,0 +1,427 @@
+use crate::block::Block;
+use crate::p2p::{Node, SharedChain};
+use crate::transaction::SerializableTransaction;
+use crate::utils::{from_hex_string, to_hex_string};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::io::{self, Read};
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::sync::Arc;
+use std::thread;
+use tiny_http::{Header, Method, Response, Server};
+
+// JSON-RPC 2.0 error codes, plus one for transactions the chain rejects.
+pub const PARSE_ERROR: i64 = -32700;
+pub const INVALID_REQUEST: i64 = -32600;
+pub const METHOD_NOT_FOUND: i64 = -32601;
+pub const INVALID_PARAMS: i64 = -32602;
+pub const INTERNAL_ERROR: i64 = -32603;
+pub const TRANSACTION_REJECTED: i64 = -32000;
+
+// Largest request body the server reads, far more than any request needs.
+// Longer bodies get 413 Payload Too Large.
+pub const MAX_BODY_BYTES: usize = 1024 * 1024;
+
+#[derive(Debug)]
+pub struct RpcError {
+    pub code: i64,
+    pub message: String,
+}
+
+impl RpcError {
+    fn new(code: i64, message: impl Into<String>) -> Self {
+        RpcError {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct Request {
+    method: String,
+    #[serde(default)]
+    params: Value,
+    #[serde(default)]
+    id: Value,
+}
+
+// Answers JSON-RPC requests against a blockchain. Blocks and transactions
+// are returned in their SerializableBlock and SerializableTransaction views,
+// and sendTransaction takes the latter, so every hash, key and signature, in
+// and out, is hex encoded.
+// Parameters can be given by name or by position, e.g. {"height": 3} or [3].
+//
+// Methods: getBlockByHeight(height), getBlockByHash(hash),
+// getTransaction(hash), getMerkleProof(hash), getBalance(address),
+// sendTransaction(transaction), getMempool(), getChainInfo()
+#[derive(Clone)]
+pub struct RpcHandler {
+    chain: SharedChain,
+    // Transactions sent through a node are gossiped to its peers as well
+    node: Option<Node>,
+}
+
+impl RpcHandler {
+    pub fn new(chain: SharedChain) -> Self {
+        RpcHandler { chain, node: None }
+    }
+
+    pub fn for_node(node: Node) -> Self {
+        RpcHandler {
+            chain: node.chain().clone(),
+            node: Some(node),
+        }
+    }
+
+    // Handle one JSON-RPC request body and return the response body.
+    pub fn handle(&self, body: &str) -> String {
+        let (id, result) = match serde_json::from_str::<Value>(body) {
+            Err(e) => (Value::Null, Err(RpcError::new(PARSE_ERROR, e.to_string()))),
+            Ok(value) => match serde_json::from_value::<Request>(value) {
+                Err(e) => (Value::Null, Err(RpcError::new(INVALID_REQUEST, e.to_string()))),
+                Ok(request) => (request.id, self.call(&request.method, &request.params)),
+            },
+        };
+
+        let response = match result {
+            Ok(result) => json!({ "jsonrpc": "2.0", "result": result, "id": id }),
+            Err(error) => json!({
+                "jsonrpc": "2.0",
+                "error": { "code": error.code, "message": error.message },
+                "id": id,
+            }),
+        };
+        response.to_string()
+    }
+
+    pub fn call(&self, method: &str, params: &Value) -> Result<Value, RpcError> {
+        match method {
+            "getBlockByHeight" => {
+                let height = param(params, "height", 0)?
+                    .as_u64()
+                    .ok_or_else(|| RpcError::new(INVALID_PARAMS, "height must be a number"))?;
+                let blockchain = self.chain.lock().unwrap();
+                Ok(u32::try_from(height)
+                    .ok()
+                    .and_then(|height| blockchain.get_block_by_id(height))
+                    .map_or(Value::Null, block_json))
+            }
+            "getBlockByHash" => {
+                let hash = hash_param(params)?;
+                let blockchain = self.chain.lock().unwrap();
+                Ok(blockchain.get_block_by_hash(&hash).map_or(Value::Null, block_json))
+            }
+            "getTransaction" => {
+                let hash = hash_param(params)?;
+                let blockchain = self.chain.lock().unwrap();
+                if let Some((block, transaction)) = blockchain.find_transaction(&hash) {
+                    return Ok(json!({
+                        "hash": to_hex_string(&transaction.hash),
+                        "block_height": block.id,
+                        "block_hash": block.hash.as_deref().map(to_hex_string),
+                        "transaction": transaction.to_serializable(),
+                    }));
+                }
+                Ok(blockchain.mempool().get(&hash).map_or(Value::Null, |transaction| {
+                    json!({
+                        "hash": to_hex_string(&transaction.hash),
+                        "block_height": null,
+                        "block_hash": null,
+                        "transaction": transaction.to_serializable(),
+                    })
+                }))
+            }
+            "getMerkleProof" => {
+                let hash = hash_param(params)?;
+                let blockchain = self.chain.lock().unwrap();
+                Ok(blockchain.prove_transaction(&hash).map_or(Value::Null, |proof| json!(proof)))
+            }
+            "getBalance" => {
+                let address = param(params, "address", 0)?
+                    .as_str()
+                    .ok_or_else(|| RpcError::new(INVALID_PARAMS, "address must be a string"))?;
+                let blockchain = self.chain.lock().unwrap();
+                Ok(json!({
+                    "address": address,
+                    "balance": blockchain.balance_of(address),
+                    "next_nonce": blockchain.next_nonce(address),
+                }))
+            }
+            "sendTransaction" => {
+                let transaction = serde_json::from_value::<SerializableTransaction>(param(params, "transaction", 0)?.clone())
+                    .map_err(|e| RpcError::new(INVALID_PARAMS, e.to_string()))?
+                    .to_transaction()
+                    .ok_or_else(|| RpcError::new(INVALID_PARAMS, "transaction hashes, keys and signatures must be hex strings"))?;
+                let hash = to_hex_string(&transaction.hash);
+                let result = match &self.node {
+                    Some(node) => node.submit_transaction(transaction),
+                    None => self.chain.lock().unwrap().add_transaction(transaction),
+                };
+                result.map_err(|e| RpcError::new(TRANSACTION_REJECTED, e.to_string()))?;
+                self.chain
+                    .lock()
+                    .unwrap()
+                    .persist()
+                    .map_err(|e| RpcError::new(INTERNAL_ERROR, format!("failed to save the chain: {}", e)))?;
+                Ok(json!({ "hash": hash }))
+            }
+            "getMempool" => {
+                let blockchain = self.chain.lock().unwrap();
+                let mempool = blockchain.mempool();
+                let transactions = mempool
+                    .iter()
+                    .map(|transaction| {
+                        json!({
+                            "hash": to_hex_string(&transaction.hash),
+                            "size": transaction.size(),
+                            "transaction": transaction.to_serializable(),
+                        })
+                    })
+                    .collect::<Vec<Value>>();
+                Ok(json!({
+                    "count": mempool.len(),
+                    "bytes": mempool.total_bytes(),
+                    "transactions": transactions,
+                }))
+            }
+            "getChainInfo" => {
+                let blockchain = self.chain.lock().unwrap();
+                let tip = blockchain.get_latest_block();
+                let height = blockchain.get_block_height();
+                Ok(json!({
+                    "chain_id": blockchain.chain_id(),
+                    "height": height,
+                    "tip": tip.hash.as_deref().map(to_hex_string),
+                    "genesis": blockchain.get_chain()[0].hash.as_deref().map(to_hex_string),
+                    "chain_work": blockchain.chain_work().to_string(),
+                    "next_difficulty": blockchain.expected_difficulty(height + 1),
+                    "next_block_reward": blockchain.block_reward(height + 1),
+                    "ledger": blockchain.get_config().ledger,
+                    "mempool_size": blockchain.mempool().len(),
+                }))
+            }
+            _ => Err(RpcError::new(METHOD_NOT_FOUND, format!("unknown method {}", method))),
+        }
+    }
+}
+
+// Serves an RpcHandler over HTTP: every POST body is one JSON-RPC request.
+pub struct RpcServer {
+    local_addr: SocketAddr,
+    server: Arc<Server>,
+}
+
+impl RpcServer {
+    // Listen on `addr`, which may use port 0 to pick a free port.
+    pub fn start(handler: RpcHandler, addr: impl ToSocketAddrs) -> io::Result<RpcServer> {
+        let server = Arc::new(Server::http(addr).map_err(io::Error::other)?);
+        let local_addr = server
+            .server_addr()
+            .to_ip()
+            .ok_or_else(|| io::Error::other("RPC server is not listening on an IP address"))?;
+
+        let serving = server.clone();
+        thread::spawn(move || {
+            for mut request in serving.incoming_requests() {
+                if *request.method() != Method::Post {
+                    let _ = request.respond(Response::empty(405));
+                    continue;
+                }
+                let mut body = String::new();
+                let limit = MAX_BODY_BYTES as u64 + 1;
+                if request.as_reader().take(limit).read_to_string(&mut body).is_err() {
+                    let _ = request.respond(Response::empty(400));
+                    continue;
+                }
+                if body.len() > MAX_BODY_BYTES {
+                    let _ = request.respond(Response::empty(413));
+                    continue;
+                }
+                let content_type = Header::from_bytes("Content-Type", "application/json").unwrap();
+                let response = Response::from_string(handler.handle(&body)).with_header(content_type);
+                let _ = request.respond(response);
+            }
+        });
+
+        Ok(RpcServer { local_addr, server })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    pub fn shutdown(&self) {
+        self.server.unblock();
+    }
+}
+
+fn block_json(block: &Block) -> Value {
+    json!(block.to_serializable())
+}
+
+// A parameter by name from an object, or by position from an array.
+fn param<'a>(params: &'a Value, name: &str, index: usize) -> Result<&'a Value, RpcError> {
+    let value = match params {
+        Value::Object(map) => map.get(name),
+        Value::Array(values) => values.get(index),
+        _ => None,
+    };
+    value.ok_or_else(|| RpcError::new(INVALID_PARAMS, format!("missing parameter {}", name)))
+}
+
+fn hash_param(params: &Value) -> Result<Vec<u8>, RpcError> {
+    param(params, "hash", 0)?
+        .as_str()
+        .and_then(from_hex_string)
+        .ok_or_else(|| RpcError::new(INVALID_PARAMS, "hash must be a hex string"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::Blockchain;
+    use crate::config::ChainConfig;
+    use crate::genesis::GenesisSpec;
+    use crate::wallet::Wallet;
+    use std::sync::Mutex;
+
+    // A handler on a chain where alice holds 100 coins
+    fn handler(alice: &Wallet) -> RpcHandler {
+        let spec = GenesisSpec {
+            allocations: [(alice.address(), 100)].into_iter().collect(),
+            consensus: ChainConfig {
+                initial_difficulty: 4,
+                ..ChainConfig::default()
+            },
+            ..GenesisSpec::default()
+        };
+        RpcHandler::new(Arc::new(Mutex::new(Blockchain::from_genesis(&spec))))
+    }
+
+    fn is_hex(value: &Value) -> bool {
+        value.as_str().is_some_and(|hex| from_hex_string(hex).is_some())
+    }
+
+    fn assert_hex_transaction(transaction: &Value) {
+        for field in ["hash", "public_key", "signature"] {
+            assert!(is_hex(&transaction[field]), "{} is not hex: {}", field, transaction[field]);
+        }
+    }
+
+    #[test]
+    fn sent_transactions_are_hex_everywhere() {
+        let alice = Wallet::generate();
+        let handler = handler(&alice);
+        let transaction = alice.create_transaction("bob", 10, 1, 0);
+        let hash = to_hex_string(&transaction.hash);
+
+        let sent = handler
+            .call("sendTransaction", &json!([transaction.to_serializable()]))
+            .unwrap();
+        assert_eq!(sent["hash"], hash);
+
+        let mempool = handler.call("getMempool", &json!([])).unwrap();
+        assert_eq!(mempool["count"], 1);
+        assert_hex_transaction(&mempool["transactions"][0]["transaction"]);
+
+        let pending = handler.call("getTransaction", &json!({ "hash": hash })).unwrap();
+        assert_eq!(pending["block_height"], Value::Null);
+        assert_hex_transaction(&pending["transaction"]);
+
+        handler.chain.lock().unwrap().mine_block("miner");
+        let mined = handler.call("getTransaction", &json!([hash])).unwrap();
+        assert_eq!(mined["block_height"], 1);
+        assert_hex_transaction(&mined["transaction"]);
+
+        let block = handler.call("getBlockByHeight", &json!({ "height": 1 })).unwrap();
+        assert!(is_hex(&block["hash"]));
+        assert_eq!(block["transactions"][1]["hash"], hash);
+        assert_hex_transaction(&block["transactions"][1]);
+        let by_hash = handler.call("getBlockByHash", &json!([block["hash"]])).unwrap();
+        assert_eq!(by_hash["id"], 1);
+    }
+
+    #[test]
+    fn balances_and_chain_info_follow_the_chain() {
+        let alice = Wallet::generate();
+        let handler = handler(&alice);
+        let balance = handler.call("getBalance", &json!([alice.address()])).unwrap();
+        assert_eq!(balance["balance"], 100);
+        assert_eq!(balance["next_nonce"], 0);
+
+        let info = handler.call("getChainInfo", &Value::Null).unwrap();
+        assert_eq!(info["height"], 0);
+        assert_eq!(info["tip"], info["genesis"]);
+        assert_eq!(handler.call("getBlockByHeight", &json!([5])).unwrap(), Value::Null);
+    }
+
+    #[test]
+    fn rejected_transactions_and_bad_params_are_errors() {
+        let alice = Wallet::generate();
+        let handler = handler(&alice);
+
+        let overdraft = alice.create_transaction("bob", 1000, 1, 0);
+        let error = handler
+            .call("sendTransaction", &json!([overdraft.to_serializable()]))
+            .unwrap_err();
+        assert_eq!(error.code, TRANSACTION_REJECTED);
+
+        let mut transaction = json!(alice.create_transaction("bob", 10, 1, 0).to_serializable());
+        transaction["signature"] = json!("not hex");
+        let error = handler.call("sendTransaction", &json!([transaction])).unwrap_err();
+        assert_eq!(error.code, INVALID_PARAMS);
+
+        assert_eq!(handler.call("getTransaction", &json!(["xyz"])).unwrap_err().code, INVALID_PARAMS);
+        assert_eq!(handler.call("getBalance", &json!({})).unwrap_err().code, INVALID_PARAMS);
+        assert_eq!(handler.call("mineBlock", &json!([])).unwrap_err().code, METHOD_NOT_FOUND);
+    }
+
+    #[test]
+    fn responses_follow_json_rpc() {
+        let alice = Wallet::generate();
+        let handler = handler(&alice);
+
+        let response: Value = serde_json::from_str(&handler.handle(
+            r#"{"jsonrpc":"2.0","method":"getChainInfo","id":7}"#,
+        ))
+        .unwrap();
+        assert_eq!(response["id"], 7);
+        assert_eq!(response["result"]["height"], 0);
+
+        let response: Value = serde_json::from_str(&handler.handle("{not json")).unwrap();
+        assert_eq!(response["error"]["code"], PARSE_ERROR);
+        let response: Value = serde_json::from_str(&handler.handle(r#"{"id":1}"#)).unwrap();
+        assert_eq!(response["error"]["code"], INVALID_REQUEST);
+    }
+
+    // POST a body to the server and return the status line of the response
+    fn post(addr: SocketAddr, body: &[u8]) -> String {
+        use std::io::{BufRead, BufReader, Write};
+        let mut stream = std::net::TcpStream::connect(addr).unwrap();
+        let head = format!(
+            "POST / HTTP/1.1\r\nHost: localhost\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            body.len()
+        );
+        stream.write_all(head.as_bytes()).unwrap();
+        stream.write_all(body).unwrap();
+        let mut status = String::new();
+        BufReader::new(stream).read_line(&mut status).unwrap();
+        status
+    }
+
+    #[test]
+    fn bodies_over_the_limit_are_refused() {
+        let alice = Wallet::generate();
+        let server = RpcServer::start(handler(&alice), "127.0.0.1:0").unwrap();
+        let request = br#"{"jsonrpc":"2.0","method":"getChainInfo","id":1}"#;
+        assert!(post(server.local_addr(), request).contains(" 200 "));
+
+        let mut padded = request.to_vec();
+        padded.resize(MAX_BODY_BYTES, b' ');
+        assert!(post(server.local_addr(), &padded).contains(" 200 "));
+        padded.push(b' ');
+        assert!(post(server.local_addr(), &padded).contains(" 413 "));
+        server.shutdown();
+    }
+}
@@ -0,0 +1,302 @@
+use crate::cli::{self, CommandResult, Context};
+use rust_chain::block::Block;
+use rust_chain::blockchain::Blockchain;
+use rust_chain::storage::PersistencePolicy;
+use rust_chain::transaction::Transaction;
+use rust_chain::utils::{from_hex_string, to_hex_string};
+use rust_chain::wallet::Keystore;
+use rustyline::completion::{Completer, Pair};
+use rustyline::error::ReadlineError;
+use rustyline::history::DefaultHistory;
+use rustyline::{Editor, Helper, Highlighter, Hinter, Validator};
+use serde_json::Value;
+use std::error::Error;
+use std::process::ExitCode;
+
+const COMMANDS: &[&str] = &[
+    "help", "tx", "mine", "block", "chain", "mempool", "balance", "wallets", "proof", "tamper", "validate",
+    "exit",
+];
+
+// Block header fields, then transaction fields, that `tamper` can change
+const BLOCK_FIELDS: &[&str] = &["timestamp", "nonce", "difficulty", "previous_hash", "merkle_root", "hash"];
+const TRANSACTION_FIELDS: &[&str] = &["amount", "fee", "nonce", "sender", "receiver"];
+
+const HELP: &str = "\
+Commands:
+  tx <from> <to> <amount> [fee]          send coins between wallets
+  mine [miner]                           mine a block, paying the given wallet or the chain's miner
+  block <id|hash>                        show a block
+  chain                                  list the blocks
+  mempool                                list the pending transactions
+  balance <wallet|address>               show a balance
+  wallets                                list the wallets in the keystore
+  proof <tx hash>                        build a Merkle proof and check it against its block
+  tamper <block> <field> <value> [tx]    change a block in memory, see below
+  validate                               validate the chain and report the first broken block
+  exit                                   leave the shell
+
+tamper changes a header field of a block (timestamp, nonce, difficulty,
+previous_hash, merkle_root, hash) or a field of one of its transactions
+(amount, fee, nonce, sender, receiver). Transaction fields change the
+transaction at index [tx], by default the first one after the coinbase.
+Once the chain is tampered with it is no longer saved.";
+
+// Tab completion of command names, block ids, transaction hashes and wallet
+// names, refreshed from the chain before every prompt.
+#[derive(Helper, Hinter, Highlighter, Validator, Default)]
+struct ShellHelper {
+    block_ids: Vec<String>,
+    transaction_hashes: Vec<String>,
+    wallets: Vec<String>,
+}
+
+impl ShellHelper {
+    fn refresh(&mut self, blockchain: &Blockchain, keystore: &Keystore) {
+        let chain = blockchain.get_chain();
+        self.block_ids = chain.iter().map(|block| block.id.to_string()).collect();
+        self.transaction_hashes = chain
+            .iter()
+            .flat_map(|block| &block.transactions)
+            .chain(blockchain.mempool().iter())
+            .map(|transaction| to_hex_string(&transaction.hash))
+            .collect();
+        self.wallets = keystore.names().cloned().collect();
+    }
+}
+
+impl Completer for ShellHelper {
+    type Candidate = Pair;
+
+    fn complete(&self, line: &str, pos: usize, _ctx: &rustyline::Context<'_>) -> rustyline::Result<(usize, Vec<Pair>)> {
+        let before = &line[..pos];
+        let start = before.rfind(' ').map_or(0, |i| i + 1);
+        let word = &before[start..];
+        let previous = before[..start].split_whitespace().collect::<Vec<_>>();
+
+        let options = match (previous.first().copied(), previous.len()) {
+            (None, _) => COMMANDS.to_vec(),
+            (Some("block"), 1) | (Some("tamper"), 1) => as_strs(&self.block_ids),
+            (Some("tamper"), 2) => [BLOCK_FIELDS, TRANSACTION_FIELDS].concat(),
+            (Some("proof"), 1) => as_strs(&self.transaction_hashes),
+            (Some("tx"), 1 | 2) | (Some("balance"), 1) | (Some("mine"), 1) => as_strs(&self.wallets),
+            _ => Vec::new(),
+        };
+
+        let candidates = options
+            .into_iter()
+            .filter(|option| option.starts_with(word))
+            .map(|option| Pair {
+                display: option.to_string(),
+                replacement: option.to_string(),
+            })
+            .collect();
+        Ok((start, candidates))
+    }
+}
+
+// A line editing REPL over the chain in the data directory. New blocks are
+// saved as they are mined and the rest when the shell exits, unless the
+// chain was tampered with.
+pub fn run(ctx: &Context) -> CommandResult {
+    let mut blockchain = ctx.load_chain()?;
+    blockchain.set_persistence_policy(PersistencePolicy::OnEveryBlock);
+    let keystore = ctx.load_keystore()?;
+    let mut editor: Editor<ShellHelper, DefaultHistory> = Editor::new()?;
+    editor.set_helper(Some(ShellHelper::default()));
+
+    println!(
+        "rust_chain shell on {} ({} blocks). Type help for the commands, tab completes.",
+        ctx.chain_dir(),
+        blockchain.get_chain_length()
+    );
+    loop {
+        if let Some(helper) = editor.helper_mut() {
+            helper.refresh(&blockchain, &keystore);
+        }
+        let line = match editor.readline("rust_chain> ") {
+            Ok(line) => line,
+            Err(ReadlineError::Interrupted) | Err(ReadlineError::Eof) => break,
+            Err(e) => return Err(e.into()),
+        };
+        let _ = editor.add_history_entry(line.as_str());
+
+        let words = line.split_whitespace().collect::<Vec<_>>();
+        match words.first() {
+            None => continue,
+            Some(&"exit") | Some(&"quit") => break,
+            Some(_) => {
+                if let Err(e) = run_command(ctx, &mut blockchain, &keystore, &words) {
+                    println!("error: {}", e);
+                }
+                if let Err(e) = blockchain.persist() {
+                    println!("error: failed to save the chain: {}", e);
+                }
+            }
+        }
+    }
+    blockchain.save()?;
+    Ok(ExitCode::SUCCESS)
+}
+
+fn run_command(
+    ctx: &Context,
+    blockchain: &mut Blockchain,
+    keystore: &Keystore,
+    words: &[&str],
+) -> Result<(), Box<dyn Error>> {
+    let arg = |index: usize, name: &str| -> Result<&str, Box<dyn Error>> {
+        words.get(index).copied().ok_or_else(|| format!("missing {}, see help", name).into())
+    };
+
+    match words[0] {
+        "help" => println!("{}", HELP),
+        "tx" => {
+            let amount = arg(3, "amount")?.parse()?;
+            let fee = words.get(4).map_or(Ok(1), |fee| fee.parse())?;
+            let transaction =
+                cli::build_transaction(ctx, blockchain, keystore, arg(1, "sender")?, arg(2, "receiver")?, amount, fee)?;
+            let hash = to_hex_string(&transaction.hash);
+            let height = blockchain.get_block_height();
+            blockchain.add_transaction(transaction)?;
+            println!("Transaction {} added to the mempool", hash);
+            if blockchain.get_block_height() != height {
+                println!("The mempool was full enough to mine block {}", blockchain.get_block_height());
+            }
+        }
+        "mine" => {
+            let miner_address = match words.get(1) {
+                Some(miner) => cli::resolve_address(keystore, miner),
+                None => blockchain
+                    .miner_address()
+                    .ok_or("the chain has no miner address, use mine <wallet>")?
+                    .to_string(),
+            };
+            let block = blockchain.mine_block(&miner_address).clone();
+            cli::print_block(ctx, &block)?;
+        }
+        "block" => {
+            let block = find_block(blockchain, arg(1, "block id or hash")?)?;
+            cli::print_block(ctx, block)?;
+        }
+        "chain" => {
+            for block in blockchain.get_chain() {
+                println!(
+                    "{:>4}  {}  {} txs  {}",
+                    block.id,
+                    cli::format_time(block.timestamp),
+                    block.transactions.len(),
+                    cli::block_hash(block)
+                );
+            }
+        }
+        "mempool" => {
+            let pending = blockchain.mempool().iter().collect::<Vec<_>>();
+            println!("{} pending transactions", pending.len());
+            cli::print_transactions(&pending);
+        }
+        "balance" => {
+            let address = cli::resolve_address(keystore, arg(1, "wallet or address")?);
+            println!("{}: {}", address, blockchain.balance_of(&address));
+        }
+        "wallets" => {
+            for name in keystore.names() {
+                println!("{:<10} {}", name, keystore.address_of(name).unwrap_or_default());
+            }
+        }
+        "proof" => {
+            let hash = from_hex_string(arg(1, "transaction hash")?).ok_or("the transaction hash must be hex")?;
+            let proof = blockchain
+                .prove_transaction(&hash)
+                .ok_or("the transaction is not in a block on the chain")?;
+            let (merkle_root, merkle_proof) = proof.decode().ok_or("the proof is not valid hex")?;
+            println!(
+                "Block {}, {} hashes from the leaf {} to the root {}",
+                proof.block_height,
+                proof.path.len(),
+                proof.leaf,
+                proof.merkle_root
+            );
+            if merkle_proof.verify(&merkle_root) {
+                println!("The proof verifies against the Merkle root of block {}", proof.block_height);
+            } else {
+                println!("The proof does NOT verify against the Merkle root of block {}", proof.block_height);
+            }
+        }
+        "tamper" => {
+            let id: u32 = arg(1, "block id")?.parse()?;
+            let field = arg(2, "field")?;
+            let value = arg(3, "value")?;
+            let block = blockchain
+                .chain
+                .iter_mut()
+                .find(|block| block.id == id)
+                .ok_or_else(|| format!("block {} not found", id))?;
+            if BLOCK_FIELDS.contains(&field) {
+                tamper_block(block, field, value)?;
+                println!("Block {} now has {} = {}", id, field, value);
+            } else {
+                // By default the first transaction after the coinbase
+                let index = match words.get(4) {
+                    Some(index) => index.parse()?,
+                    None => usize::from(block.transactions.len() > 1),
+                };
+                let transaction = block
+                    .transactions
+                    .get_mut(index)
+                    .ok_or_else(|| format!("block {} has no transaction {}", id, index))?;
+                tamper_transaction(transaction, field, value)?;
+                println!("Transaction {} of block {} now has {} = {}", index, id, field, value);
+            }
+            blockchain.detach_from_file();
+            println!("The chain was changed in memory only and will not be saved. Try validate.");
+        }
+        "validate" => match blockchain.validate_chain() {
+            Ok(()) => println!("Chain of {} blocks is valid", blockchain.get_chain_length()),
+            Err(error) => println!("Chain is invalid, {}", error),
+        },
+        command => return Err(format!("unknown command {}, see help", command).into()),
+    }
+    Ok(())
+}
+
+fn as_strs(values: &[String]) -> Vec<&str> {
+    values.iter().map(String::as_str).collect()
+}
+
+fn find_block<'a>(blockchain: &'a Blockchain, id_or_hash: &str) -> Result<&'a Block, Box<dyn Error>> {
+    let block = match id_or_hash.parse::<u32>() {
+        Ok(id) => blockchain.get_block_by_id(id),
+        Err(_) => from_hex_string(id_or_hash).and_then(|hash| blockchain.get_block_by_hash(&hash)),
+    };
+    block.ok_or_else(|| format!("block {} not found", id_or_hash).into())
+}
+
+fn tamper_block(block: &mut Block, field: &str, value: &str) -> Result<(), Box<dyn Error>> {
+    let hex = || from_hex_string(value).ok_or("the value must be hex");
+    match field {
+        "timestamp" => block.timestamp = value.parse()?,
+        "nonce" => block.nonce = value.parse()?,
+        "difficulty" => block.difficulty = value.parse()?,
+        "previous_hash" => block.previous_hash = Some(hex()?),
+        "merkle_root" => block.merkle_root = Some(hex()?),
+        "hash" => block.hash = Some(hex()?),
+        _ => return Err(format!("unknown block field {}", field).into()),
+    }
+    Ok(())
+}
+
+// Transaction fields are private, so go through its JSON form. The hash is
+// left alone, exactly like someone editing a stored block would.
+fn tamper_transaction(transaction: &mut Transaction, field: &str, value: &str) -> Result<(), Box<dyn Error>> {
+    if !TRANSACTION_FIELDS.contains(&field) {
+        return Err(format!("unknown transaction field {}", field).into());
+    }
+    let mut json = serde_json::to_value(&*transaction)?;
+    json[field] = match json[field] {
+        Value::Number(_) => Value::from(value.parse::<u64>()?),
+        _ => Value::from(value),
+    };
+    *transaction = serde_json::from_value(json)?;
+    Ok(())
+}
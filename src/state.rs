@@ -0,0 +1,218 @@
+use crate::block::Block;
+use crate::config::Ledger;
+use crate::transaction::{Transaction, TransactionError};
+use crate::utxo::UtxoSet;
+use std::collections::HashMap;
+
+// The state a chain's transactions are replayed into, depending on the ledger
+// model it was configured with.
+#[derive(Debug, Clone)]
+pub enum LedgerState {
+    Account(AccountState),
+    Utxo(UtxoSet),
+}
+
+impl Default for LedgerState {
+    fn default() -> Self {
+        LedgerState::new(Ledger::default())
+    }
+}
+
+impl LedgerState {
+    pub fn new(ledger: Ledger) -> Self {
+        match ledger {
+            Ledger::Account => LedgerState::Account(AccountState::default()),
+            Ledger::Utxo => LedgerState::Utxo(UtxoSet::default()),
+        }
+    }
+
+    pub fn from_blocks(ledger: Ledger, blocks: &[Block]) -> Result<Self, TransactionError> {
+        let mut state = LedgerState::new(ledger);
+        for block in blocks {
+            state.apply_block(block)?;
+        }
+        Ok(state)
+    }
+
+    pub fn apply_block(&mut self, block: &Block) -> Result<(), TransactionError> {
+        match self {
+            LedgerState::Account(state) => state.apply_block(block),
+            LedgerState::Utxo(utxos) => utxos.apply_block(block),
+        }
+    }
+
+    pub fn apply_transaction(&mut self, transaction: &Transaction) -> Result<(), TransactionError> {
+        match self {
+            LedgerState::Account(state) => state.apply_transaction(transaction),
+            LedgerState::Utxo(utxos) => utxos.apply_transaction(transaction),
+        }
+    }
+
+    pub fn balance_of(&self, address: &str) -> u64 {
+        match self {
+            LedgerState::Account(state) => state.balance_of(address),
+            LedgerState::Utxo(utxos) => utxos.balance_of(address),
+        }
+    }
+
+    // Nonce the next transaction of `address` must use. UTXO transactions are
+    // made unique by the outputs they spend, so there it is always 0.
+    pub fn next_nonce(&self, address: &str) -> u64 {
+        match self {
+            LedgerState::Account(state) => state.next_nonce(address),
+            LedgerState::Utxo(_) => 0,
+        }
+    }
+}
+
+// Account balances derived by replaying transactions in chain order. The
+// state is never persisted, it is rebuilt from the blocks whenever needed.
+#[derive(Debug, Clone, Default)]
+pub struct AccountState {
+    balances: HashMap<String, u64>,
+    // Number of transactions each address has sent, which is also the nonce
+    // its next transaction must carry
+    nonces: HashMap<String, u64>,
+}
+
+impl AccountState {
+    // Replay every transaction of the given blocks, failing on the first one
+    // that spends more than its sender holds.
+    pub fn from_blocks(blocks: &[Block]) -> Result<Self, TransactionError> {
+        let mut state = AccountState::default();
+        for block in blocks {
+            state.apply_block(block)?;
+        }
+        Ok(state)
+    }
+
+    pub fn apply_block(&mut self, block: &Block) -> Result<(), TransactionError> {
+        for transaction in &block.transactions {
+            self.apply_transaction(transaction)?;
+        }
+        Ok(())
+    }
+
+    // Move `amount` from the sender to the receiver, and take the fee from the
+    // sender too, it goes to the miner through the coinbase. A coinbase has no
+    // sender and only credits the receiver. Every other transaction must carry the next
+    // nonce of its sender, which rules out replays and gaps. On error the state
+    // is unchanged.
+    pub fn apply_transaction(&mut self, transaction: &Transaction) -> Result<(), TransactionError> {
+        if !transaction.inputs().is_empty() || !transaction.outputs().is_empty() {
+            return Err(TransactionError::LedgerMismatch);
+        }
+
+        let amount = transaction.amount();
+        if transaction.is_coinbase() {
+            let credited = self
+                .balance_of(transaction.receiver())
+                .checked_add(amount)
+                .ok_or(TransactionError::AmountOverflow)?;
+            self.balances.insert(transaction.receiver().to_string(), credited);
+            return Ok(());
+        }
+
+        let expected = self.next_nonce(transaction.sender());
+        if transaction.nonce() < expected {
+            return Err(TransactionError::StaleNonce {
+                address: transaction.sender().to_string(),
+                expected,
+                got: transaction.nonce(),
+            });
+        }
+        if transaction.nonce() > expected {
+            return Err(TransactionError::NonceGap {
+                address: transaction.sender().to_string(),
+                expected,
+                got: transaction.nonce(),
+            });
+        }
+
+        let required = amount
+            .checked_add(transaction.fee())
+            .ok_or(TransactionError::AmountOverflow)?;
+        let balance = self.balance_of(transaction.sender());
+        if balance < required {
+            return Err(TransactionError::InsufficientFunds {
+                address: transaction.sender().to_string(),
+                balance,
+                required,
+            });
+        }
+
+        // Worked out before anything changes, so an overflow leaves the state
+        // as it was. A sender paying itself is credited after the debit.
+        let remaining = balance - required;
+        let receiver_balance = if transaction.receiver() == transaction.sender() {
+            remaining
+        } else {
+            self.balance_of(transaction.receiver())
+        };
+        let credited = receiver_balance
+            .checked_add(amount)
+            .ok_or(TransactionError::AmountOverflow)?;
+
+        self.balances
+            .insert(transaction.sender().to_string(), remaining);
+        self.nonces
+            .insert(transaction.sender().to_string(), expected + 1);
+        self.balances
+            .insert(transaction.receiver().to_string(), credited);
+        Ok(())
+    }
+
+    pub fn balance_of(&self, address: &str) -> u64 {
+        self.balances.get(address).copied().unwrap_or(0)
+    }
+
+    pub fn next_nonce(&self, address: &str) -> u64 {
+        self.nonces.get(address).copied().unwrap_or(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A state where the given addresses hold the given balances
+    fn state_with(balances: &[(&str, u64)]) -> AccountState {
+        let mut state = AccountState::default();
+        for (address, amount) in balances {
+            state
+                .apply_transaction(&Transaction::coinbase(address.to_string(), *amount, 0))
+                .unwrap();
+        }
+        state
+    }
+
+    #[test]
+    fn amount_and_fee_past_the_maximum_are_rejected() {
+        let mut state = state_with(&[("alice", u64::MAX)]);
+        let transaction = Transaction::new("alice".to_string(), "bob".to_string(), u64::MAX, 1, 0);
+        assert_eq!(state.apply_transaction(&transaction), Err(TransactionError::AmountOverflow));
+        assert_eq!(state.balance_of("alice"), u64::MAX);
+        assert_eq!(state.next_nonce("alice"), 0);
+    }
+
+    #[test]
+    fn credits_past_the_maximum_are_rejected() {
+        let mut state = state_with(&[("alice", 10), ("bob", u64::MAX)]);
+        let transaction = Transaction::new("alice".to_string(), "bob".to_string(), 5, 1, 0);
+        assert_eq!(state.apply_transaction(&transaction), Err(TransactionError::AmountOverflow));
+        assert_eq!(state.balance_of("alice"), 10);
+        assert_eq!(state.next_nonce("alice"), 0);
+
+        let coinbase = Transaction::coinbase("bob".to_string(), 1, 1);
+        assert_eq!(state.apply_transaction(&coinbase), Err(TransactionError::AmountOverflow));
+        assert_eq!(state.balance_of("bob"), u64::MAX);
+    }
+
+    #[test]
+    fn senders_can_pay_themselves() {
+        let mut state = state_with(&[("alice", u64::MAX)]);
+        let transaction = Transaction::new("alice".to_string(), "alice".to_string(), u64::MAX - 1, 1, 0);
+        state.apply_transaction(&transaction).unwrap();
+        assert_eq!(state.balance_of("alice"), u64::MAX - 1);
+    }
+}
@@ -0,0 +1,438 @@
+use crate::block::Block;
+use crate::codec::{self, CodecError};
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::Path;
+use std::time::Duration;
+
+// Where a Blockchain keeps its blocks and metadata. A store holds every block
+// the chain accepted, side branches included, plus an index of the best chain
+// by height that the Blockchain keeps up to date with set_best_chain.
+//
+// Implementations: MemoryStore keeps everything in memory, for tests and
+// throwaway chains. BlockLog appends to a flat file, see block_log.rs, and
+// RedbStore keeps blocks in an embedded key-value database, see redb_store.rs.
+pub trait BlockStore: fmt::Debug {
+    // Store a block and make sure it is on disk before returning. Blocks come
+    // parents first, and a block that is already stored is left alone.
+    fn put_block(&mut self, block: &Block) -> Result<(), StoreError>;
+
+    fn get_by_hash(&self, hash: &[u8]) -> Result<Option<Block>, StoreError>;
+
+    // The block at `height` on the best chain.
+    fn get_by_height(&self, height: u32) -> Result<Option<Block>, StoreError>;
+
+    // The last block of the best chain, None while the store is empty.
+    fn tip(&self) -> Result<Option<Block>, StoreError>;
+
+    // Make the stored blocks with `hashes` the best chain from `height` on,
+    // and drop the rest of the old best chain above them. Called when the
+    // chain gets a new tip and after a reorg.
+    fn set_best_chain(&mut self, height: u32, hashes: &[Vec<u8>]) -> Result<(), StoreError>;
+
+    // Every block in the order it was put, so parents before their children.
+    fn iterate(&self) -> Box<dyn Iterator<Item = Result<Block, StoreError>> + '_>;
+
+    // Everything about the chain that is not in its blocks, see Blockchain::save.
+    fn save_metadata(&mut self, metadata: &[u8]) -> Result<(), StoreError>;
+
+    fn load_metadata(&self) -> Result<Option<Vec<u8>>, StoreError>;
+
+    // File or directory the store keeps its data in, if it is on disk.
+    fn path(&self) -> Option<&Path> {
+        None
+    }
+}
+
+// When a Blockchain writes what changed to its store by itself, from
+// Blockchain::persist. A chain can always be saved explicitly with save.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PersistencePolicy {
+    // Only when save is called
+    #[default]
+    Manual,
+    // Whenever a block was added or the mempool changed since the last save
+    OnEveryBlock,
+    // Once the interval has passed since the last save, if anything changed
+    Periodic(Duration),
+}
+
+// The store type of a Blockchain that is not given one, any store chosen at
+// runtime.
+pub type DynBlockStore = Box<dyn BlockStore + Send>;
+
+impl<T: BlockStore + ?Sized> BlockStore for Box<T> {
+    fn put_block(&mut self, block: &Block) -> Result<(), StoreError> {
+        (**self).put_block(block)
+    }
+
+    fn get_by_hash(&self, hash: &[u8]) -> Result<Option<Block>, StoreError> {
+        (**self).get_by_hash(hash)
+    }
+
+    fn get_by_height(&self, height: u32) -> Result<Option<Block>, StoreError> {
+        (**self).get_by_height(height)
+    }
+
+    fn tip(&self) -> Result<Option<Block>, StoreError> {
+        (**self).tip()
+    }
+
+    fn set_best_chain(&mut self, height: u32, hashes: &[Vec<u8>]) -> Result<(), StoreError> {
+        (**self).set_best_chain(height, hashes)
+    }
+
+    fn iterate(&self) -> Box<dyn Iterator<Item = Result<Block, StoreError>> + '_> {
+        (**self).iterate()
+    }
+
+    fn save_metadata(&mut self, metadata: &[u8]) -> Result<(), StoreError> {
+        (**self).save_metadata(metadata)
+    }
+
+    fn load_metadata(&self) -> Result<Option<Vec<u8>>, StoreError> {
+        (**self).load_metadata()
+    }
+
+    fn path(&self) -> Option<&Path> {
+        (**self).path()
+    }
+}
+
+#[derive(Debug)]
+pub enum StoreError {
+    Io(io::Error),
+    // A record of the block log does not match its checksum and valid
+    // records follow it. Only the last record can be torn by a crash, this
+    // is damage to the file.
+    Corrupt { offset: u64 },
+    Decode(CodecError),
+    // The chain metadata is not valid JSON
+    Metadata(serde_json::Error),
+    Database(redb::Error),
+    // A block given to set_best_chain that was never put
+    MissingBlock(Vec<u8>),
+    // The store was written before transaction ids were canonical. Its
+    // transaction hashes and Merkle roots came from the serde JSON of the
+    // transactions, so its blocks do not validate any more.
+    FormatTooOld,
+}
+
+impl fmt::Display for StoreError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StoreError::Io(error) => write!(f, "block store: {}", error),
+            StoreError::Corrupt { offset } => {
+                write!(f, "block log record at offset {} does not match its checksum", offset)
+            }
+            StoreError::Decode(error) => write!(f, "stored block can not be decoded: {}", error),
+            StoreError::Metadata(error) => write!(f, "chain metadata: {}", error),
+            StoreError::Database(error) => write!(f, "block database: {}", error),
+            StoreError::MissingBlock(hash) => {
+                write!(f, "block {} is not in the store", crate::utils::to_hex_string(hash))
+            }
+            StoreError::FormatTooOld => write!(
+                f,
+                "the chain was written by an older rust_chain with different transaction ids, start a new one with `init --force`"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for StoreError {}
+
+impl From<io::Error> for StoreError {
+    fn from(error: io::Error) -> Self {
+        StoreError::Io(error)
+    }
+}
+
+impl From<redb::Error> for StoreError {
+    fn from(error: redb::Error) -> Self {
+        StoreError::Database(error)
+    }
+}
+
+impl From<redb::DatabaseError> for StoreError {
+    fn from(error: redb::DatabaseError) -> Self {
+        StoreError::Database(error.into())
+    }
+}
+
+impl From<redb::TransactionError> for StoreError {
+    fn from(error: redb::TransactionError) -> Self {
+        StoreError::Database(error.into())
+    }
+}
+
+impl From<redb::TableError> for StoreError {
+    fn from(error: redb::TableError) -> Self {
+        StoreError::Database(error.into())
+    }
+}
+
+impl From<redb::StorageError> for StoreError {
+    fn from(error: redb::StorageError) -> Self {
+        StoreError::Database(error.into())
+    }
+}
+
+impl From<redb::CommitError> for StoreError {
+    fn from(error: redb::CommitError) -> Self {
+        StoreError::Database(error.into())
+    }
+}
+
+// Replace the file at `path` so that it holds either the old or the new
+// contents even after a crash: write a temporary file next to it, sync it,
+// rename it over the old one and sync the directory holding both.
+pub fn write_atomically(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let mut temporary = path.as_os_str().to_owned();
+    temporary.push(".tmp");
+    let temporary = Path::new(&temporary);
+
+    let mut file = File::create(temporary)?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+    drop(file);
+    fs::rename(temporary, path)?;
+
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    File::open(dir)?.sync_all()
+}
+
+// Blocks are stored in the binary format of codec.rs.
+pub(crate) fn encode_block(block: &Block) -> Vec<u8> {
+    codec::encode(block)
+}
+
+// Stores written before the binary format hold JSON blocks. Those predate
+// canonical transaction ids as well, so they are reported as too old.
+pub(crate) fn decode_block(bytes: &[u8]) -> Result<Block, StoreError> {
+    if !codec::is_binary(bytes) && serde_json::from_slice::<Block>(bytes).is_ok() {
+        return Err(StoreError::FormatTooOld);
+    }
+    codec::decode(bytes).map_err(StoreError::Decode)
+}
+
+// Blocks whose transaction hashes are not their ids were written before ids
+// were canonical. Replaying them would fail on the first block with a
+// validation error that does not say why.
+pub(crate) fn check_transaction_ids(block: Block) -> Result<Block, StoreError> {
+    if block.transactions.iter().any(|transaction| transaction.verify_id().is_err()) {
+        return Err(StoreError::FormatTooOld);
+    }
+    Ok(block)
+}
+
+// Keeps everything in memory and loses it when dropped.
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    // In the order they were put
+    blocks: Vec<Block>,
+    by_hash: HashMap<Vec<u8>, usize>,
+    // Hashes of the best chain by height
+    best_chain: Vec<Vec<u8>>,
+    metadata: Option<Vec<u8>>,
+}
+
+impl BlockStore for MemoryStore {
+    fn put_block(&mut self, block: &Block) -> Result<(), StoreError> {
+        let hash = block.hash.clone().unwrap_or_default();
+        if !self.by_hash.contains_key(&hash) {
+            self.by_hash.insert(hash, self.blocks.len());
+            self.blocks.push(block.clone());
+        }
+        Ok(())
+    }
+
+    fn get_by_hash(&self, hash: &[u8]) -> Result<Option<Block>, StoreError> {
+        Ok(self.by_hash.get(hash).map(|&index| self.blocks[index].clone()))
+    }
+
+    fn get_by_height(&self, height: u32) -> Result<Option<Block>, StoreError> {
+        match self.best_chain.get(height as usize) {
+            Some(hash) => self.get_by_hash(hash),
+            None => Ok(None),
+        }
+    }
+
+    fn tip(&self) -> Result<Option<Block>, StoreError> {
+        match self.best_chain.last() {
+            Some(hash) => self.get_by_hash(hash),
+            None => Ok(None),
+        }
+    }
+
+    fn set_best_chain(&mut self, height: u32, hashes: &[Vec<u8>]) -> Result<(), StoreError> {
+        if let Some(missing) = hashes.iter().find(|hash| !self.by_hash.contains_key(*hash)) {
+            return Err(StoreError::MissingBlock(missing.clone()));
+        }
+        self.best_chain.truncate(height as usize);
+        self.best_chain.extend(hashes.iter().cloned());
+        Ok(())
+    }
+
+    fn iterate(&self) -> Box<dyn Iterator<Item = Result<Block, StoreError>> + '_> {
+        Box::new(self.blocks.iter().cloned().map(Ok))
+    }
+
+    fn save_metadata(&mut self, metadata: &[u8]) -> Result<(), StoreError> {
+        self.metadata = Some(metadata.to_vec());
+        Ok(())
+    }
+
+    fn load_metadata(&self) -> Result<Option<Vec<u8>>, StoreError> {
+        Ok(self.metadata.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block_log::BlockLog;
+    use crate::blockchain::{Blockchain, SubmitOutcome};
+    use crate::config::ChainConfig;
+    use crate::genesis::GenesisSpec;
+    use crate::redb_store::RedbStore;
+    use crate::test_utils::{mined_block, TempDir};
+    use crate::transaction::Transaction;
+
+    const NOW: i64 = 1_700_000_000;
+
+    // `count` blocks on top of `parent`, paying `miner`
+    fn branch(parent: &Block, count: usize, miner: &str) -> Vec<Block> {
+        let mut blocks: Vec<Block> = Vec::new();
+        for _ in 0..count {
+            let parent = blocks.last().unwrap_or(parent);
+            let coinbase = Transaction::coinbase(miner.to_string(), 50, parent.id + 1);
+            blocks.push(mined_block(Some(parent), parent.timestamp + 1, vec![coinbase]));
+        }
+        blocks
+    }
+
+    fn hashes(blocks: &[&Block]) -> Vec<Vec<u8>> {
+        blocks.iter().map(|block| block.hash.clone().unwrap()).collect()
+    }
+
+    fn hash_of(block: Result<Option<Block>, StoreError>) -> Option<Vec<u8>> {
+        block.unwrap().and_then(|block| block.hash)
+    }
+
+    // What every store has to do the same way: blocks by hash and by best
+    // chain height, the best chain moving to a side branch and back, and
+    // iteration in the order the blocks were put.
+    fn check_blocks_and_best_chain(mut store: impl BlockStore) {
+        let genesis = mined_block(None, NOW, vec![Transaction::coinbase("miner".to_string(), 50, 0)]);
+        let main = branch(&genesis, 2, "main");
+        let side = branch(&genesis, 3, "side");
+        assert!(store.tip().unwrap().is_none());
+
+        for block in [&genesis, &main[0], &main[1]] {
+            store.put_block(block).unwrap();
+        }
+        store.set_best_chain(0, &hashes(&[&genesis, &main[0], &main[1]])).unwrap();
+        assert_eq!(hash_of(store.get_by_height(1)), main[0].hash);
+        assert_eq!(hash_of(store.get_by_hash(main[1].hash.as_ref().unwrap())), main[1].hash);
+        assert_eq!(hash_of(store.tip()), main[1].hash);
+
+        // A reorg onto the longer side branch
+        for block in &side {
+            store.put_block(block).unwrap();
+        }
+        store.set_best_chain(1, &hashes(&side.iter().collect::<Vec<_>>())).unwrap();
+        assert_eq!(hash_of(store.get_by_height(0)), genesis.hash);
+        assert_eq!(hash_of(store.get_by_height(1)), side[0].hash);
+        assert_eq!(hash_of(store.tip()), side[2].hash);
+        assert_eq!(hash_of(store.get_by_hash(main[0].hash.as_ref().unwrap())), main[0].hash);
+
+        // and back onto a shorter best chain, which drops the heights above it
+        store.set_best_chain(1, &hashes(&[&main[0]])).unwrap();
+        assert_eq!(hash_of(store.tip()), main[0].hash);
+        assert!(store.get_by_height(2).unwrap().is_none());
+
+        // Putting a block again leaves the store as it was
+        store.put_block(&side[0]).unwrap();
+        let put_order = [&genesis, &main[0], &main[1], &side[0], &side[1], &side[2]];
+        let iterated: Vec<Vec<u8>> = store.iterate().map(|block| block.unwrap().hash.unwrap()).collect();
+        assert_eq!(iterated, hashes(&put_order));
+
+        assert!(store.get_by_hash(&[0; 32]).unwrap().is_none());
+        assert!(matches!(
+            store.set_best_chain(1, &[vec![0; 32]]),
+            Err(StoreError::MissingBlock(_))
+        ));
+
+        assert!(store.load_metadata().unwrap().is_none());
+        store.save_metadata(b"{}").unwrap();
+        assert_eq!(store.load_metadata().unwrap(), Some(b"{}".to_vec()));
+    }
+
+    // A chain that reorgs onto a side branch, saved and opened again from
+    // its store, comes back with the same best chain.
+    fn check_reopen<S: BlockStore>(create: impl Fn() -> S, open: impl Fn() -> S) {
+        let spec = GenesisSpec {
+            consensus: ChainConfig {
+                initial_difficulty: 4,
+                min_difficulty: 1,
+                max_difficulty: 8,
+                ..ChainConfig::default()
+            },
+            ..GenesisSpec::default()
+        };
+        let mut other = Blockchain::from_genesis(&spec);
+        for _ in 0..3 {
+            other.mine_block("side");
+        }
+
+        let mut blockchain = Blockchain::with_store(&spec, create()).unwrap();
+        for _ in 0..2 {
+            blockchain.mine_block("main");
+        }
+        let outcomes: Vec<SubmitOutcome> = other.get_chain()[1..]
+            .iter()
+            .map(|block| blockchain.submit_block(block.clone()).unwrap())
+            .collect();
+        assert!(matches!(outcomes[2], SubmitOutcome::Reorg { .. }));
+        blockchain.save().unwrap();
+        let chain = blockchain.get_chain().clone();
+        drop(blockchain);
+
+        let reopened = Blockchain::open(open()).unwrap();
+        assert_eq!(
+            reopened.get_chain().iter().map(|block| block.hash.clone()).collect::<Vec<_>>(),
+            chain.iter().map(|block| block.hash.clone()).collect::<Vec<_>>()
+        );
+        assert_eq!(reopened.get_chain().last().unwrap().hash, other.get_latest_block().hash);
+        assert_eq!(reopened.validate_chain(), Ok(()));
+        let store = reopened.store().unwrap();
+        assert_eq!(hash_of(store.tip()), other.get_latest_block().hash);
+        assert_eq!(store.iterate().count(), 6);
+    }
+
+    #[test]
+    fn memory_store_conforms() {
+        check_blocks_and_best_chain(MemoryStore::default());
+    }
+
+    #[test]
+    fn block_log_conforms() {
+        let dir = TempDir::new("block-log-conformance");
+        check_blocks_and_best_chain(BlockLog::create(dir.path()).unwrap());
+        let dir = TempDir::new("block-log-reopen-chain");
+        check_reopen(|| BlockLog::create(dir.path()).unwrap(), || BlockLog::open(dir.path()).unwrap());
+    }
+
+    #[test]
+    fn redb_store_conforms() {
+        let dir = TempDir::new("redb-conformance");
+        check_blocks_and_best_chain(RedbStore::create(dir.path()).unwrap());
+        let dir = TempDir::new("redb-reopen-chain");
+        check_reopen(|| RedbStore::create(dir.path()).unwrap(), || RedbStore::open(dir.path()).unwrap());
+    }
+}
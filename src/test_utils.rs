@@ -0,0 +1,54 @@
+// Helpers shared by the unit tests of several modules.
+
+use crate::block::{Block, BLOCK_VERSION};
+use crate::transaction::Transaction;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// A fresh directory under the system temp directory, removed again when
+// dropped. Tests running in parallel each get their own.
+pub struct TempDir {
+    path: PathBuf,
+}
+
+impl TempDir {
+    pub fn new(name: &str) -> TempDir {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let path = std::env::temp_dir().join(format!(
+            "rust_chain-{}-{}-{}",
+            name,
+            std::process::id(),
+            COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir_all(&path).expect("temp directory can be created");
+        TempDir { path }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.path);
+    }
+}
+
+// A current version block on top of `parent`, mined at a low difficulty.
+pub fn mined_block(parent: Option<&Block>, timestamp: i64, transactions: Vec<Transaction>) -> Block {
+    let mut block = Block {
+        id: parent.map_or(0, |parent| parent.id + 1),
+        timestamp,
+        previous_hash: parent.and_then(|parent| parent.hash.clone()),
+        hash: None,
+        merkle_root: None,
+        transactions,
+        difficulty: 4,
+        nonce: 0,
+        version: BLOCK_VERSION,
+    };
+    block.mine();
+    block
+}
@@ -1,38 +1,439 @@
+use crate::codec::{CodecError, Decode, Encode, Reader, Writer};
+use crate::utils::{from_hex_string, to_hex_string};
+use crate::utxo::{OutPoint, TxInput, TxOutput};
+use crate::wallet::address_from_public_key;
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
 use serde::{Serialize, Deserialize};
 use sha2::{Sha256, Digest};
+use std::fmt;
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Transaction {
     sender: String,
     receiver: String,
-    amount: u64, // or whatever type 
+    amount: u64, // or whatever type
+    // Sequence number of the transaction among those sent by `sender`, starting
+    // at 0. For a coinbase it is the height of its block.
+    #[serde(default)]
+    nonce: u64,
+    // Paid to the miner of the block that includes the transaction, on top of amount
+    #[serde(default)]
+    fee: u64,
+    // Ed25519 public key of the sender and its signature over signing_bytes()
+    #[serde(default)]
+    public_key: Vec<u8>,
+    #[serde(default)]
+    signature: Vec<u8>,
+    // Only used on UTXO chains, where they replace receiver and amount
+    #[serde(default)]
+    inputs: Vec<TxInput>,
+    #[serde(default)]
+    outputs: Vec<TxOutput>,
     pub hash: Vec<u8>,
 }
 
+#[derive(Debug, PartialEq)]
+pub enum TransactionError {
+    Unsigned,
+    InvalidPublicKey,
+    SenderMismatch,
+    InvalidSignature,
+    InsufficientFunds {
+        address: String,
+        balance: u64,
+        required: u64,
+    },
+    LedgerMismatch,
+    UnknownOrSpentOutput(OutPoint),
+    DoubleSpend(OutPoint),
+    NotOutputOwner(OutPoint),
+    ValueMismatch {
+        inputs: u64,
+        outputs: u64,
+    },
+    StaleNonce {
+        address: String,
+        expected: u64,
+        got: u64,
+    },
+    NonceGap {
+        address: String,
+        expected: u64,
+        got: u64,
+    },
+    DuplicateTransaction,
+    // The hash field is not the id of the transaction
+    IdMismatch,
+    ReplacementUnderpriced {
+        fee: u64,
+        required: u64,
+    },
+    MempoolFull,
+    // The amounts and fee of the transaction, or a balance it credits, add
+    // up past u64::MAX
+    AmountOverflow,
+}
+
+impl fmt::Display for TransactionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TransactionError::Unsigned => write!(f, "transaction is not signed"),
+            TransactionError::InvalidPublicKey => write!(f, "transaction public key is not a valid Ed25519 key"),
+            TransactionError::SenderMismatch => write!(f, "sender address does not match the signing public key"),
+            TransactionError::InvalidSignature => write!(f, "transaction signature does not verify"),
+            TransactionError::InsufficientFunds { address, balance, required } => write!(
+                f,
+                "{} has a balance of {} but the transaction needs {}",
+                address, balance, required
+            ),
+            TransactionError::LedgerMismatch => {
+                write!(f, "transaction does not fit the ledger model of the chain")
+            }
+            TransactionError::UnknownOrSpentOutput(outpoint) => {
+                write!(f, "output {} does not exist or is already spent", outpoint)
+            }
+            TransactionError::DoubleSpend(outpoint) => {
+                write!(f, "output {} is already spent by another pending transaction", outpoint)
+            }
+            TransactionError::NotOutputOwner(outpoint) => {
+                write!(f, "output {} does not belong to the sender", outpoint)
+            }
+            TransactionError::ValueMismatch { inputs, outputs } => {
+                write!(f, "inputs add up to {} but outputs and fee add up to {}", inputs, outputs)
+            }
+            TransactionError::StaleNonce { address, expected, got } => write!(
+                f,
+                "nonce {} of {} was already used, the next one is {}",
+                got, address, expected
+            ),
+            TransactionError::NonceGap { address, expected, got } => write!(
+                f,
+                "nonce {} of {} skips ahead, the next one is {}",
+                got, address, expected
+            ),
+            TransactionError::DuplicateTransaction => write!(f, "transaction is already known"),
+            TransactionError::IdMismatch => write!(f, "transaction hash is not the id of its contents"),
+            TransactionError::ReplacementUnderpriced { fee, required } => write!(
+                f,
+                "replacing a pending transaction needs a fee above {}, got {}",
+                required, fee
+            ),
+            TransactionError::MempoolFull => {
+                write!(f, "mempool is full and the fee rate is too low to evict anything")
+            }
+            TransactionError::AmountOverflow => write!(f, "transaction amounts add up past the largest amount"),
+        }
+    }
+}
+
+impl std::error::Error for TransactionError {}
+
 impl Transaction {
-	pub fn new(sender: String, receiver: String, amount: u64 ) -> Self {
+	pub fn new(sender: String, receiver: String, amount: u64, fee: u64, nonce: u64 ) -> Self {
         let transaction = Transaction {
-            sender: sender.clone(),
-            receiver: receiver.clone(),
-            amount: amount,
+            sender,
+            receiver,
+            amount,
+            nonce,
+            fee,
+            public_key: Vec::new(),
+            signature: Vec::new(),
+            inputs: Vec::new(),
+            outputs: Vec::new(),
             hash: Vec::new(), // Temporary placeholder
         };
-        let hash = transaction.calculate_hash(); // Calculate the hash based on current content
-
-		println!("Sender: {}, Receiver: {}, Amount: {}, Transaction hash: {:#?}", sender, receiver, amount, hash);
+        let hash = transaction.id(); // Calculate the hash based on current content
 
         // Return the transaction with its hash field correctly populated
         Transaction { hash, ..transaction }
     }
 
-    pub fn calculate_hash(&self) -> Vec<u8> {
-        let transaction_data = serde_json::to_string(self).unwrap();
+    // The first transaction of every block mints the block reward to the miner.
+    // It has no sender and carries no signature, and its nonce is the block
+    // height so no two coinbases share a hash.
+    pub fn coinbase(receiver: String, amount: u64, height: u32) -> Self {
+        Transaction::new(String::new(), receiver, amount, 0, height as u64)
+    }
+
+    // A UTXO transaction spends earlier outputs owned by `sender` into new outputs.
+    // The inputs must add up to the outputs plus the fee.
+    pub fn new_utxo(sender: String, inputs: Vec<TxInput>, outputs: Vec<TxOutput>, fee: u64) -> Self {
+        let transaction = Transaction {
+            sender,
+            receiver: String::new(),
+            amount: 0,
+            nonce: 0,
+            fee,
+            public_key: Vec::new(),
+            signature: Vec::new(),
+            inputs,
+            outputs,
+            hash: Vec::new(),
+        };
+        let hash = transaction.id();
+        Transaction { hash, ..transaction }
+    }
+
+    // Coinbase of a UTXO chain, minting the reward as a single output.
+    pub fn utxo_coinbase(receiver: String, amount: u64, height: u32) -> Self {
+        let mut transaction =
+            Transaction::new_utxo(String::new(), Vec::new(), vec![TxOutput { address: receiver, amount }], 0);
+        transaction.nonce = height as u64;
+        transaction.hash = transaction.id();
+        transaction
+    }
+
+    pub fn is_coinbase(&self) -> bool {
+        self.sender.is_empty() && self.public_key.is_empty() && self.signature.is_empty()
+    }
+
+    // Canonical bytes covered by the signature: every field is length prefixed
+    // or fixed width so two different transactions can never share an encoding.
+    pub fn signing_bytes(&self) -> Vec<u8> {
+        let mut data = Vec::new();
+        for field in [self.sender.as_bytes(), self.receiver.as_bytes(), &self.public_key] {
+            data.extend_from_slice(&(field.len() as u32).to_le_bytes());
+            data.extend_from_slice(field);
+        }
+        data.extend_from_slice(&self.amount.to_le_bytes());
+        data.extend_from_slice(&self.nonce.to_le_bytes());
+        data.extend_from_slice(&self.fee.to_le_bytes());
+
+        data.extend_from_slice(&(self.inputs.len() as u32).to_le_bytes());
+        for input in &self.inputs {
+            let outpoint = &input.previous_output;
+            data.extend_from_slice(&(outpoint.txid.len() as u32).to_le_bytes());
+            data.extend_from_slice(&outpoint.txid);
+            data.extend_from_slice(&outpoint.index.to_le_bytes());
+        }
+        data.extend_from_slice(&(self.outputs.len() as u32).to_le_bytes());
+        for output in &self.outputs {
+            data.extend_from_slice(&(output.address.len() as u32).to_le_bytes());
+            data.extend_from_slice(output.address.as_bytes());
+            data.extend_from_slice(&output.amount.to_le_bytes());
+        }
+        data
+    }
+
+    // Attach the public key and a signature made with the sender's key, then
+    // refresh the hash so it covers the signature too.
+    pub fn sign(&mut self, signing_key: &SigningKey) {
+        self.public_key = signing_key.verifying_key().to_bytes().to_vec();
+        self.signature = signing_key.sign(&self.signing_bytes()).to_bytes().to_vec();
+        self.hash = self.id();
+    }
+
+    // Check that the transaction is signed by the key its sender address belongs to.
+    pub fn verify_signature(&self) -> Result<(), TransactionError> {
+        if self.public_key.is_empty() || self.signature.is_empty() {
+            return Err(TransactionError::Unsigned);
+        }
+
+        let public_key: [u8; 32] = self
+            .public_key
+            .as_slice()
+            .try_into()
+            .map_err(|_| TransactionError::InvalidPublicKey)?;
+        let verifying_key =
+            VerifyingKey::from_bytes(&public_key).map_err(|_| TransactionError::InvalidPublicKey)?;
+
+        if address_from_public_key(&verifying_key) != self.sender {
+            return Err(TransactionError::SenderMismatch);
+        }
+
+        let signature =
+            Signature::from_slice(&self.signature).map_err(|_| TransactionError::InvalidSignature)?;
+        verifying_key
+            .verify(&self.signing_bytes(), &signature)
+            .map_err(|_| TransactionError::InvalidSignature)
+    }
+
+    // The id of the transaction, which its hash field must hold: SHA-256 of
+    // signing_bytes followed by the length prefixed signature. The hash field
+    // itself is not part of it and neither is how serde lays the fields out,
+    // so a transaction has the same id before and after it is stored.
+    pub fn id(&self) -> Vec<u8> {
         let mut hasher = Sha256::new();
-        hasher.update(transaction_data.as_bytes());
+        hasher.update(self.signing_bytes());
+        hasher.update((self.signature.len() as u32).to_le_bytes());
+        hasher.update(&self.signature);
         hasher.finalize().to_vec()
     }
 
+    // Check that the stored hash is the id of the transaction's contents.
+    pub fn verify_id(&self) -> Result<(), TransactionError> {
+        if self.hash != self.id() {
+            return Err(TransactionError::IdMismatch);
+        }
+        Ok(())
+    }
+
     pub fn hash(&self) -> &Vec<u8> {
         &self.hash
     }
+
+    pub fn sender(&self) -> &str {
+        &self.sender
+    }
+
+    pub fn receiver(&self) -> &str {
+        &self.receiver
+    }
+
+    pub fn amount(&self) -> u64 {
+        self.amount
+    }
+
+    pub fn nonce(&self) -> u64 {
+        self.nonce
+    }
+
+    pub fn fee(&self) -> u64 {
+        self.fee
+    }
+
+    // Size in bytes of the canonical encoding plus the signature, used to
+    // price transactions by fee per byte.
+    pub fn size(&self) -> usize {
+        self.signing_bytes().len() + self.signature.len()
+    }
+
+    // Compare fee per byte without floating point: a/b > c/d <=> a*d > c*b.
+    pub fn cmp_fee_rate(&self, other: &Transaction) -> std::cmp::Ordering {
+        let ours = self.fee as u128 * other.size() as u128;
+        let theirs = other.fee as u128 * self.size() as u128;
+        ours.cmp(&theirs)
+    }
+
+    pub fn inputs(&self) -> &[TxInput] {
+        &self.inputs
+    }
+
+    pub fn outputs(&self) -> &[TxOutput] {
+        &self.outputs
+    }
+
+    // View of the transaction with every byte field hex encoded, for printing
+    // and the RPC API.
+    pub fn to_serializable(&self) -> SerializableTransaction {
+        SerializableTransaction {
+            hash: to_hex_string(&self.hash),
+            sender: self.sender.clone(),
+            receiver: self.receiver.clone(),
+            amount: self.amount,
+            nonce: self.nonce,
+            fee: self.fee,
+            public_key: to_hex_string(&self.public_key),
+            signature: to_hex_string(&self.signature),
+            inputs: self
+                .inputs
+                .iter()
+                .map(|input| SerializableInput {
+                    txid: to_hex_string(&input.previous_output.txid),
+                    index: input.previous_output.index,
+                })
+                .collect(),
+            outputs: self.outputs.clone(),
+        }
+    }
+
+    // Sum of the output amounts, or None if it does not fit in a u64.
+    pub fn output_total(&self) -> Option<u64> {
+        self.outputs
+            .iter()
+            .try_fold(0u64, |total, output| total.checked_add(output.amount))
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SerializableInput {
+    txid: String,
+    index: u32,
+}
+
+// Hex encoded view of a Transaction, see Transaction::to_serializable. It is
+// also how the RPC API takes transactions in, so it reads back with
+// to_transaction.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SerializableTransaction {
+    hash: String,
+    sender: String,
+    #[serde(default)]
+    receiver: String,
+    #[serde(default)]
+    amount: u64,
+    #[serde(default)]
+    nonce: u64,
+    #[serde(default)]
+    fee: u64,
+    #[serde(default)]
+    public_key: String,
+    #[serde(default)]
+    signature: String,
+    #[serde(default)]
+    inputs: Vec<SerializableInput>,
+    #[serde(default)]
+    outputs: Vec<TxOutput>,
+}
+
+impl SerializableTransaction {
+    // The transaction this is a view of, or None if a field is not hex.
+    pub fn to_transaction(&self) -> Option<Transaction> {
+        let inputs = self
+            .inputs
+            .iter()
+            .map(|input| {
+                Some(TxInput {
+                    previous_output: OutPoint {
+                        txid: from_hex_string(&input.txid)?,
+                        index: input.index,
+                    },
+                })
+            })
+            .collect::<Option<Vec<TxInput>>>()?;
+        Some(Transaction {
+            sender: self.sender.clone(),
+            receiver: self.receiver.clone(),
+            amount: self.amount,
+            nonce: self.nonce,
+            fee: self.fee,
+            public_key: from_hex_string(&self.public_key)?,
+            signature: from_hex_string(&self.signature)?,
+            inputs,
+            outputs: self.outputs.clone(),
+            hash: from_hex_string(&self.hash)?,
+        })
+    }
+}
+
+impl Encode for Transaction {
+    fn encode_to(&self, writer: &mut Writer) {
+        writer.string(&self.sender);
+        writer.string(&self.receiver);
+        writer.u64(self.amount);
+        writer.u64(self.nonce);
+        writer.u64(self.fee);
+        writer.bytes(&self.public_key);
+        writer.bytes(&self.signature);
+        writer.list(&self.inputs);
+        writer.list(&self.outputs);
+        writer.bytes(&self.hash);
+    }
+}
+
+impl Decode for Transaction {
+    fn decode_from(reader: &mut Reader) -> Result<Self, CodecError> {
+        Ok(Transaction {
+            sender: reader.string()?,
+            receiver: reader.string()?,
+            amount: reader.u64()?,
+            nonce: reader.u64()?,
+            fee: reader.u64()?,
+            public_key: reader.bytes()?,
+            signature: reader.bytes()?,
+            inputs: reader.list()?,
+            outputs: reader.list()?,
+            hash: reader.bytes()?,
+        })
+    }
 }
@@ -1,6 +1,29 @@
-pub fn to_hex_string(bytes: &Vec<u8>) -> String {
+pub fn to_hex_string(bytes: &[u8]) -> String {
     bytes
         .iter()
         .map(|byte| format!("{:02x}", byte))
         .collect::<String>()
-}
\ No newline at end of file
+}
+// Count the leading zero bits of a hash, used to check Proof of Work.
+pub fn leading_zero_bits(bytes: &[u8]) -> u32 {
+    let mut bits = 0;
+    for byte in bytes {
+        if *byte == 0 {
+            bits += 8;
+        } else {
+            bits += byte.leading_zeros();
+            break;
+        }
+    }
+    bits
+}
+
+pub fn from_hex_string(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
@@ -0,0 +1,255 @@
+use crate::block::Block;
+use crate::codec::{CodecError, Decode, Encode, Reader, Writer};
+use crate::transaction::{Transaction, TransactionError};
+use crate::utils::to_hex_string;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+// Reference to one output of an earlier transaction.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct OutPoint {
+    pub txid: Vec<u8>,
+    pub index: u32,
+}
+
+impl fmt::Display for OutPoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", to_hex_string(&self.txid), self.index)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct TxInput {
+    pub previous_output: OutPoint,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct TxOutput {
+    pub address: String,
+    pub amount: u64,
+}
+
+impl Encode for TxInput {
+    fn encode_to(&self, writer: &mut Writer) {
+        writer.bytes(&self.previous_output.txid);
+        writer.u32(self.previous_output.index);
+    }
+}
+
+impl Decode for TxInput {
+    fn decode_from(reader: &mut Reader) -> Result<Self, CodecError> {
+        Ok(TxInput {
+            previous_output: OutPoint {
+                txid: reader.bytes()?,
+                index: reader.u32()?,
+            },
+        })
+    }
+}
+
+impl Encode for TxOutput {
+    fn encode_to(&self, writer: &mut Writer) {
+        writer.string(&self.address);
+        writer.u64(self.amount);
+    }
+}
+
+impl Decode for TxOutput {
+    fn decode_from(reader: &mut Reader) -> Result<Self, CodecError> {
+        Ok(TxOutput {
+            address: reader.string()?,
+            amount: reader.u64()?,
+        })
+    }
+}
+
+// The unspent transaction outputs after replaying a chain in UTXO mode. Like
+// AccountState it is never persisted, only rebuilt from the blocks.
+#[derive(Debug, Clone, Default)]
+pub struct UtxoSet {
+    unspent: HashMap<OutPoint, TxOutput>,
+}
+
+impl UtxoSet {
+    pub fn from_blocks(blocks: &[Block]) -> Result<Self, TransactionError> {
+        let mut utxos = UtxoSet::default();
+        for block in blocks {
+            utxos.apply_block(block)?;
+        }
+        Ok(utxos)
+    }
+
+    pub fn apply_block(&mut self, block: &Block) -> Result<(), TransactionError> {
+        for transaction in &block.transactions {
+            self.apply_transaction(transaction)?;
+        }
+        Ok(())
+    }
+
+    // Spend the inputs and create the outputs of a transaction. Every input
+    // must be unspent and belong to the sender, and the inputs must add up to
+    // exactly the outputs plus the fee. On error the set is unchanged.
+    pub fn apply_transaction(&mut self, transaction: &Transaction) -> Result<(), TransactionError> {
+        if !transaction.receiver().is_empty() || transaction.amount() != 0 {
+            return Err(TransactionError::LedgerMismatch);
+        }
+
+        if !transaction.is_coinbase() {
+            if transaction.inputs().is_empty() {
+                return Err(TransactionError::LedgerMismatch);
+            }
+
+            let mut seen = HashSet::new();
+            let mut input_total: u64 = 0;
+            for input in transaction.inputs() {
+                let outpoint = &input.previous_output;
+                if !seen.insert(outpoint) {
+                    return Err(TransactionError::DoubleSpend(outpoint.clone()));
+                }
+                let output = self
+                    .unspent
+                    .get(outpoint)
+                    .ok_or_else(|| TransactionError::UnknownOrSpentOutput(outpoint.clone()))?;
+                if output.address != transaction.sender() {
+                    return Err(TransactionError::NotOutputOwner(outpoint.clone()));
+                }
+                input_total = input_total.saturating_add(output.amount);
+            }
+
+            // Outputs that add up past u64::MAX would wrap around to a small
+            // total, so they never match the inputs
+            let output_total = transaction
+                .output_total()
+                .and_then(|total| total.checked_add(transaction.fee()));
+            if output_total != Some(input_total) {
+                return Err(TransactionError::ValueMismatch {
+                    inputs: input_total,
+                    outputs: output_total.unwrap_or(u64::MAX),
+                });
+            }
+
+            for input in transaction.inputs() {
+                self.unspent.remove(&input.previous_output);
+            }
+        }
+
+        for (index, output) in transaction.outputs().iter().enumerate() {
+            let outpoint = OutPoint {
+                txid: transaction.hash.clone(),
+                index: index as u32,
+            };
+            self.unspent.insert(outpoint, output.clone());
+        }
+        Ok(())
+    }
+
+    pub fn get(&self, outpoint: &OutPoint) -> Option<&TxOutput> {
+        self.unspent.get(outpoint)
+    }
+
+    // All unspent outputs paying the given address.
+    pub fn outputs_of(&self, address: &str) -> Vec<(OutPoint, TxOutput)> {
+        self.unspent
+            .iter()
+            .filter(|(_, output)| output.address == address)
+            .map(|(outpoint, output)| (outpoint.clone(), output.clone()))
+            .collect()
+    }
+
+    pub fn balance_of(&self, address: &str) -> u64 {
+        self.unspent
+            .values()
+            .filter(|output| output.address == address)
+            .fold(0, |total: u64, output| total.saturating_add(output.amount))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::Blockchain;
+    use crate::config::{ChainConfig, Ledger};
+    use crate::wallet::Wallet;
+
+    // A set where `address` owns a single output of `amount`
+    fn funded(address: &str, amount: u64) -> (UtxoSet, OutPoint) {
+        let coinbase = Transaction::utxo_coinbase(address.to_string(), amount, 0);
+        let outpoint = OutPoint {
+            txid: coinbase.hash.clone(),
+            index: 0,
+        };
+        let mut utxos = UtxoSet::default();
+        utxos.apply_transaction(&coinbase).unwrap();
+        (utxos, outpoint)
+    }
+
+    fn spend(wallet: &Wallet, outpoint: &OutPoint, amounts: &[u64], fee: u64) -> Transaction {
+        let inputs = vec![TxInput {
+            previous_output: outpoint.clone(),
+        }];
+        let outputs = amounts
+            .iter()
+            .map(|&amount| TxOutput {
+                address: "bob".to_string(),
+                amount,
+            })
+            .collect();
+        let mut transaction = Transaction::new_utxo(wallet.address(), inputs, outputs, fee);
+        wallet.sign_transaction(&mut transaction);
+        transaction
+    }
+
+    #[test]
+    fn inputs_must_cover_outputs_and_fee_exactly() {
+        let alice = Wallet::generate();
+        let (mut utxos, outpoint) = funded(&alice.address(), 10);
+
+        assert_eq!(
+            utxos.apply_transaction(&spend(&alice, &outpoint, &[6, 4], 1)),
+            Err(TransactionError::ValueMismatch { inputs: 10, outputs: 11 })
+        );
+        assert_eq!(utxos.apply_transaction(&spend(&alice, &outpoint, &[6, 3], 1)), Ok(()));
+        assert_eq!(utxos.balance_of("bob"), 9);
+        assert_eq!(utxos.balance_of(&alice.address()), 0);
+    }
+
+    #[test]
+    fn outputs_adding_up_past_the_maximum_are_rejected() {
+        let alice = Wallet::generate();
+        let (mut utxos, outpoint) = funded(&alice.address(), 1);
+
+        // Wrapping around, u64::MAX + 2 would be 1 and match the input
+        let transaction = spend(&alice, &outpoint, &[u64::MAX, 2], 0);
+        assert_eq!(transaction.output_total(), None);
+        assert_eq!(
+            utxos.apply_transaction(&transaction),
+            Err(TransactionError::ValueMismatch { inputs: 1, outputs: u64::MAX })
+        );
+        assert!(utxos.get(&outpoint).is_some());
+
+        // The fee can not wrap the total around either
+        let transaction = spend(&alice, &outpoint, &[u64::MAX], 2);
+        assert!(matches!(utxos.apply_transaction(&transaction), Err(TransactionError::ValueMismatch { .. })));
+    }
+
+    #[test]
+    fn chains_reject_overflowing_outputs() {
+        let alice = Wallet::generate();
+        let mut blockchain = Blockchain::with_config(ChainConfig {
+            ledger: Ledger::Utxo,
+            initial_difficulty: 4,
+            min_difficulty: 1,
+            ..ChainConfig::default()
+        });
+        blockchain.mine_block(&alice.address());
+        let (outpoint, _) = blockchain.spendable_outputs(&alice.address()).remove(0);
+
+        let transaction = spend(&alice, &outpoint, &[u64::MAX, 2], 0);
+        assert!(matches!(
+            blockchain.add_transaction(transaction),
+            Err(TransactionError::ValueMismatch { .. })
+        ));
+        assert!(blockchain.mempool().is_empty());
+    }
+}
@@ -0,0 +1,277 @@
+use crate::transaction::{Transaction, TransactionError};
+use crate::utxo::{OutPoint, TxInput, TxOutput};
+use crate::utils::{from_hex_string, to_hex_string};
+use chacha20poly1305::aead::{Aead, KeyInit};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use ed25519_dalek::{SigningKey, VerifyingKey};
+use rand::rngs::OsRng;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::File;
+use std::io::Write;
+
+// Version byte prepended to the public key hash before base58check encoding.
+const ADDRESS_VERSION: u8 = 0x00;
+// PBKDF2 rounds used to turn a keystore password into an encryption key. The
+// count is stored with each entry so it can be raised without breaking old files.
+const KEY_DERIVATION_ROUNDS: u32 = 10_000;
+
+// Derive the address of a public key: the first 20 bytes of its SHA-256 hash,
+// base58check encoded with a version byte, like a Bitcoin address.
+pub fn address_from_public_key(public_key: &VerifyingKey) -> String {
+    let digest = Sha256::digest(public_key.as_bytes());
+    bs58::encode(&digest[..20])
+        .with_check_version(ADDRESS_VERSION)
+        .into_string()
+}
+
+#[derive(Debug, PartialEq)]
+pub enum WalletError {
+    UnknownWallet(String),
+    WalletExists(String),
+    WrongPassword,
+    CorruptKeystore,
+}
+
+impl fmt::Display for WalletError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WalletError::UnknownWallet(name) => write!(f, "no wallet named '{}' in the keystore", name),
+            WalletError::WalletExists(name) => write!(f, "a wallet named '{}' already exists", name),
+            WalletError::WrongPassword => write!(f, "wrong keystore password"),
+            WalletError::CorruptKeystore => write!(f, "keystore entry is corrupt"),
+        }
+    }
+}
+
+impl std::error::Error for WalletError {}
+
+// A wallet holds one Ed25519 keypair and signs transactions for its address.
+pub struct Wallet {
+    signing_key: SigningKey,
+}
+
+impl Wallet {
+    pub fn generate() -> Self {
+        Wallet {
+            signing_key: SigningKey::generate(&mut OsRng),
+        }
+    }
+
+    pub fn from_secret_bytes(secret: &[u8; 32]) -> Self {
+        Wallet {
+            signing_key: SigningKey::from_bytes(secret),
+        }
+    }
+
+    pub fn public_key(&self) -> VerifyingKey {
+        self.signing_key.verifying_key()
+    }
+
+    pub fn address(&self) -> String {
+        address_from_public_key(&self.public_key())
+    }
+
+    // Attach this wallet's public key and a signature to the transaction.
+    pub fn sign_transaction(&self, transaction: &mut Transaction) {
+        transaction.sign(&self.signing_key);
+    }
+
+    // Build and sign a transaction sending `amount` from this wallet to `receiver`,
+    // offering `fee` to the miner. `nonce` must be the next one of this address,
+    // see Blockchain::next_nonce.
+    pub fn create_transaction(&self, receiver: &str, amount: u64, fee: u64, nonce: u64) -> Transaction {
+        let mut transaction = Transaction::new(self.address(), receiver.to_string(), amount, fee, nonce);
+        self.sign_transaction(&mut transaction);
+        transaction
+    }
+
+    // Build and sign a UTXO transaction paying `amount` to `receiver` out of the
+    // given spendable outputs, see Blockchain::spendable_outputs. Outputs are
+    // picked in order until they cover the amount and fee, the rest comes back
+    // as change.
+    pub fn create_utxo_transaction(
+        &self,
+        spendable: &[(OutPoint, TxOutput)],
+        receiver: &str,
+        amount: u64,
+        fee: u64,
+    ) -> Result<Transaction, TransactionError> {
+        let required = amount.checked_add(fee).ok_or(TransactionError::AmountOverflow)?;
+        let mut inputs = Vec::new();
+        let mut total: u64 = 0;
+        for (outpoint, output) in spendable {
+            if total >= required && !inputs.is_empty() {
+                break;
+            }
+            inputs.push(TxInput {
+                previous_output: outpoint.clone(),
+            });
+            // Saturates only past u64::MAX, which no transaction can spend anyway
+            total = total.saturating_add(output.amount);
+        }
+
+        if total < required || inputs.is_empty() {
+            return Err(TransactionError::InsufficientFunds {
+                address: self.address(),
+                balance: total,
+                required,
+            });
+        }
+
+        let mut outputs = vec![TxOutput {
+            address: receiver.to_string(),
+            amount,
+        }];
+        if total > required {
+            outputs.push(TxOutput {
+                address: self.address(),
+                amount: total - required,
+            });
+        }
+
+        let mut transaction = Transaction::new_utxo(self.address(), inputs, outputs, fee);
+        self.sign_transaction(&mut transaction);
+        Ok(transaction)
+    }
+}
+
+// A secret key encrypted with ChaCha20-Poly1305 under a key derived from the
+// keystore password. All byte fields are hex encoded in the keystore file.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct EncryptedKey {
+    address: String,
+    rounds: u32,
+    salt: String,
+    nonce: String,
+    ciphertext: String,
+}
+
+// Named wallets saved to a JSON file, usually keystore.json next to the chain.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct Keystore {
+    wallets: BTreeMap<String, EncryptedKey>,
+}
+
+impl Keystore {
+    pub fn load_from_file(path: &str) -> Result<Keystore, Box<dyn std::error::Error>> {
+        if std::path::Path::new(path).exists() {
+            let data = std::fs::read_to_string(path)?;
+            if !data.trim().is_empty() {
+                return Ok(serde_json::from_str(&data)?);
+            }
+        }
+        Ok(Keystore::default())
+    }
+
+    pub fn save_to_file(&self, path: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let data = serde_json::to_string_pretty(&self)?;
+        let mut file = File::create(path)?;
+        file.write_all(data.as_bytes())?;
+        Ok(())
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &String> {
+        self.wallets.keys()
+    }
+
+    // Address of a stored wallet, available without the password.
+    pub fn address_of(&self, name: &str) -> Option<&str> {
+        self.wallets.get(name).map(|entry| entry.address.as_str())
+    }
+
+    pub fn add(&mut self, name: &str, wallet: &Wallet, password: &str) -> Result<(), WalletError> {
+        if self.wallets.contains_key(name) {
+            return Err(WalletError::WalletExists(name.to_string()));
+        }
+
+        let mut salt = [0u8; 16];
+        let mut nonce = [0u8; 12];
+        OsRng.fill_bytes(&mut salt);
+        OsRng.fill_bytes(&mut nonce);
+
+        let cipher = Self::cipher(password, &salt, KEY_DERIVATION_ROUNDS);
+        let ciphertext = cipher
+            .encrypt(Nonce::from_slice(&nonce), wallet.signing_key.to_bytes().as_slice())
+            .map_err(|_| WalletError::CorruptKeystore)?;
+
+        self.wallets.insert(
+            name.to_string(),
+            EncryptedKey {
+                address: wallet.address(),
+                rounds: KEY_DERIVATION_ROUNDS,
+                salt: to_hex_string(&salt),
+                nonce: to_hex_string(&nonce),
+                ciphertext: to_hex_string(&ciphertext),
+            },
+        );
+        Ok(())
+    }
+
+    // Decrypt a stored wallet. A wrong password fails the AEAD tag check.
+    pub fn unlock(&self, name: &str, password: &str) -> Result<Wallet, WalletError> {
+        let entry = self
+            .wallets
+            .get(name)
+            .ok_or_else(|| WalletError::UnknownWallet(name.to_string()))?;
+
+        let salt = from_hex_string(&entry.salt).ok_or(WalletError::CorruptKeystore)?;
+        let nonce = from_hex_string(&entry.nonce).ok_or(WalletError::CorruptKeystore)?;
+        let ciphertext = from_hex_string(&entry.ciphertext).ok_or(WalletError::CorruptKeystore)?;
+        if nonce.len() != 12 {
+            return Err(WalletError::CorruptKeystore);
+        }
+
+        let secret = Self::cipher(password, &salt, entry.rounds)
+            .decrypt(Nonce::from_slice(&nonce), ciphertext.as_slice())
+            .map_err(|_| WalletError::WrongPassword)?;
+        let secret: [u8; 32] = secret
+            .as_slice()
+            .try_into()
+            .map_err(|_| WalletError::CorruptKeystore)?;
+
+        let wallet = Wallet::from_secret_bytes(&secret);
+        if wallet.address() != entry.address {
+            return Err(WalletError::CorruptKeystore);
+        }
+        Ok(wallet)
+    }
+
+    fn cipher(password: &str, salt: &[u8], rounds: u32) -> ChaCha20Poly1305 {
+        let mut key = [0u8; 32];
+        pbkdf2::pbkdf2_hmac::<Sha256>(password.as_bytes(), salt, rounds, &mut key);
+        ChaCha20Poly1305::new(Key::from_slice(&key))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn amounts_past_the_maximum_are_rejected() {
+        let wallet = Wallet::generate();
+        let spendable: Vec<(OutPoint, TxOutput)> = [u64::MAX - 1, 1]
+            .into_iter()
+            .enumerate()
+            .map(|(index, amount)| {
+                let outpoint = OutPoint { txid: vec![0; 32], index: index as u32 };
+                (outpoint, TxOutput { address: wallet.address(), amount })
+            })
+            .collect();
+
+        for (amount, fee) in [(u64::MAX, 1), (1, u64::MAX)] {
+            assert_eq!(
+                wallet.create_utxo_transaction(&spendable, "bob", amount, fee).err(),
+                Some(TransactionError::AmountOverflow)
+            );
+        }
+
+        let transaction = wallet.create_utxo_transaction(&spendable, "bob", u64::MAX - 1, 1).unwrap();
+        assert_eq!(transaction.inputs().len(), 2);
+        assert_eq!(transaction.output_total(), Some(u64::MAX - 1));
+    }
+}
@@ -0,0 +1,220 @@
+use rust_chain::block::Block;
+use rust_chain::blockchain::Blockchain;
+use rust_chain::config::ChainConfig;
+use rust_chain::genesis::GenesisSpec;
+use rust_chain::storage::{BlockStore, MemoryStore, PersistencePolicy, StoreError};
+use rust_chain::p2p::{Message, Node, SharedChain, MAX_MESSAGE_BYTES};
+use rust_chain::utils::to_hex_string;
+use rust_chain::wallet::Wallet;
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpStream;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Poll until the condition holds, failing the test after a few seconds.
+fn wait_for(what: &str, condition: impl Fn() -> bool) {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while !condition() {
+        assert!(Instant::now() < deadline, "timed out waiting for {}", what);
+        thread::sleep(Duration::from_millis(20));
+    }
+}
+
+fn tip_of(chain: &SharedChain) -> Option<Vec<u8>> {
+    chain.lock().unwrap().get_latest_block().hash.clone()
+}
+
+#[test]
+fn nodes_sync_and_gossip_blocks_and_transactions() {
+    // All nodes start from the same, deterministic genesis block
+    let load = || Arc::new(Mutex::new(Blockchain::new()));
+    let (chain_a, chain_b, chain_c) = (load(), load(), load());
+
+    // Node A is a few blocks ahead before anyone connects
+    let alice = Wallet::generate();
+    let bob = Wallet::generate();
+    for _ in 0..3 {
+        chain_a.lock().unwrap().mine_block(&alice.address());
+    }
+
+    let a = Node::start(chain_a.clone(), "127.0.0.1:0").unwrap();
+    let b = Node::start(chain_b.clone(), "127.0.0.1:0").unwrap();
+    let c = Node::start(chain_c.clone(), "127.0.0.1:0").unwrap();
+
+    // A line of peers, C only learns about A's blocks through B
+    b.connect(a.local_addr()).unwrap();
+    c.connect(b.local_addr()).unwrap();
+    wait_for("B and C to sync from A", || {
+        tip_of(&chain_b) == tip_of(&chain_a) && tip_of(&chain_c) == tip_of(&chain_a)
+    });
+    assert_eq!(chain_c.lock().unwrap().get_chain_length(), 4);
+
+    // A transaction sent to C reaches A's mempool through B
+    let transaction = alice.create_transaction(&bob.address(), 10, 1, 0);
+    c.submit_transaction(transaction.clone()).unwrap();
+    wait_for("the transaction to reach A", || {
+        chain_a.lock().unwrap().mempool().contains(&transaction.hash)
+    });
+
+    // A block mined by A reaches C and takes the transaction out of its mempool
+    let block = a.mine_block(&alice.address());
+    assert!(block.transactions.iter().any(|tx| tx.hash == transaction.hash));
+    wait_for("C to receive the new block", || tip_of(&chain_c) == block.hash);
+    assert!(chain_c.lock().unwrap().mempool().is_empty());
+    assert_eq!(chain_c.lock().unwrap().balance_of(&bob.address()), 10);
+    assert_eq!(chain_c.lock().unwrap().validate_chain(), Ok(()));
+
+    for node in [&a, &b, &c] {
+        node.shutdown();
+    }
+}
+
+#[test]
+fn peers_sending_overlong_messages_are_dropped() {
+    let node = Node::start(Arc::new(Mutex::new(Blockchain::new())), "127.0.0.1:0").unwrap();
+    let mut peer = TcpStream::connect(node.local_addr()).unwrap();
+    let mut reader = BufReader::new(peer.try_clone().unwrap());
+    let mut version = String::new();
+    reader.read_line(&mut version).unwrap();
+    assert!(version.contains("\"version\""));
+
+    // One byte over the limit and no newline in sight. The node may close
+    // the connection before all of it is written.
+    let _ = peer.write_all(&vec![b' '; MAX_MESSAGE_BYTES + 1]);
+    wait_for("the node to drop the peer", || node.peer_count() == 0);
+    node.shutdown();
+}
+
+#[test]
+fn a_peer_that_never_reads_holds_up_nobody_else() {
+    let load = || {
+        Arc::new(Mutex::new(Blockchain::with_config(ChainConfig {
+            initial_difficulty: 4,
+            min_difficulty: 1,
+            max_difficulty: 8,
+            ..ChainConfig::default()
+        })))
+    };
+    let (chain_a, chain_b) = (load(), load());
+    for _ in 0..100 {
+        chain_a.lock().unwrap().mine_block("miner");
+    }
+    let genesis = to_hex_string(chain_a.lock().unwrap().get_chain()[0].hash.as_ref().unwrap());
+    let a = Node::start(chain_a.clone(), "127.0.0.1:0").unwrap();
+
+    // Answer the handshake, then ask for the whole chain over and over and
+    // never read any of the answers
+    let mut stuck = TcpStream::connect(a.local_addr()).unwrap();
+    let mut version = String::new();
+    BufReader::new(stuck.try_clone().unwrap()).read_line(&mut version).unwrap();
+    stuck.write_all(version.as_bytes()).unwrap();
+    let mut get_blocks = serde_json::to_vec(&Message::GetBlocks { locator: vec![genesis] }).unwrap();
+    get_blocks.push(b'\n');
+    for _ in 0..500 {
+        if stuck.write_all(&get_blocks).is_err() {
+            break;
+        }
+    }
+
+    // Another peer still syncs and gets new blocks
+    let b = Node::start(chain_b.clone(), "127.0.0.1:0").unwrap();
+    b.connect(a.local_addr()).unwrap();
+    wait_for("B to sync from A", || tip_of(&chain_b) == tip_of(&chain_a));
+    let block = a.mine_block("miner");
+    wait_for("B to receive the new block", || tip_of(&chain_b) == block.hash);
+
+    // The stuck peer's queue filled up and it was dropped
+    wait_for("A to drop the stuck peer", || a.peer_count() == 1);
+    for node in [&a, &b] {
+        node.shutdown();
+    }
+}
+
+// A memory store that fails to save once `failing` is set.
+#[derive(Debug)]
+struct FailingStore {
+    store: MemoryStore,
+    failing: Arc<AtomicBool>,
+}
+
+impl FailingStore {
+    fn check(&self) -> Result<(), StoreError> {
+        if self.failing.load(Ordering::SeqCst) {
+            return Err(StoreError::Io(std::io::Error::other("disk full")));
+        }
+        Ok(())
+    }
+}
+
+impl BlockStore for FailingStore {
+    fn put_block(&mut self, block: &Block) -> Result<(), StoreError> {
+        self.check()?;
+        self.store.put_block(block)
+    }
+
+    fn get_by_hash(&self, hash: &[u8]) -> Result<Option<Block>, StoreError> {
+        self.store.get_by_hash(hash)
+    }
+
+    fn get_by_height(&self, height: u32) -> Result<Option<Block>, StoreError> {
+        self.store.get_by_height(height)
+    }
+
+    fn tip(&self) -> Result<Option<Block>, StoreError> {
+        self.store.tip()
+    }
+
+    fn set_best_chain(&mut self, height: u32, hashes: &[Vec<u8>]) -> Result<(), StoreError> {
+        self.check()?;
+        self.store.set_best_chain(height, hashes)
+    }
+
+    fn iterate(&self) -> Box<dyn Iterator<Item = Result<Block, StoreError>> + '_> {
+        self.store.iterate()
+    }
+
+    fn save_metadata(&mut self, metadata: &[u8]) -> Result<(), StoreError> {
+        self.check()?;
+        self.store.save_metadata(metadata)
+    }
+
+    fn load_metadata(&self) -> Result<Option<Vec<u8>>, StoreError> {
+        self.store.load_metadata()
+    }
+}
+
+#[test]
+fn nodes_stop_when_the_chain_fails_to_save() {
+    let failing = Arc::new(AtomicBool::new(false));
+    let store = FailingStore {
+        store: MemoryStore::default(),
+        failing: failing.clone(),
+    };
+    let spec = GenesisSpec {
+        consensus: ChainConfig {
+            initial_difficulty: 4,
+            min_difficulty: 1,
+            max_difficulty: 8,
+            ..ChainConfig::default()
+        },
+        ..GenesisSpec::default()
+    };
+    let mut blockchain = Blockchain::with_store(&spec, Box::new(store) as _).unwrap();
+    blockchain.set_persistence_policy(PersistencePolicy::OnEveryBlock);
+    let chain_b = Arc::new(Mutex::new(Blockchain::from_genesis(&spec)));
+    chain_b.lock().unwrap().mine_block("miner");
+
+    let a = Node::start(Arc::new(Mutex::new(blockchain)), "127.0.0.1:0").unwrap();
+    let b = Node::start(chain_b.clone(), "127.0.0.1:0").unwrap();
+    failing.store(true, Ordering::SeqCst);
+
+    // A syncs B's block, fails to save it and stops
+    b.connect(a.local_addr()).unwrap();
+    wait_for("A to stop", || a.is_stopped());
+    assert!(matches!(a.take_save_error(), Some(StoreError::Io(_))));
+    wait_for("A to close its connections", || a.peer_count() == 0);
+    assert!(a.connect(b.local_addr()).is_err());
+    b.shutdown();
+}